@@ -1,4 +1,6 @@
 fn main() {
     println!("cargo:rerun-if-changed=migrations/");
-    tauri_build::build()
+    if std::env::var_os("CARGO_FEATURE_GUI").is_some() {
+        tauri_build::build()
+    }
 }
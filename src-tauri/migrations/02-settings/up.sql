@@ -0,0 +1,4 @@
+CREATE TABLE Settings (
+    key TEXT PRIMARY KEY,
+    value TEXT NOT NULL
+);
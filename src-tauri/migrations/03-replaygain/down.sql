@@ -0,0 +1,4 @@
+ALTER TABLE Tracks DROP COLUMN replaygain_album_peak;
+ALTER TABLE Tracks DROP COLUMN replaygain_album_gain;
+ALTER TABLE Tracks DROP COLUMN replaygain_track_peak;
+ALTER TABLE Tracks DROP COLUMN replaygain_track_gain;
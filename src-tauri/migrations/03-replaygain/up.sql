@@ -0,0 +1,4 @@
+ALTER TABLE Tracks ADD COLUMN replaygain_track_gain REAL;
+ALTER TABLE Tracks ADD COLUMN replaygain_track_peak REAL;
+ALTER TABLE Tracks ADD COLUMN replaygain_album_gain REAL;
+ALTER TABLE Tracks ADD COLUMN replaygain_album_peak REAL;
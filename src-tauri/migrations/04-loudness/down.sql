@@ -0,0 +1,9 @@
+ALTER TABLE Albums DROP COLUMN loudness_true_peak;
+ALTER TABLE Albums DROP COLUMN loudness_range;
+ALTER TABLE Albums DROP COLUMN loudness_integrated;
+
+ALTER TABLE Tracks DROP COLUMN loudness_failed;
+ALTER TABLE Tracks DROP COLUMN loudness_analyzed_at;
+ALTER TABLE Tracks DROP COLUMN loudness_true_peak;
+ALTER TABLE Tracks DROP COLUMN loudness_range;
+ALTER TABLE Tracks DROP COLUMN loudness_integrated;
@@ -0,0 +1,9 @@
+ALTER TABLE Tracks ADD COLUMN loudness_integrated REAL;
+ALTER TABLE Tracks ADD COLUMN loudness_range REAL;
+ALTER TABLE Tracks ADD COLUMN loudness_true_peak REAL;
+ALTER TABLE Tracks ADD COLUMN loudness_analyzed_at INTEGER;
+ALTER TABLE Tracks ADD COLUMN loudness_failed INTEGER NOT NULL DEFAULT 0;
+
+ALTER TABLE Albums ADD COLUMN loudness_integrated REAL;
+ALTER TABLE Albums ADD COLUMN loudness_range REAL;
+ALTER TABLE Albums ADD COLUMN loudness_true_peak REAL;
@@ -0,0 +1,12 @@
+CREATE TABLE EqualizerPresets (
+    id INTEGER PRIMARY KEY AUTOINCREMENT,
+    name TEXT NOT NULL UNIQUE,
+    settings TEXT NOT NULL
+);
+
+INSERT INTO EqualizerPresets (name, settings) VALUES
+    ('Flat', '{"kind":"graphic","preampDb":0.0,"gainsDb":[0,0,0,0,0,0,0,0,0,0]}'),
+    ('Bass Boost', '{"kind":"graphic","preampDb":-4.0,"gainsDb":[5,4,3,1.5,0,0,0,0,0,0]}'),
+    ('Treble Boost', '{"kind":"graphic","preampDb":-4.0,"gainsDb":[0,0,0,0,0,0,1.5,3,4,5]}'),
+    ('Vocal', '{"kind":"graphic","preampDb":-3.0,"gainsDb":[-2,-2,-1,0,2,3,3,2,0,-1]}'),
+    ('Loudness', '{"kind":"parametric","preampDb":-5.0,"bands":[{"filter":"lowShelf","frequency":100.0,"gainDb":5.0,"q":0.7},{"filter":"highShelf","frequency":10000.0,"gainDb":3.0,"q":0.7}]}');
@@ -0,0 +1,3 @@
+DROP INDEX Listens_track_id;
+DROP INDEX Listens_started_at;
+DROP TABLE Listens;
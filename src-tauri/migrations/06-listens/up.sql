@@ -0,0 +1,13 @@
+CREATE TABLE Listens (
+    id INTEGER PRIMARY KEY AUTOINCREMENT,
+    track_id INTEGER,
+    path TEXT NOT NULL,
+    started_at INTEGER NOT NULL,
+    played_secs REAL,
+    completed INTEGER NOT NULL DEFAULT 0,
+    skipped INTEGER NOT NULL DEFAULT 0,
+    FOREIGN KEY (track_id) REFERENCES Tracks(id) ON DELETE SET NULL
+);
+
+CREATE INDEX Listens_started_at ON Listens(started_at);
+CREATE INDEX Listens_track_id ON Listens(track_id);
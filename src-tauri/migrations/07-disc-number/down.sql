@@ -0,0 +1 @@
+ALTER TABLE Tracks DROP COLUMN disc_number;
@@ -0,0 +1 @@
+ALTER TABLE Tracks ADD COLUMN disc_number INTEGER;
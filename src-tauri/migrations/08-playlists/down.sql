@@ -0,0 +1,2 @@
+DROP TABLE PlaylistTracks;
+DROP TABLE Playlists;
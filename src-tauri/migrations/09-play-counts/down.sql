@@ -0,0 +1,3 @@
+ALTER TABLE Tracks DROP COLUMN last_played;
+ALTER TABLE Tracks DROP COLUMN skip_count;
+ALTER TABLE Tracks DROP COLUMN play_count;
@@ -0,0 +1,3 @@
+ALTER TABLE Tracks ADD COLUMN play_count INTEGER NOT NULL DEFAULT 0;
+ALTER TABLE Tracks ADD COLUMN skip_count INTEGER NOT NULL DEFAULT 0;
+ALTER TABLE Tracks ADD COLUMN last_played INTEGER;
@@ -0,0 +1,2 @@
+DROP TABLE Scrobbles;
+ALTER TABLE Listens DROP COLUMN duration_secs;
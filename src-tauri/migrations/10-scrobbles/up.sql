@@ -0,0 +1,17 @@
+ALTER TABLE Listens ADD COLUMN duration_secs REAL;
+
+-- Listens waiting to be scrobbled, with the tags they were played with.
+CREATE TABLE Scrobbles (
+    id INTEGER PRIMARY KEY AUTOINCREMENT,
+    listen_id INTEGER NOT NULL,
+    artist TEXT NOT NULL,
+    title TEXT NOT NULL,
+    album TEXT,
+    track_number INTEGER,
+    duration_secs REAL,
+    listened_at INTEGER NOT NULL,
+    exported_at INTEGER,
+    FOREIGN KEY (listen_id) REFERENCES Listens(id) ON DELETE CASCADE
+);
+
+CREATE INDEX Scrobbles_exported_at ON Scrobbles(exported_at);
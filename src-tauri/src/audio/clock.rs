@@ -0,0 +1,30 @@
+use std::time::Duration;
+
+/// How often the player ticks by default.
+const TICK_INTERVAL: Duration = Duration::from_millis(100);
+
+/// Paces the ticks driving progress events and session saves.
+pub trait TickSource: Send {
+    /// Blocks until the next tick is due. Returning `false` stops the ticks.
+    fn wait_for_tick(&mut self) -> bool;
+}
+
+/// Ticks on a fixed interval of real time.
+pub struct IntervalTicks {
+    interval: Duration,
+}
+
+impl Default for IntervalTicks {
+    fn default() -> Self {
+        Self {
+            interval: TICK_INTERVAL,
+        }
+    }
+}
+
+impl TickSource for IntervalTicks {
+    fn wait_for_tick(&mut self) -> bool {
+        std::thread::sleep(self.interval);
+        true
+    }
+}
@@ -1,10 +1,16 @@
-use std::{collections::VecDeque, fmt::Debug, fs::File, path::Path};
+use std::{
+    fmt::Debug,
+    fs::File,
+    path::Path,
+    sync::{Arc, Mutex, MutexGuard},
+};
 
 use symphonia::{
     core::{
-        audio::SampleBuffer,
+        audio::{SampleBuffer, SignalSpec},
         codecs::Decoder,
-        formats::{FormatOptions, FormatReader, SeekMode},
+        errors::{Error as SymphoniaError, SeekErrorKind},
+        formats::{FormatOptions, FormatReader, Packet, SeekMode},
         io::MediaSourceStream,
         meta::{MetadataOptions, StandardTagKey, Tag},
         probe::{Hint, ProbeResult},
@@ -13,23 +19,39 @@ use symphonia::{
     default::get_probe,
 };
 
-#[derive(Debug)]
+use super::gain::{parse_gain, parse_peak, ReplayGain};
+use crate::error::CommandError;
+
+#[derive(Debug, serde::Serialize, serde::Deserialize, Clone)]
+#[serde(rename_all = "camelCase")]
 pub struct AudioFile {
     path: String,
+    track_id: Option<i64>,
+    album_id: Option<i64>,
+    replay_gain: Option<ReplayGain>,
 }
 
-const MINIMUM_FRAMES_IN_BUFFER_COUNT: usize = 1028;
-
 #[derive(Debug, serde::Serialize, Clone)]
 #[serde(rename_all = "camelCase")]
-pub(super) struct AudioPlaybackStatus {
-    pub percentage: f64,
-    pub total_duration_secs: f64,
+pub struct AudioPlaybackStatus {
+    /// `None` while the length of the track is unknown.
+    pub percentage: Option<f64>,
+    pub total_duration_secs: Option<f64>,
+    pub is_duration_estimated: bool,
     pub played_secs: f64,
     pub volume: f64,
+    pub underruns: u64,
+}
+
+/// A picture embedded in an audio file's tags, e.g. its cover.
+#[derive(Debug, Clone)]
+pub struct Picture {
+    pub media_type: String,
+    pub data: Vec<u8>,
 }
 
 #[derive(Debug, serde::Serialize, Clone)]
+#[serde(rename_all = "camelCase")]
 pub struct AudioMetadata {
     pub file_path: String,
     pub artist: Option<String>,
@@ -38,6 +60,8 @@ pub struct AudioMetadata {
     pub year: Option<usize>,
     pub genre: Option<String>,
     pub track_number: Option<usize>,
+    pub disc_number: Option<usize>,
+    pub replay_gain: ReplayGain,
 }
 
 impl AudioMetadata {
@@ -50,6 +74,8 @@ impl AudioMetadata {
             year: None,
             genre: None,
             track_number: None,
+            disc_number: None,
+            replay_gain: ReplayGain::default(),
         }
     }
 
@@ -62,7 +88,22 @@ impl AudioMetadata {
                 Some(StandardTagKey::Date) => self.year = tag.value.to_string().parse().ok(),
                 Some(StandardTagKey::Genre) => self.genre = Some(tag.value.to_string()),
                 Some(StandardTagKey::TrackNumber) => {
-                    self.track_number = tag.value.to_string().parse().ok()
+                    self.track_number = parse_position(&tag.value.to_string())
+                }
+                Some(StandardTagKey::DiscNumber) => {
+                    self.disc_number = parse_position(&tag.value.to_string())
+                }
+                Some(StandardTagKey::ReplayGainTrackGain) => {
+                    self.replay_gain.track_gain = parse_gain(&tag.value.to_string())
+                }
+                Some(StandardTagKey::ReplayGainTrackPeak) => {
+                    self.replay_gain.track_peak = parse_peak(&tag.value.to_string())
+                }
+                Some(StandardTagKey::ReplayGainAlbumGain) => {
+                    self.replay_gain.album_gain = parse_gain(&tag.value.to_string())
+                }
+                Some(StandardTagKey::ReplayGainAlbumPeak) => {
+                    self.replay_gain.album_peak = parse_peak(&tag.value.to_string())
                 }
                 _ => (),
             }
@@ -70,11 +111,18 @@ impl AudioMetadata {
     }
 }
 
+/// Parses track and disc numbers, which are often tagged as "3/12".
+fn parse_position(value: &str) -> Option<usize> {
+    value.split('/').next()?.trim().parse().ok()
+}
+
+#[derive(Clone)]
 pub(super) struct AudioPlaybackMetadata {
     pub channel_count: usize,
     pub sample_rate: u32,
     time_base: TimeBase,
-    frames_count: u64,
+    /// Length of the track, when the container tells it or once the end has been decoded.
+    frames_count: Option<u64>,
     track_id: u32,
 }
 
@@ -83,7 +131,7 @@ impl AudioPlaybackMetadata {
         channel_count: usize,
         sample_rate: u32,
         time_base: TimeBase,
-        frames_count: u64,
+        frames_count: Option<u64>,
         track_id: u32,
     ) -> Self {
         Self {
@@ -94,15 +142,63 @@ impl AudioPlaybackMetadata {
             track_id,
         }
     }
+
+    /// Packets are timed in the track's time base, which isn't always one tick per frame,
+    /// e.g. in MP4 files.
+    fn timestamp_to_frames(&self, timestamp: u64) -> u64 {
+        let time = self.time_base.calc_time(timestamp);
+        (to_secs(time) * self.sample_rate as f64).round() as u64
+    }
+
+    fn frames_to_timestamp(&self, frames: u64) -> u64 {
+        let time = self.frames_to_time(frames);
+        self.time_base.calc_timestamp(time)
+    }
+
+    fn frames_to_time(&self, frames: u64) -> Time {
+        match self.sample_rate {
+            0 => Time::default(),
+            sample_rate => TimeBase::new(1, sample_rate).calc_time(frames),
+        }
+    }
+}
+
+/// What's been decoded so far, used to estimate the length of tracks that don't declare it,
+/// like VBR MP3s without a Xing header or ADTS AAC streams.
+#[derive(Debug, Clone, Copy, Default)]
+pub(super) struct DecodeProgress {
+    file_size: Option<u64>,
+    decoded_bytes: u64,
+    decoded_frames: u64,
+    end_timestamp: u64,
+    /// Whether the end of the stream was decoded, which tells the length of the track.
+    reached_end: bool,
+}
+
+impl DecodeProgress {
+    fn record(&mut self, packet: &Packet) {
+        self.decoded_bytes += packet.buf().len() as u64;
+        self.decoded_frames += packet.dur();
+        self.end_timestamp = packet.ts() + packet.dur();
+    }
+
+    fn estimate_frames(&self) -> Option<u64> {
+        let file_size = self.file_size?;
+        if self.decoded_bytes == 0 {
+            return None;
+        }
+        let frames_per_byte = self.decoded_frames as f64 / self.decoded_bytes as f64;
+        Some((file_size as f64 * frames_per_byte) as u64)
+    }
 }
 
-pub(super) struct AudioHandle {
+pub struct AudioHandle {
     pub(super) track_metadata: AudioPlaybackMetadata,
-    volume: f64,
-    time: u64,
-    samples: Vec<VecDeque<f64>>,
-    reader: Box<dyn FormatReader>,
-    decoder: Box<dyn Decoder>,
+    pub(super) tags: AudioMetadata,
+    pub(super) replay_gain: ReplayGain,
+    /// How far the reader got, as of the last decoded packet handed over.
+    progress: DecodeProgress,
+    reader: Arc<Mutex<TrackReader>>,
 }
 
 impl AudioHandle {
@@ -110,87 +206,226 @@ impl AudioHandle {
         reader: Box<dyn FormatReader>,
         decoder: Box<dyn Decoder>,
         track_metadata: AudioPlaybackMetadata,
-        volume: f64,
+        tags: AudioMetadata,
+        replay_gain: ReplayGain,
     ) -> Self {
-        let channel_count = track_metadata.channel_count;
+        let reader = TrackReader {
+            track_metadata: track_metadata.clone(),
+            reader,
+            decoder,
+            end_of_stream: false,
+            progress: DecodeProgress::default(),
+            pending: Vec::new(),
+            seek_target: None,
+            seeks: 0,
+        };
         Self {
             track_metadata,
-            reader,
+            tags,
+            replay_gain,
+            progress: DecodeProgress::default(),
+            reader: Arc::new(Mutex::new(reader)),
+        }
+    }
+
+    pub fn channel_count(&self) -> usize {
+        self.track_metadata.channel_count
+    }
+
+    pub fn sample_rate(&self) -> u32 {
+        self.track_metadata.sample_rate
+    }
+
+    /// The reader, for the decoder thread to decode from without holding the handle.
+    pub(super) fn get_reader(&self) -> Arc<Mutex<TrackReader>> {
+        self.reader.clone()
+    }
+
+    fn lock_reader(&self) -> anyhow::Result<MutexGuard<'_, TrackReader>> {
+        let Ok(reader) = self.reader.lock() else {
+            anyhow::bail!("Couldn't acquire reader lock");
+        };
+        Ok(reader)
+    }
+
+    /// Records how far the reader got, for the length of tracks that don't declare it.
+    pub(super) fn update_progress(&mut self, progress: DecodeProgress) {
+        self.progress = progress;
+    }
+
+    /// Decodes the next packet of the track and appends its interleaved samples to `buf`.
+    ///
+    /// Returns `false` once the end of the stream has been reached.
+    pub fn decode_next(&mut self, buf: &mut Vec<f64>) -> anyhow::Result<bool> {
+        let mut reader = self.lock_reader()?;
+        let has_more = reader.decode_next(buf)?;
+        let progress = reader.progress();
+        drop(reader);
+        self.update_progress(progress);
+        Ok(has_more)
+    }
+
+    /// Reads the channel count and sample rate from the first packet, for containers that
+    /// don't declare them, and the time base too when `has_time_base` is `false`.
+    fn read_spec_from_first_packet(&mut self, has_time_base: bool) -> anyhow::Result<()> {
+        let mut reader = self.lock_reader()?;
+        let mut samples = Vec::new();
+        let Some(spec) = reader.decode_packet(&mut samples)? else {
+            let message = format!("{} has no decodable audio.", self.tags.file_path);
+            return Err(CommandError::DecodeFailed(message).into());
+        };
+        let track_metadata = &mut reader.track_metadata;
+        track_metadata.channel_count = spec.channels.count();
+        track_metadata.sample_rate = spec.rate;
+        if !has_time_base {
+            track_metadata.time_base = TimeBase::new(1, spec.rate);
+        }
+        let track_metadata = track_metadata.clone();
+        reader.pending = samples;
+        let progress = reader.progress();
+        drop(reader);
+        self.track_metadata = track_metadata;
+        self.update_progress(progress);
+        Ok(())
+    }
+
+    pub(super) fn get_status(
+        &self,
+        played_frames: u64,
+        volume: f64,
+        underruns: u64,
+    ) -> AudioPlaybackStatus {
+        AudioPlaybackStatus {
+            percentage: self.get_percentage(played_frames),
+            total_duration_secs: self.get_duration().map(to_secs),
+            is_duration_estimated: self.is_duration_estimated(),
+            played_secs: to_secs(self.get_played_time(played_frames)),
             volume,
-            decoder,
-            time: 0,
-            samples: vec![VecDeque::new(); channel_count],
+            underruns,
         }
     }
 
-    pub(super) fn get_sample_buffer(&mut self) -> anyhow::Result<Vec<f64>> {
-        if self.needs_to_fetch_more_samples() {
-            self.fetch_samples()?;
+    /// Seeks to `seconds` and returns the frame playback resumes from, or `None` when
+    /// `seconds` is past the end of the track.
+    pub(super) fn seek(&mut self, seconds: f64) -> anyhow::Result<Option<u64>> {
+        let frame = (seconds.max(0.0) * self.sample_rate() as f64).round() as u64;
+        if matches!(self.get_known_frames_count(), Some(frames_count) if frame >= frames_count) {
+            return Ok(None);
         }
-        let mut buf = Vec::new();
-        for channel_buffer in self.samples.iter_mut() {
-            if let Some(sample) = channel_buffer.pop_front() {
-                buf.push(sample * (self.volume));
-            }
+        match self.seek_frame(frame) {
+            Ok(()) => Ok(Some(frame)),
+            Err(err) => match err.downcast_ref::<SymphoniaError>() {
+                Some(SymphoniaError::SeekError(SeekErrorKind::OutOfRange)) => Ok(None),
+                _ => Err(err),
+            },
         }
-        Ok(buf)
     }
 
-    pub(super) fn update_volume(&mut self, volume: f64) {
-        self.volume = volume;
+    /// Seeks to a frame, i.e. a number of samples per channel from the start of the track.
+    pub(super) fn seek_frame(&mut self, frame: u64) -> anyhow::Result<()> {
+        let timestamp = self.frames_to_timestamp(frame);
+        self.lock_reader()?.seek_timestamp(timestamp)
     }
 
-    pub(super) fn get_status(&self) -> AudioPlaybackStatus {
-        AudioPlaybackStatus {
-            percentage: self.get_percentage(),
-            total_duration_secs: self.get_duration().seconds as f64,
-            played_secs: self.get_played_time().seconds as f64,
-            volume: self.volume,
+    /// Returns `None` when the length of the track is unknown.
+    pub(super) fn get_percentage(&self, played_frames: u64) -> Option<f64> {
+        let frames_count = self.get_frames_count()?;
+        if frames_count == 0 {
+            return None;
         }
+        Some((played_frames as f64 / frames_count as f64).min(1.0))
     }
 
-    pub(super) fn seek(&mut self, seconds: usize) -> anyhow::Result<()> {
-        let mut time = self.get_duration();
-        time.seconds = seconds as u64;
-        self.time = self.track_metadata.time_base.calc_timestamp(time);
-        self.reader.seek(
-            SeekMode::Accurate,
-            symphonia::core::formats::SeekTo::Time {
-                time,
-                track_id: None,
-            },
-        )?;
-        Ok(())
+    fn get_played_time(&self, played_frames: u64) -> Time {
+        self.frames_to_time(played_frames)
     }
 
-    pub(super) fn has_finished(&self) -> bool {
-        self.time >= self.track_metadata.frames_count
+    /// Returns the length of the track, estimated from the decoded packets when the container
+    /// doesn't tell it, or `None` when it can't be estimated.
+    pub fn get_duration(&self) -> Option<Time> {
+        let frames_count = self.get_frames_count()?;
+        Some(self.frames_to_time(frames_count))
     }
 
-    pub(super) fn increment_time(&mut self) {
-        self.time += 1;
+    pub fn is_duration_estimated(&self) -> bool {
+        self.get_known_frames_count().is_none() && self.progress.estimate_frames().is_some()
     }
 
-    pub(super) fn get_percentage(&self) -> f64 {
-        self.time as f64 / self.track_metadata.frames_count as f64
+    fn get_frames_count(&self) -> Option<u64> {
+        self.get_known_frames_count().or_else(|| {
+            let estimate = self.progress.estimate_frames()?;
+            Some(self.timestamp_to_frames(estimate))
+        })
     }
 
-    fn get_played_time(&self) -> Time {
-        self.track_metadata.time_base.calc_time(self.time)
+    /// The length of the track, when the container tells it or once the end has been decoded.
+    fn get_known_frames_count(&self) -> Option<u64> {
+        self.track_metadata.frames_count.or_else(|| {
+            let progress = &self.progress;
+            let decoded_to_end = progress.reached_end && progress.end_timestamp > 0;
+            decoded_to_end.then(|| self.timestamp_to_frames(progress.end_timestamp))
+        })
     }
 
-    pub fn get_duration(&self) -> Time {
-        self.track_metadata
-            .time_base
-            .calc_time(self.track_metadata.frames_count)
+    fn timestamp_to_frames(&self, timestamp: u64) -> u64 {
+        self.track_metadata.timestamp_to_frames(timestamp)
     }
 
-    fn needs_to_fetch_more_samples(&self) -> bool {
-        self.samples[0].len() < MINIMUM_FRAMES_IN_BUFFER_COUNT && !self.has_finished()
+    fn frames_to_timestamp(&self, frames: u64) -> u64 {
+        self.track_metadata.frames_to_timestamp(frames)
     }
 
-    fn fetch_samples(&mut self) -> anyhow::Result<()> {
-        while let Ok(packet) = self.reader.next_packet() {
+    fn frames_to_time(&self, frames: u64) -> Time {
+        self.track_metadata.frames_to_time(frames)
+    }
+}
+
+/// Reads and decodes a track. Kept apart from the rest of the handle, so the decoder thread
+/// can wait on the file without holding up whoever reads the state of the track.
+pub(super) struct TrackReader {
+    track_metadata: AudioPlaybackMetadata,
+    end_of_stream: bool,
+    progress: DecodeProgress,
+    /// Samples decoded ahead of playback, e.g. to read the stream parameters.
+    pending: Vec<f64>,
+    /// Timestamp a seek asked for, when the reader landed before it.
+    seek_target: Option<u64>,
+    /// Counts seeks, so a decoder thread notices the reader was moved under it.
+    seeks: u64,
+    reader: Box<dyn FormatReader>,
+    decoder: Box<dyn Decoder>,
+}
+
+impl TrackReader {
+    pub(super) fn progress(&self) -> DecodeProgress {
+        self.progress
+    }
+
+    pub(super) fn seeks(&self) -> u64 {
+        self.seeks
+    }
+
+    /// Decodes the next packet of the track and appends its interleaved samples to `buf`.
+    ///
+    /// Returns `false` once the end of the stream has been reached.
+    pub(super) fn decode_next(&mut self, buf: &mut Vec<f64>) -> anyhow::Result<bool> {
+        if !self.pending.is_empty() {
+            buf.append(&mut self.pending);
+            return Ok(true);
+        }
+        Ok(self.decode_packet(buf)?.is_some())
+    }
+
+    /// Decodes the next packet into `buf`, returning the spec of the decoded audio.
+    fn decode_packet(&mut self, buf: &mut Vec<f64>) -> anyhow::Result<Option<SignalSpec>> {
+        while !self.end_of_stream {
+            let packet = match self.reader.next_packet() {
+                Ok(packet) => packet,
+                Err(_) => {
+                    self.reach_end_of_stream();
+                    break;
+                }
+            };
             if packet.track_id() != self.track_metadata.track_id {
                 continue;
             }
@@ -202,32 +437,75 @@ impl AudioHandle {
                     let mut sample_buf = SampleBuffer::<f64>::new(duration, spec);
 
                     sample_buf.copy_interleaved_ref(audio_buf);
+                    self.progress.record(&packet);
 
-                    for channel_idx in 0..self.track_metadata.channel_count {
-                        let mut channel_buf: VecDeque<f64> = sample_buf
-                            .samples()
-                            .chunks(self.track_metadata.channel_count)
-                            .map(|chunk| chunk.get(channel_idx).unwrap_or(&0f64))
-                            .copied()
-                            .collect();
-
-                        self.samples[channel_idx].append(&mut channel_buf);
+                    let skipped_frames = self.frames_before_seek_target(&packet);
+                    let skipped_samples = skipped_frames as usize * spec.channels.count();
+                    if skipped_samples >= sample_buf.samples().len() {
+                        continue;
                     }
-                    break;
+                    buf.extend_from_slice(&sample_buf.samples()[skipped_samples..]);
+                    return Ok(Some(spec));
                 }
-                Err(symphonia::core::errors::Error::DecodeError(_)) => (),
-                Err(_) => break,
+                Err(SymphoniaError::DecodeError(_)) => (),
+                Err(_) => self.reach_end_of_stream(),
             }
         }
+        Ok(None)
+    }
+
+    /// Returns how many frames at the start of `packet` come before the position a seek asked
+    /// for, and stops skipping once the target is reached.
+    fn frames_before_seek_target(&mut self, packet: &Packet) -> u64 {
+        let Some(target) = self.seek_target else {
+            return 0;
+        };
+        let track_metadata = &self.track_metadata;
+        if packet.ts() + packet.dur() <= target {
+            return track_metadata.timestamp_to_frames(packet.dur());
+        }
+        self.seek_target = None;
+        track_metadata.timestamp_to_frames(target.saturating_sub(packet.ts()))
+    }
+
+    fn reach_end_of_stream(&mut self) {
+        self.end_of_stream = true;
+        self.progress.reached_end = true;
+    }
+
+    /// Seeks to a timestamp in the track's time base.
+    ///
+    /// The reader usually lands on a packet boundary before `timestamp`, so the frames up to
+    /// it are dropped as they're decoded.
+    fn seek_timestamp(&mut self, timestamp: u64) -> anyhow::Result<()> {
+        let seeked_to = self.reader.seek(
+            SeekMode::Accurate,
+            symphonia::core::formats::SeekTo::TimeStamp {
+                ts: timestamp,
+                track_id: self.track_metadata.track_id,
+            },
+        )?;
+        self.seeks += 1;
+        self.decoder.reset();
+        self.pending.clear();
+        self.end_of_stream = false;
+        self.seek_target = match seeked_to.actual_ts < seeked_to.required_ts {
+            true => Some(seeked_to.required_ts),
+            false => None,
+        };
         Ok(())
     }
 }
 
+pub(super) fn to_secs(time: Time) -> f64 {
+    time.seconds as f64 + time.frac
+}
+
 pub trait AudioSource {
     /// Returns the metadata of the audio
     ///
     /// Use the `AudioHandle` to fetch samples, seek, playback status, etc...
-    fn get_handle(&self, volume: f64) -> anyhow::Result<AudioHandle>;
+    fn get_handle(&self) -> anyhow::Result<AudioHandle>;
 
     /// Returns the metadata of the audio
     fn get_metadata(&self) -> anyhow::Result<AudioMetadata>;
@@ -235,6 +513,115 @@ pub trait AudioSource {
 impl AudioSource for AudioFile {
     fn get_metadata(&self) -> anyhow::Result<AudioMetadata> {
         let mut probe_result = self.probe()?;
+        Ok(self.read_metadata(&mut probe_result))
+    }
+
+    fn get_handle(&self) -> anyhow::Result<AudioHandle> {
+        let mut probe_result = self.probe()?;
+        let tags = self.read_metadata(&mut probe_result);
+        let replay_gain = match self.replay_gain {
+            Some(replay_gain) if !replay_gain.is_empty() => replay_gain,
+            _ => tags.replay_gain,
+        };
+
+        let format = probe_result.format;
+
+        let Some(track) = format.default_track() else {
+            let message = format!("{} has no audio track.", self.path);
+            return Err(CommandError::UnsupportedFormat(message).into());
+        };
+
+        let decoder =
+            symphonia::default::get_codecs().make(&track.codec_params, &Default::default())?;
+
+        let track_id = track.id;
+
+        let channels = track.codec_params.channels.map(|channels| channels.count());
+        let sample_rate = track.codec_params.sample_rate;
+        let frames_count = track.codec_params.n_frames;
+        let time_base = track
+            .codec_params
+            .time_base
+            .or_else(|| sample_rate.map(|sample_rate| TimeBase::new(1, sample_rate)));
+
+        let track_information = AudioPlaybackMetadata::new(
+            channels.unwrap_or_default(),
+            sample_rate.unwrap_or_default(),
+            time_base.unwrap_or_else(|| TimeBase::new(1, 1)),
+            frames_count,
+            track_id,
+        );
+        let mut handle = AudioHandle::new(format, decoder, track_information, tags, replay_gain);
+        let file_size = std::fs::metadata(&self.path).ok().map(|meta| meta.len());
+        handle.lock_reader()?.progress.file_size = file_size;
+
+        if channels.is_none() || sample_rate.is_none() {
+            handle.read_spec_from_first_packet(time_base.is_some())?;
+        }
+        Ok(handle)
+    }
+}
+
+impl AudioFile {
+    pub fn new(path: String) -> Self {
+        Self {
+            path,
+            track_id: None,
+            album_id: None,
+            replay_gain: None,
+        }
+    }
+
+    /// Attaches the library track the file is, used to record listens.
+    pub fn with_track_id(mut self, track_id: i64) -> Self {
+        self.track_id = Some(track_id);
+        self
+    }
+
+    /// Attaches the library album the file belongs to, used to detect album playback.
+    pub fn with_album_id(mut self, album_id: i64) -> Self {
+        self.album_id = Some(album_id);
+        self
+    }
+
+    /// Overrides the gain read from the file's tags, e.g. with values stored in the library.
+    pub fn with_replay_gain(mut self, replay_gain: ReplayGain) -> Self {
+        self.replay_gain = Some(replay_gain);
+        self
+    }
+
+    pub fn get_path(&self) -> &str {
+        &self.path
+    }
+
+    pub fn get_track_id(&self) -> Option<i64> {
+        self.track_id
+    }
+
+    pub fn get_album_id(&self) -> Option<i64> {
+        self.album_id
+    }
+
+    /// The first picture embedded in the file's tags, if any.
+    pub fn get_embedded_picture(&self) -> anyhow::Result<Option<Picture>> {
+        let mut probe_result = self.probe()?;
+        let mut revisions = Vec::new();
+        if let Some(probed_metadata) = probe_result.metadata.get() {
+            revisions.extend(probed_metadata.current().cloned());
+        }
+        revisions.extend(probe_result.format.metadata().current().cloned());
+        let picture = revisions
+            .iter()
+            .flat_map(|revision| revision.visuals())
+            .next()
+            .map(|visual| Picture {
+                media_type: visual.media_type.clone(),
+                data: visual.data.to_vec(),
+            });
+        Ok(picture)
+    }
+
+    fn read_metadata(&self, probe_result: &mut ProbeResult) -> AudioMetadata {
         let mut metadata = AudioMetadata::new(self.path.clone());
 
         if let Some(mut probed_metadata) = probe_result.metadata.get() {
@@ -256,52 +643,77 @@ impl AudioSource for AudioFile {
             metadata.merge_tags(metadata_revision.tags());
         }
 
-        Ok(metadata)
+        metadata
     }
 
-    fn get_handle(&self, volume: f64) -> anyhow::Result<AudioHandle> {
-        let probe_result = self.probe()?;
-
-        let format = probe_result.format;
-
-        let track = format.default_track().unwrap();
+    fn probe(&self) -> anyhow::Result<ProbeResult> {
+        let file = Box::new(File::open(Path::new(&self.path))?);
 
-        let decoder =
-            symphonia::default::get_codecs().make(&track.codec_params, &Default::default())?;
+        let mss = MediaSourceStream::new(file, Default::default());
 
-        let track_id = track.id;
+        let hint = Hint::new();
 
-        let channels = track.codec_params.channels.unwrap();
-        let sample_rate = track.codec_params.sample_rate.unwrap();
-        let time_base = track.codec_params.time_base.unwrap();
-        let frames_count = track.codec_params.n_frames.unwrap();
+        let format_opts: FormatOptions = Default::default();
+        let metadata_opts: MetadataOptions = Default::default();
 
-        let track_information = AudioPlaybackMetadata::new(
-            channels.count(),
-            sample_rate,
-            time_base,
-            frames_count,
-            track_id,
-        );
-        Ok(AudioHandle::new(format, decoder, track_information, volume))
+        Ok(get_probe().format(&hint, mss, &format_opts, &metadata_opts)?)
     }
 }
 
-impl AudioFile {
-    pub fn new(path: String) -> Self {
-        Self { path }
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::audio::tests::{write_counting_wav, TestDir, SAMPLE_RATE};
+
+    #[test]
+    fn test_estimates_frames_from_decoded_packets() {
+        let mut progress = DecodeProgress {
+            file_size: Some(10_000),
+            ..Default::default()
+        };
+        assert_eq!(progress.estimate_frames(), None);
+
+        progress.record(&Packet::new_from_slice(0, 0, 1152, &[0; 400]));
+        progress.record(&Packet::new_from_slice(0, 1152, 1152, &[0; 600]));
+        assert_eq!(progress.estimate_frames(), Some(23_040));
+        assert_eq!(progress.end_timestamp, 2304);
     }
 
-    fn probe(&self) -> anyhow::Result<ProbeResult> {
-        let file = Box::new(File::open(Path::new(&self.path))?);
+    fn next_frame_index(handle: &mut AudioHandle) -> i64 {
+        let mut samples = Vec::new();
+        assert!(handle.decode_next(&mut samples).unwrap());
+        (samples[0] * 32768.0).round() as i64
+    }
 
-        let mss = MediaSourceStream::new(file, Default::default());
+    #[test]
+    fn test_seek_resumes_at_exact_frame() {
+        let dir = TestDir::new("decoder-seek");
+        let path = write_counting_wav(&dir, "seek.wav", 0, 2 * SAMPLE_RATE);
+        let mut handle = AudioFile::new(path).get_handle().unwrap();
+        assert_eq!(next_frame_index(&mut handle), 0);
 
-        let hint = Hint::new();
+        assert_eq!(handle.seek(1.0625).unwrap(), Some(8500));
+        assert_eq!(next_frame_index(&mut handle), 8500);
+        let played_secs = handle.get_status(8500, 1.0, 0).played_secs;
+        assert_eq!(played_secs, 1.0625);
 
-        let format_opts: FormatOptions = Default::default();
-        let metadata_opts: MetadataOptions = Default::default();
+        assert_eq!(handle.seek(0.0).unwrap(), Some(0));
+        assert_eq!(next_frame_index(&mut handle), 0);
 
-        Ok(get_probe().format(&hint, mss, &format_opts, &metadata_opts)?)
+        assert_eq!(handle.seek(2.5).unwrap(), None);
+    }
+
+    #[test]
+    fn test_counts_frames_in_other_time_bases() {
+        let dir = TestDir::new("decoder-time-base");
+        let path = write_counting_wav(&dir, "time-base.wav", 0, SAMPLE_RATE);
+        let mut handle = AudioFile::new(path).get_handle().unwrap();
+        // Like an MP4 track timed in milliseconds.
+        handle.track_metadata.time_base = TimeBase::new(1, 1000);
+
+        assert_eq!(handle.timestamp_to_frames(500), 4000);
+        assert_eq!(handle.frames_to_timestamp(4000), 500);
+        assert_eq!(handle.get_status(4000, 1.0, 0).played_secs, 0.5);
+        assert_eq!(handle.get_duration().map(to_secs), Some(1.0));
     }
 }
@@ -0,0 +1,107 @@
+use cpal::traits::{DeviceTrait, HostTrait};
+use cpal::{Device, Host};
+
+use crate::error::CommandError;
+
+#[derive(Debug, serde::Serialize, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct OutputDeviceInfo {
+    pub host: String,
+    pub name: String,
+    pub is_default: bool,
+    pub configs: Vec<OutputConfigInfo>,
+}
+
+#[derive(Debug, serde::Serialize, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct OutputConfigInfo {
+    pub channels: u16,
+    pub min_sample_rate: u32,
+    pub max_sample_rate: u32,
+    pub sample_format: String,
+}
+
+/// A device picked by the user, identified by its name and, optionally, its host.
+#[derive(Debug, Clone)]
+pub struct DeviceSelection {
+    pub host: Option<String>,
+    pub name: String,
+}
+
+pub fn list_hosts() -> Vec<String> {
+    cpal::available_hosts()
+        .into_iter()
+        .map(|host_id| host_id.name().to_string())
+        .collect()
+}
+
+pub fn list_output_devices(host_name: Option<&str>) -> anyhow::Result<Vec<OutputDeviceInfo>> {
+    let host = get_host(host_name)?;
+    let default_name = host
+        .default_output_device()
+        .and_then(|device| device.name().ok());
+
+    let mut devices = Vec::new();
+    for device in host.output_devices()? {
+        let Ok(name) = device.name() else {
+            continue;
+        };
+        let configs = match device.supported_output_configs() {
+            Ok(configs) => configs
+                .map(|config| OutputConfigInfo {
+                    channels: config.channels(),
+                    min_sample_rate: config.min_sample_rate().0,
+                    max_sample_rate: config.max_sample_rate().0,
+                    sample_format: config.sample_format().to_string(),
+                })
+                .collect(),
+            Err(_) => Vec::new(),
+        };
+        devices.push(OutputDeviceInfo {
+            host: host.id().name().to_string(),
+            is_default: default_name.as_ref() == Some(&name),
+            name,
+            configs,
+        });
+    }
+    Ok(devices)
+}
+
+pub fn find_output_device(selection: &DeviceSelection) -> anyhow::Result<Device> {
+    let host = get_host(selection.host.as_deref())?;
+    for device in host.output_devices()? {
+        if matches!(device.name(), Ok(name) if name == selection.name) {
+            return Ok(device);
+        }
+    }
+    let message = format!("Output device \"{}\" not found.", selection.name);
+    Err(CommandError::NoDevice(message).into())
+}
+
+/// Returns the selected device, falling back to the default one when it's missing.
+pub fn get_device(selection: Option<&DeviceSelection>) -> anyhow::Result<Device> {
+    if let Some(selection) = selection {
+        match find_output_device(selection) {
+            Ok(device) => return Ok(device),
+            Err(err) => eprintln!("{err} Falling back to the default device."),
+        }
+    }
+    match cpal::default_host().default_output_device() {
+        Some(device) => Ok(device),
+        None => Err(CommandError::NoDevice("No device available.".to_string()).into()),
+    }
+}
+
+fn get_host(host_name: Option<&str>) -> anyhow::Result<Host> {
+    let Some(host_name) = host_name else {
+        return Ok(cpal::default_host());
+    };
+    let Some(host_id) = cpal::available_hosts()
+        .into_iter()
+        .find(|host_id| host_id.name() == host_name)
+    else {
+        let message = format!("Audio host \"{host_name}\" not available.");
+        return Err(CommandError::NoDevice(message).into());
+    };
+    Ok(cpal::host_from_id(host_id)?)
+}
@@ -0,0 +1,61 @@
+use std::any::Any;
+
+use super::equalizer::{Equalizer, EqualizerSettings};
+
+/// A stage of signal processing between the decoder and the output stream.
+pub trait AudioProcessor: Send {
+    /// Called before the first block of every track, and whenever the format changes.
+    fn prepare(&mut self, sample_rate: u32, channel_count: usize);
+
+    /// Processes a block of interleaved frames in place.
+    fn process(&mut self, frames: &mut [f64], channel_count: usize);
+
+    /// Clears any internal state, e.g. after a seek.
+    fn reset(&mut self);
+
+    /// Lets the chain hand out a processor by its concrete type, to reconfigure it.
+    fn as_any_mut(&mut self) -> &mut dyn Any;
+}
+
+/// Processors applied, in order, to every block of decoded audio.
+pub(super) struct DspChain {
+    processors: Vec<Box<dyn AudioProcessor>>,
+    channel_count: usize,
+}
+
+impl DspChain {
+    pub(super) fn new(equalizer: EqualizerSettings) -> Self {
+        Self {
+            processors: vec![Box::new(Equalizer::new(equalizer))],
+            channel_count: 0,
+        }
+    }
+
+    pub(super) fn get_processor_mut<T: AudioProcessor + 'static>(&mut self) -> Option<&mut T> {
+        self.processors
+            .iter_mut()
+            .find_map(|processor| processor.as_any_mut().downcast_mut::<T>())
+    }
+
+    pub(super) fn prepare(&mut self, sample_rate: u32, channel_count: usize) {
+        self.channel_count = channel_count;
+        for processor in self.processors.iter_mut() {
+            processor.prepare(sample_rate, channel_count);
+        }
+    }
+
+    pub(super) fn process(&mut self, frames: &mut [f64]) {
+        if self.channel_count == 0 {
+            return;
+        }
+        for processor in self.processors.iter_mut() {
+            processor.process(frames, self.channel_count);
+        }
+    }
+
+    pub(super) fn reset(&mut self) {
+        for processor in self.processors.iter_mut() {
+            processor.reset();
+        }
+    }
+}
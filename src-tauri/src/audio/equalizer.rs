@@ -0,0 +1,363 @@
+use std::any::Any;
+use std::f64::consts::PI;
+
+use super::dsp::AudioProcessor;
+use super::gain::db_to_linear;
+
+/// Center frequencies of the graphic equalizer bands, one octave apart.
+pub const GRAPHIC_BANDS_HZ: [f64; 10] = [
+    31.0, 62.0, 125.0, 250.0, 500.0, 1000.0, 2000.0, 4000.0, 8000.0, 16000.0,
+];
+
+/// Bandwidth of each graphic band, roughly one octave.
+const GRAPHIC_BAND_Q: f64 = 1.41;
+
+#[derive(Debug, serde::Serialize, serde::Deserialize, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "camelCase")]
+pub enum FilterType {
+    Peaking,
+    LowShelf,
+    HighShelf,
+    LowPass,
+    HighPass,
+}
+
+#[derive(Debug, serde::Serialize, serde::Deserialize, Clone, Copy, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct EqualizerBand {
+    pub filter: FilterType,
+    pub frequency: f64,
+    pub gain_db: f64,
+    pub q: f64,
+}
+
+#[derive(Debug, serde::Serialize, serde::Deserialize, Clone, PartialEq, Default)]
+#[serde(tag = "kind", rename_all = "camelCase")]
+pub enum EqualizerSettings {
+    #[default]
+    Off,
+    #[serde(rename_all = "camelCase")]
+    Graphic { preamp_db: f64, gains_db: [f64; 10] },
+    #[serde(rename_all = "camelCase")]
+    Parametric {
+        preamp_db: f64,
+        bands: Vec<EqualizerBand>,
+    },
+}
+
+impl EqualizerSettings {
+    fn preamp_db(&self) -> f64 {
+        match self {
+            EqualizerSettings::Off => 0.0,
+            EqualizerSettings::Graphic { preamp_db, .. } => *preamp_db,
+            EqualizerSettings::Parametric { preamp_db, .. } => *preamp_db,
+        }
+    }
+
+    fn bands(&self) -> Vec<EqualizerBand> {
+        match self {
+            EqualizerSettings::Off => Vec::new(),
+            EqualizerSettings::Graphic { gains_db, .. } => GRAPHIC_BANDS_HZ
+                .iter()
+                .zip(gains_db)
+                .map(|(frequency, gain_db)| EqualizerBand {
+                    filter: FilterType::Peaking,
+                    frequency: *frequency,
+                    gain_db: *gain_db,
+                    q: GRAPHIC_BAND_Q,
+                })
+                .collect(),
+            EqualizerSettings::Parametric { bands, .. } => bands.clone(),
+        }
+    }
+}
+
+/// Normalized biquad coefficients, `a0` being 1.
+#[derive(Debug, Clone, Copy, PartialEq)]
+struct Coefficients {
+    b0: f64,
+    b1: f64,
+    b2: f64,
+    a1: f64,
+    a2: f64,
+}
+
+impl Coefficients {
+    const IDENTITY: Coefficients = Coefficients {
+        b0: 1.0,
+        b1: 0.0,
+        b2: 0.0,
+        a1: 0.0,
+        a2: 0.0,
+    };
+
+    /// Filter design from the RBJ audio EQ cookbook.
+    fn new(band: &EqualizerBand, sample_rate: u32) -> Self {
+        let nyquist = sample_rate as f64 / 2.0;
+        let frequency = band.frequency.clamp(1.0, nyquist * 0.98);
+        let w0 = 2.0 * PI * frequency / sample_rate as f64;
+        let (sin, cos) = w0.sin_cos();
+        let alpha = sin / (2.0 * band.q.max(0.01));
+        let a = 10f64.powf(band.gain_db / 40.0);
+        let sqrt_a_alpha = 2.0 * a.sqrt() * alpha;
+
+        let (b0, b1, b2, a0, a1, a2) = match band.filter {
+            FilterType::Peaking => (
+                1.0 + alpha * a,
+                -2.0 * cos,
+                1.0 - alpha * a,
+                1.0 + alpha / a,
+                -2.0 * cos,
+                1.0 - alpha / a,
+            ),
+            FilterType::LowShelf => (
+                a * ((a + 1.0) - (a - 1.0) * cos + sqrt_a_alpha),
+                2.0 * a * ((a - 1.0) - (a + 1.0) * cos),
+                a * ((a + 1.0) - (a - 1.0) * cos - sqrt_a_alpha),
+                (a + 1.0) + (a - 1.0) * cos + sqrt_a_alpha,
+                -2.0 * ((a - 1.0) + (a + 1.0) * cos),
+                (a + 1.0) + (a - 1.0) * cos - sqrt_a_alpha,
+            ),
+            FilterType::HighShelf => (
+                a * ((a + 1.0) + (a - 1.0) * cos + sqrt_a_alpha),
+                -2.0 * a * ((a - 1.0) + (a + 1.0) * cos),
+                a * ((a + 1.0) + (a - 1.0) * cos - sqrt_a_alpha),
+                (a + 1.0) - (a - 1.0) * cos + sqrt_a_alpha,
+                2.0 * ((a - 1.0) - (a + 1.0) * cos),
+                (a + 1.0) - (a - 1.0) * cos - sqrt_a_alpha,
+            ),
+            FilterType::LowPass => (
+                (1.0 - cos) / 2.0,
+                1.0 - cos,
+                (1.0 - cos) / 2.0,
+                1.0 + alpha,
+                -2.0 * cos,
+                1.0 - alpha,
+            ),
+            FilterType::HighPass => (
+                (1.0 + cos) / 2.0,
+                -(1.0 + cos),
+                (1.0 + cos) / 2.0,
+                1.0 + alpha,
+                -2.0 * cos,
+                1.0 - alpha,
+            ),
+        };
+        Coefficients {
+            b0: b0 / a0,
+            b1: b1 / a0,
+            b2: b2 / a0,
+            a1: a1 / a0,
+            a2: a2 / a0,
+        }
+    }
+
+    fn lerp(&self, target: &Coefficients, t: f64) -> Coefficients {
+        let mix = |from: f64, to: f64| from + (to - from) * t;
+        Coefficients {
+            b0: mix(self.b0, target.b0),
+            b1: mix(self.b1, target.b1),
+            b2: mix(self.b2, target.b2),
+            a1: mix(self.a1, target.a1),
+            a2: mix(self.a2, target.a2),
+        }
+    }
+}
+
+/// A transposed direct form II biquad, with one state per channel.
+struct Biquad {
+    current: Coefficients,
+    target: Coefficients,
+    states: Vec<[f64; 2]>,
+}
+
+impl Biquad {
+    fn new(channel_count: usize) -> Self {
+        Self {
+            current: Coefficients::IDENTITY,
+            target: Coefficients::IDENTITY,
+            states: vec![[0.0; 2]; channel_count],
+        }
+    }
+
+    fn process(&mut self, frames: &mut [f64], channel_count: usize) {
+        let frame_count = frames.len() / channel_count;
+        let is_ramping = self.current != self.target;
+        let mut coefficients = self.current;
+        for (frame_idx, frame) in frames.chunks_exact_mut(channel_count).enumerate() {
+            if is_ramping {
+                let t = (frame_idx + 1) as f64 / frame_count as f64;
+                coefficients = self.current.lerp(&self.target, t);
+            }
+            for (sample, state) in frame.iter_mut().zip(self.states.iter_mut()) {
+                let input = *sample;
+                let output = coefficients.b0 * input + state[0];
+                state[0] = coefficients.b1 * input - coefficients.a1 * output + state[1];
+                state[1] = coefficients.b2 * input - coefficients.a2 * output;
+                *sample = output;
+            }
+        }
+        self.current = self.target;
+    }
+}
+
+/// Graphic or parametric equalizer built from a series of biquads.
+pub struct Equalizer {
+    settings: EqualizerSettings,
+    filters: Vec<Biquad>,
+    /// How many leading filters belong to the current settings; the rest are fading out.
+    active_filters: usize,
+    preamp: f64,
+    target_preamp: f64,
+    sample_rate: u32,
+    channel_count: usize,
+}
+
+impl Equalizer {
+    pub fn new(settings: EqualizerSettings) -> Self {
+        let preamp = db_to_linear(settings.preamp_db());
+        Self {
+            settings,
+            filters: Vec::new(),
+            active_filters: 0,
+            preamp,
+            target_preamp: preamp,
+            sample_rate: 0,
+            channel_count: 0,
+        }
+    }
+
+    /// Retargets the filters to new settings. Existing filter state is kept and coefficients
+    /// are ramped during the next block, so changes while playing don't click.
+    pub fn update(&mut self, settings: EqualizerSettings) {
+        self.settings = settings;
+        self.target_preamp = db_to_linear(self.settings.preamp_db());
+        self.retarget();
+    }
+
+    fn retarget(&mut self) {
+        if self.sample_rate == 0 {
+            return;
+        }
+        let bands = self.settings.bands();
+        while self.filters.len() < bands.len() {
+            self.filters.push(Biquad::new(self.channel_count));
+        }
+        for (idx, filter) in self.filters.iter_mut().enumerate() {
+            filter.target = match bands.get(idx) {
+                Some(band) => Coefficients::new(band, self.sample_rate),
+                None => Coefficients::IDENTITY,
+            };
+        }
+        self.active_filters = bands.len();
+    }
+}
+
+impl AudioProcessor for Equalizer {
+    fn prepare(&mut self, sample_rate: u32, channel_count: usize) {
+        self.sample_rate = sample_rate;
+        self.channel_count = channel_count;
+        self.filters.clear();
+        self.retarget();
+        for filter in self.filters.iter_mut() {
+            filter.current = filter.target;
+        }
+        self.filters.truncate(self.active_filters);
+        self.preamp = self.target_preamp;
+    }
+
+    fn process(&mut self, frames: &mut [f64], channel_count: usize) {
+        if frames.is_empty() {
+            return;
+        }
+        if self.preamp != 1.0 || self.target_preamp != 1.0 {
+            let frame_count = frames.len() / channel_count;
+            for (frame_idx, frame) in frames.chunks_exact_mut(channel_count).enumerate() {
+                let t = (frame_idx + 1) as f64 / frame_count as f64;
+                let preamp = self.preamp + (self.target_preamp - self.preamp) * t;
+                for sample in frame.iter_mut() {
+                    *sample *= preamp;
+                }
+            }
+            self.preamp = self.target_preamp;
+        }
+        for filter in self.filters.iter_mut() {
+            filter.process(frames, channel_count);
+        }
+        // Filters that were removed have now faded to identity and can be dropped.
+        self.filters.truncate(self.active_filters);
+    }
+
+    fn reset(&mut self) {
+        for filter in self.filters.iter_mut() {
+            for state in filter.states.iter_mut() {
+                *state = [0.0; 2];
+            }
+        }
+    }
+
+    fn as_any_mut(&mut self) -> &mut dyn Any {
+        self
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SAMPLE_RATE: u32 = 48000;
+
+    fn sine(frequency: f64, frame_count: usize) -> Vec<f64> {
+        (0..frame_count)
+            .flat_map(|idx| {
+                let value = (2.0 * PI * frequency * idx as f64 / SAMPLE_RATE as f64).sin() * 0.25;
+                [value, value]
+            })
+            .collect()
+    }
+
+    fn peak(samples: &[f64]) -> f64 {
+        samples
+            .iter()
+            .fold(0.0, |peak, sample| sample.abs().max(peak))
+    }
+
+    #[test]
+    fn test_flat_equalizer_is_transparent() {
+        let mut equalizer = Equalizer::new(EqualizerSettings::Graphic {
+            preamp_db: 0.0,
+            gains_db: [0.0; 10],
+        });
+        equalizer.prepare(SAMPLE_RATE, 2);
+        let input = sine(440.0, 4800);
+        let mut output = input.clone();
+        equalizer.process(&mut output, 2);
+        for (input, output) in input.iter().zip(output.iter()) {
+            assert!((input - output).abs() < 1e-9);
+        }
+    }
+
+    #[test]
+    fn test_peaking_band_boosts_its_frequency() {
+        let mut equalizer = Equalizer::new(EqualizerSettings::Parametric {
+            preamp_db: 0.0,
+            bands: vec![EqualizerBand {
+                filter: FilterType::Peaking,
+                frequency: 1000.0,
+                gain_db: 6.0,
+                q: 1.0,
+            }],
+        });
+        equalizer.prepare(SAMPLE_RATE, 2);
+        let mut boosted = sine(1000.0, 48000);
+        equalizer.process(&mut boosted, 2);
+        let settled = &boosted[boosted.len() / 2..];
+        assert!((peak(settled) / 0.25 - db_to_linear(6.0)).abs() < 0.02);
+
+        let mut untouched = sine(60.0, 48000);
+        equalizer.reset();
+        equalizer.process(&mut untouched, 2);
+        let settled = &untouched[untouched.len() / 2..];
+        assert!((peak(settled) / 0.25 - 1.0).abs() < 0.05);
+    }
+}
@@ -0,0 +1,146 @@
+#[derive(Debug, serde::Serialize, serde::Deserialize, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "camelCase")]
+pub enum ReplayGainMode {
+    Off,
+    Track,
+    Album,
+    /// Album gain while consecutive queue items belong to the same album, track gain otherwise.
+    Auto,
+}
+
+#[derive(Debug, serde::Serialize, serde::Deserialize, Clone, Copy, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct ReplayGainConfig {
+    pub mode: ReplayGainMode,
+    pub preamp_db: f64,
+    pub prevent_clipping: bool,
+}
+
+impl Default for ReplayGainConfig {
+    fn default() -> Self {
+        Self {
+            mode: ReplayGainMode::Off,
+            preamp_db: 0.0,
+            prevent_clipping: true,
+        }
+    }
+}
+
+/// Gain (in dB) and peak (linear, 1.0 = full scale) values of a track and its album.
+#[derive(Debug, serde::Serialize, serde::Deserialize, Clone, Copy, Default, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct ReplayGain {
+    pub track_gain: Option<f64>,
+    pub track_peak: Option<f64>,
+    pub album_gain: Option<f64>,
+    pub album_peak: Option<f64>,
+}
+
+impl ReplayGain {
+    pub fn is_empty(&self) -> bool {
+        self.track_gain.is_none() && self.album_gain.is_none()
+    }
+
+    /// Fills whatever the tags didn't provide with gains derived from measured loudness.
+    pub fn or_loudness(
+        self,
+        track_loudness: Option<f64>,
+        track_peak: Option<f64>,
+        album_loudness: Option<f64>,
+        album_peak: Option<f64>,
+    ) -> Self {
+        let gain_for = |loudness: f64| REFERENCE_LOUDNESS_LUFS - loudness;
+        match self.track_gain.is_some() {
+            true => self,
+            false => Self {
+                track_gain: track_loudness.map(gain_for),
+                track_peak,
+                album_gain: self.album_gain.or(album_loudness.map(gain_for)),
+                album_peak: self.album_peak.or(album_peak),
+            },
+        }
+    }
+
+    /// Returns the linear factor samples should be multiplied by.
+    ///
+    /// Falls back to the other gain when the requested one is missing, and to unity gain when
+    /// the track has no gain information at all.
+    pub(super) fn factor(&self, config: &ReplayGainConfig, use_album_gain: bool) -> f64 {
+        if config.mode == ReplayGainMode::Off {
+            return 1.0;
+        }
+        let (gain, peak) = match use_album_gain {
+            true => (
+                self.album_gain.or(self.track_gain),
+                self.album_peak.or(self.track_peak),
+            ),
+            false => (
+                self.track_gain.or(self.album_gain),
+                self.track_peak.or(self.album_peak),
+            ),
+        };
+        let Some(gain) = gain else {
+            return 1.0;
+        };
+
+        let factor = db_to_linear(gain + config.preamp_db);
+        match peak {
+            Some(peak) if config.prevent_clipping && peak > 0.0 => factor.min(1.0 / peak),
+            _ => factor,
+        }
+    }
+}
+
+/// Loudness ReplayGain 2.0 normalizes to, used to derive gains from EBU R128 measurements.
+pub const REFERENCE_LOUDNESS_LUFS: f64 = -18.0;
+
+pub(super) fn db_to_linear(db: f64) -> f64 {
+    10f64.powf(db / 20.0)
+}
+
+/// Parses tag values such as `-6.54 dB` or `+1.2dB`.
+pub(super) fn parse_gain(value: &str) -> Option<f64> {
+    let value = value.trim().to_ascii_lowercase();
+    let value = value.strip_suffix("db").unwrap_or(&value);
+    value.trim().trim_start_matches('+').parse().ok()
+}
+
+pub(super) fn parse_peak(value: &str) -> Option<f64> {
+    value.trim().parse().ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_gain() {
+        assert_eq!(parse_gain("-6.54 dB"), Some(-6.54));
+        assert_eq!(parse_gain("+1.20dB"), Some(1.2));
+        assert_eq!(parse_gain("0.5"), Some(0.5));
+        assert_eq!(parse_gain("loud"), None);
+    }
+
+    #[test]
+    fn test_factor_prevents_clipping() {
+        let replay_gain = ReplayGain {
+            track_gain: Some(6.0),
+            track_peak: Some(0.9),
+            album_gain: Some(-3.0),
+            album_peak: Some(0.99),
+        };
+        let mut config = ReplayGainConfig {
+            mode: ReplayGainMode::Track,
+            preamp_db: 0.0,
+            prevent_clipping: true,
+        };
+        assert!((replay_gain.factor(&config, false) - 1.0 / 0.9).abs() < 1e-9);
+        assert!((replay_gain.factor(&config, true) - db_to_linear(-3.0)).abs() < 1e-9);
+
+        config.prevent_clipping = false;
+        assert!((replay_gain.factor(&config, false) - db_to_linear(6.0)).abs() < 1e-9);
+
+        config.mode = ReplayGainMode::Off;
+        assert_eq!(replay_gain.factor(&config, false), 1.0);
+    }
+}
@@ -0,0 +1,74 @@
+use super::decoder::{AudioFile, AudioMetadata};
+
+/// How many played tracks `previous` can go back through.
+const MAX_HISTORY: usize = 200;
+
+/// How a listen ended.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, serde::Serialize)]
+#[serde(rename_all = "camelCase")]
+pub enum ListenOutcome {
+    /// The track played to its end.
+    Completed,
+    /// Something else was played before the track ended.
+    Skipped,
+}
+
+/// A track played from when it started until it ended or was replaced.
+#[derive(Debug, Clone)]
+pub struct Listen {
+    pub file: AudioFile,
+    /// Seconds since the Unix epoch.
+    pub started_at: i64,
+    /// How long the track was heard for, not counting the parts seeked over. `None` when
+    /// a client only reports that it played the track.
+    pub played_secs: Option<f64>,
+    /// `None` when the length of the track is unknown.
+    pub duration_secs: Option<f64>,
+    /// The tags read from the file, when the player has them.
+    pub metadata: Option<AudioMetadata>,
+    pub outcome: ListenOutcome,
+}
+
+impl Listen {
+    /// Whether the listen is worth scrobbling: the track is longer than 30 seconds and was heard
+    /// for half its length or 4 minutes. Clients that only report plays already decided.
+    pub fn is_scrobble(&self) -> bool {
+        let Some(played_secs) = self.played_secs else {
+            return true;
+        };
+        match self.duration_secs {
+            Some(duration_secs) => {
+                duration_secs > 30.0 && (played_secs >= duration_secs / 2.0 || played_secs >= 240.0)
+            }
+            None => played_secs >= 240.0,
+        }
+    }
+}
+
+/// Receives every listen once it's over, e.g. to persist listening history.
+pub trait ListenLog: Send {
+    fn record_listen(&self, listen: &Listen) -> anyhow::Result<()>;
+}
+
+/// Tracks played before the current one, most recent last.
+#[derive(Debug, Default)]
+pub(super) struct PlaybackHistory {
+    played: Vec<AudioFile>,
+}
+
+impl PlaybackHistory {
+    pub(super) fn push(&mut self, file: AudioFile) {
+        if self.played.len() == MAX_HISTORY {
+            self.played.remove(0);
+        }
+        self.played.push(file);
+    }
+
+    pub(super) fn pop(&mut self) -> Option<AudioFile> {
+        self.played.pop()
+    }
+
+    pub(super) fn is_empty(&self) -> bool {
+        self.played.is_empty()
+    }
+}
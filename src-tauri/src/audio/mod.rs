@@ -1,16 +1,30 @@
-use cpal::traits::HostTrait;
-use cpal::Device;
-
+mod clock;
 mod decoder;
+mod device;
+mod dsp;
+mod equalizer;
+mod gain;
+mod history;
+mod output;
 mod player;
+mod queue;
+mod session;
+mod sink;
 mod stream;
+#[cfg(test)]
+pub(crate) mod tests;
+mod worker;
 
-pub use decoder::{AudioFile, AudioMetadata, AudioSource};
-pub use player::{boot_player, PlayerController};
-
-pub fn get_device() -> anyhow::Result<Device> {
-    match cpal::default_host().default_output_device() {
-        Some(device) => Ok(device),
-        None => anyhow::bail!("No device available."),
-    }
-}
+pub use clock::{IntervalTicks, TickSource};
+pub use decoder::{AudioFile, AudioMetadata, AudioSource, Picture};
+pub use device::{
+    find_output_device, list_hosts, list_output_devices, DeviceSelection, OutputDeviceInfo,
+};
+pub use equalizer::EqualizerSettings;
+pub use gain::{ReplayGain, ReplayGainConfig};
+pub use history::{Listen, ListenLog, ListenOutcome};
+pub use output::{CpalOutput, OutputBackend, OutputFormat, OutputStream};
+pub use player::{boot_player, PlaybackState, PlayerController, PlayerOptions, PlayerState};
+pub use queue::{QueueSnapshot, RepeatMode, ShuffleMode};
+pub use session::{PlayerSession, SessionStore};
+pub use sink::{output_from_env, NullOutput, WavOutput};
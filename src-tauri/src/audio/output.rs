@@ -0,0 +1,101 @@
+use cpal::traits::{DeviceTrait, StreamTrait};
+use cpal::{Device, SampleRate, Stream, StreamConfig};
+
+use super::device::get_device;
+use super::stream::OutputCallback;
+
+/// Layout of the samples a stream is fed with.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct OutputFormat {
+    pub channels: u16,
+    pub sample_rate: u32,
+}
+
+/// Called with a message when a stream fails, e.g. because its device was unplugged.
+pub type ErrorCallback = Box<dyn FnMut(String) + Send>;
+
+/// Where the player sends decoded audio.
+pub trait OutputBackend: Send + Sync {
+    /// Builds a stream pulling its samples from `callback`.
+    ///
+    /// Whether the stream starts playing on its own depends on the backend, so callers play or
+    /// pause it right away.
+    fn open_stream(
+        &self,
+        format: OutputFormat,
+        callback: OutputCallback,
+        on_error: ErrorCallback,
+    ) -> anyhow::Result<Box<dyn OutputStream>>;
+
+    /// Moves to the system's default device after a stream error. Backends without devices
+    /// have nothing to do.
+    fn fall_back_to_default(&mut self) -> anyhow::Result<()> {
+        Ok(())
+    }
+}
+
+/// A stream opened by an `OutputBackend`. Dropping it stops the output.
+pub trait OutputStream {
+    fn play(&self) -> anyhow::Result<()>;
+
+    fn pause(&self) -> anyhow::Result<()>;
+}
+
+/// Plays through a sound card with cpal.
+pub struct CpalOutput {
+    device: Device,
+}
+
+impl CpalOutput {
+    pub fn new(device: Device) -> Self {
+        Self { device }
+    }
+
+    fn get_config(&self, format: OutputFormat) -> anyhow::Result<StreamConfig> {
+        for supported_config in self.device.supported_output_configs()? {
+            if format.channels == supported_config.channels() {
+                return Ok(supported_config
+                    .with_sample_rate(SampleRate(format.sample_rate))
+                    .into());
+            }
+        }
+        anyhow::bail!("Couldn't build configuration")
+    }
+}
+
+impl OutputBackend for CpalOutput {
+    fn open_stream(
+        &self,
+        format: OutputFormat,
+        mut callback: OutputCallback,
+        mut on_error: ErrorCallback,
+    ) -> anyhow::Result<Box<dyn OutputStream>> {
+        let config = self.get_config(format)?;
+        let stream = self.device.build_output_stream(
+            &config,
+            move |data: &mut [f32], _: &cpal::OutputCallbackInfo| {
+                callback.write_data(data);
+            },
+            move |err| on_error(err.to_string()),
+            None,
+        )?;
+        Ok(Box::new(CpalStream(stream)))
+    }
+
+    fn fall_back_to_default(&mut self) -> anyhow::Result<()> {
+        self.device = get_device(None)?;
+        Ok(())
+    }
+}
+
+struct CpalStream(Stream);
+
+impl OutputStream for CpalStream {
+    fn play(&self) -> anyhow::Result<()> {
+        Ok(self.0.play()?)
+    }
+
+    fn pause(&self) -> anyhow::Result<()> {
+        Ok(self.0.pause()?)
+    }
+}
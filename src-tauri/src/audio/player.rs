@@ -1,134 +1,462 @@
+use crate::error::CommandError;
 use crate::event::EventEmitter;
 use std::sync::mpsc::{Receiver, Sender};
 use std::sync::{Arc, Mutex};
 use std::thread::JoinHandle;
+use std::time::{SystemTime, UNIX_EPOCH};
 
-use crate::audio::decoder::{AudioFile, AudioHandle};
-use crate::audio::get_device;
-use crate::audio::stream::stream_audio;
-use cpal::{traits::StreamTrait, Device, Stream};
+use crate::audio::clock::{IntervalTicks, TickSource};
+use crate::audio::decoder::{
+    to_secs, AudioFile, AudioHandle, AudioMetadata, AudioPlaybackStatus, DecodeProgress,
+    TrackReader,
+};
+use crate::audio::device::{get_device, DeviceSelection};
+use crate::audio::dsp::DspChain;
+use crate::audio::equalizer::{Equalizer, EqualizerSettings};
+use crate::audio::gain::{ReplayGainConfig, ReplayGainMode};
+use crate::audio::history::{Listen, ListenLog, ListenOutcome, PlaybackHistory};
+use crate::audio::output::{CpalOutput, OutputBackend, OutputStream};
+use crate::audio::queue::{PlayQueue, QueueSnapshot, RepeatMode, ShuffleMode};
+use crate::audio::session::{PlayerSession, SessionStore};
+use crate::audio::stream::{stream_audio, OutputState};
+use cpal::Device;
 
 use super::decoder::AudioSource;
 
 pub enum PlayerCommand {
-    Queue(AudioFile),
-    PlayNow(AudioFile),
+    Queue(Vec<AudioFile>),
+    PlayNext(AudioFile),
+    PlayNow(Vec<AudioFile>),
+    /// Sent after the queue was edited in place, to notify listeners.
+    QueueChanged,
+    /// Sent after the volume was changed, to notify listeners even while stopped.
+    VolumeChanged,
     Resume,
     Skip,
+    /// Skips to the queued track at this index.
+    JumpInQueue(usize),
+    /// Sent when the current track finished playing on its own.
+    TrackEnded,
+    Previous,
     Pause,
-    Seek(usize),
+    /// Pauses and goes back to the start of the track, which is reported as stopped.
+    Stop,
+    Seek(f64),
+    SwitchOutput(Box<dyn OutputBackend>),
+    SetReplayGain(ReplayGainConfig),
+    SetEqualizer(EqualizerSettings),
+    StreamError {
+        track_generation: u64,
+        message: String,
+    },
     Tick,
 }
 
+#[derive(Debug, serde::Serialize, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "camelCase")]
+pub enum PlaybackState {
+    Playing,
+    Paused,
+    Stopped,
+}
+
+/// The current track, as sent in `player:track-changed` events.
+#[derive(Debug, serde::Serialize, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct TrackInfo {
+    pub file: AudioFile,
+    pub metadata: AudioMetadata,
+    /// `None` when the length of the track is unknown.
+    pub duration_secs: Option<f64>,
+}
+
+/// Everything the UI needs to resync with the player.
+#[derive(Debug, serde::Serialize, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct PlayerState {
+    pub state: PlaybackState,
+    pub track: Option<TrackInfo>,
+    pub status: Option<AudioPlaybackStatus>,
+    pub volume: f64,
+    pub queue: QueueSnapshot,
+}
+
+#[derive(Debug, serde::Serialize, Clone)]
+#[serde(rename_all = "camelCase")]
+struct StateChangedEvent {
+    state: PlaybackState,
+}
+
+#[derive(Debug, serde::Serialize, Clone)]
+#[serde(rename_all = "camelCase")]
+struct VolumeChangedEvent {
+    volume: f64,
+}
+
+/// What listeners were last told, so change events are only emitted when something changed.
+#[derive(Default)]
+struct ObservedState {
+    track_changes: u64,
+    state: Option<PlaybackState>,
+    volume: Option<f64>,
+}
+
+impl ObservedState {
+    fn emit_changes<T>(&mut self, player_handle: &Arc<Mutex<PlayerHandle>>, app_handle: &T)
+    where
+        T: EventEmitter,
+    {
+        let Ok(player_handle_guard) = player_handle.lock() else {
+            return;
+        };
+        if player_handle_guard.track_changes != self.track_changes {
+            self.track_changes = player_handle_guard.track_changes;
+            let track_info = player_handle_guard.get_track_info();
+            emit(app_handle, "player:track-changed", track_info);
+        }
+        let state = player_handle_guard.get_playback_state();
+        if self.state != Some(state) {
+            self.state = Some(state);
+            emit(
+                app_handle,
+                "player:state-changed",
+                StateChangedEvent { state },
+            );
+        }
+        let volume = player_handle_guard.output_state.volume();
+        if self.volume != Some(volume) {
+            self.volume = Some(volume);
+            emit(
+                app_handle,
+                "player:volume-changed",
+                VolumeChangedEvent { volume },
+            );
+        }
+    }
+}
+
+/// How many ticks pass between saves of the player session.
+const SESSION_SAVE_TICKS: u32 = 50;
+
+/// Going back restarts the current track instead once it has played for this long.
+const RESTART_THRESHOLD_SECS: f64 = 3.0;
+
+/// A track replaced before this much of it was heard was only passed through, not skipped.
+const MIN_SKIP_SECS: f64 = 5.0;
+
+/// Persisted preferences the player is booted with.
+#[derive(Default)]
+pub struct PlayerOptions {
+    pub device: Option<DeviceSelection>,
+    pub replay_gain: ReplayGainConfig,
+    pub equalizer: EqualizerSettings,
+    pub listen_log: Option<Box<dyn ListenLog>>,
+    /// Session to restore, paused, on boot.
+    pub session: Option<PlayerSession>,
+    pub session_store: Option<Box<dyn SessionStore>>,
+    /// Plays through something else than the selected device, like a sink for tests.
+    pub output: Option<Box<dyn OutputBackend>>,
+    /// Replaces the real time ticks, e.g. so tests decide when they happen.
+    pub ticks: Option<Box<dyn TickSource>>,
+}
+
 pub fn boot_player<T>(
     tx: Sender<PlayerCommand>,
     rx: Receiver<PlayerCommand>,
     app_handle: T,
+    options: PlayerOptions,
 ) -> anyhow::Result<PlayerController>
 where
     T: EventEmitter + Send + Sync + 'static,
 {
-    let device = get_device()?;
-    let player_handle = Arc::new(Mutex::new(PlayerHandle::new(device, tx.clone())));
+    let output = match options.output {
+        Some(output) => output,
+        None => Box::new(CpalOutput::new(get_device(options.device.as_ref())?)),
+    };
+    let mut player_handle = PlayerHandle::new(output, tx.clone(), options.equalizer);
+    player_handle.set_replay_gain(options.replay_gain);
+    player_handle.listen_tx = options.listen_log.map(spawn_listen_writer);
+    player_handle.session_store = options.session_store;
+    if let Some(session) = options.session {
+        player_handle.restore_session(session);
+    }
+    let player_handle = Arc::new(Mutex::new(player_handle));
     let player_handle_clone = player_handle.clone();
-    std::thread::spawn(move || {
-        let result = run_player(player_handle_clone, rx, app_handle);
-        if let Err(err) = result {
-            eprintln!("Error in player thread: {:?}", err);
-        }
-    });
-    run_tick_emitter(tx.clone());
+    std::thread::spawn(move || run_player(player_handle_clone, rx, app_handle));
+    let ticks = options
+        .ticks
+        .unwrap_or_else(|| Box::new(IntervalTicks::default()));
+    run_tick_emitter(tx.clone(), ticks);
     Ok(PlayerController {
         player_handle,
         player_command_tx: tx,
     })
 }
 
-fn run_tick_emitter(tx: Sender<PlayerCommand>) -> JoinHandle<anyhow::Result<()>> {
-    std::thread::spawn(move || loop {
-        std::thread::sleep(std::time::Duration::from_millis(100));
-        tx.send(PlayerCommand::Tick)?;
+/// Records listens on their own thread, so the player isn't held up by the database.
+fn spawn_listen_writer(listen_log: Box<dyn ListenLog>) -> Sender<Listen> {
+    let (tx, rx) = std::sync::mpsc::channel();
+    std::thread::spawn(move || {
+        for listen in rx {
+            if let Err(err) = listen_log.record_listen(&listen) {
+                eprintln!("Couldn't record listen: {:?}", err);
+            }
+        }
+    });
+    tx
+}
+
+fn run_tick_emitter(
+    tx: Sender<PlayerCommand>,
+    mut ticks: Box<dyn TickSource>,
+) -> JoinHandle<anyhow::Result<()>> {
+    std::thread::spawn(move || {
+        while ticks.wait_for_tick() {
+            tx.send(PlayerCommand::Tick)?;
+        }
+        Ok(())
     })
 }
 
+/// Runs commands until every sender is gone. Listeners failing to receive events doesn't
+/// stop the player either.
 fn run_player<T>(
     player_handle: Arc<Mutex<PlayerHandle>>,
     rx: Receiver<PlayerCommand>,
     app_handle: T,
+) where
+    T: EventEmitter,
+{
+    let mut stream: Option<Box<dyn OutputStream>> = None;
+    let mut ticks_since_save = 0;
+    let mut observed_state = ObservedState::default();
+    while let Ok(command) = rx.recv() {
+        let result = handle_command(
+            command,
+            &player_handle,
+            &mut stream,
+            &mut ticks_since_save,
+            &mut observed_state,
+            &app_handle,
+        );
+        if let Err(err) = result {
+            eprintln!("Error in player thread: {:?}", err);
+            emit(&app_handle, "player:error", CommandError::from(err));
+        }
+        observed_state.emit_changes(&player_handle, &app_handle);
+    }
+}
+
+/// Handles one command. Failures are reported to listeners and don't stop the player.
+fn handle_command<T>(
+    command: PlayerCommand,
+    player_handle: &Arc<Mutex<PlayerHandle>>,
+    stream: &mut Option<Box<dyn OutputStream>>,
+    ticks_since_save: &mut u32,
+    observed_state: &mut ObservedState,
+    app_handle: &T,
 ) -> anyhow::Result<()>
 where
     T: EventEmitter,
 {
-    let mut stream: Option<Stream> = None;
-    while let Ok(command) = rx.recv() {
-        match command {
-            PlayerCommand::PlayNow(track) => {
-                {
-                    let Ok(mut player_handle_guard) = player_handle.lock() else {
-                        continue;
-                    };
-                    player_handle_guard.clear_queue()?;
-                    player_handle_guard.enqueue_track(track)?;
-                    player_handle_guard.next_track()?;
+    match command {
+        PlayerCommand::PlayNow(tracks) => {
+            {
+                let Ok(mut player_handle_guard) = player_handle.lock() else {
+                    return Ok(());
                 };
-                handle_play_command(&mut stream, &player_handle)?;
-            }
-            PlayerCommand::Queue(track) => {
-                {
-                    let Ok(mut player_handle_guard) = player_handle.lock() else {
-                        continue;
-                    };
+                player_handle_guard.clear_queue()?;
+                for track in tracks {
                     player_handle_guard.enqueue_track(track)?;
+                }
+                player_handle_guard.audio_queue.reshuffle();
+                player_handle_guard.next_track()?;
+            };
+            handle_play_command(stream, player_handle)?;
+            emit_queue(player_handle, app_handle);
+        }
+        PlayerCommand::Queue(tracks) => {
+            {
+                let Ok(mut player_handle_guard) = player_handle.lock() else {
+                    return Ok(());
                 };
-            }
-            PlayerCommand::Pause => {
-                pause(&mut stream, &player_handle)?;
-            }
-            PlayerCommand::Resume => {
-                play(&mut stream, &player_handle)?;
-            }
-            PlayerCommand::Seek(seconds) => {
-                if let Ok(mut player_handle_guard) = player_handle.lock() {
-                    if let Some(track_handle) = player_handle_guard.get_mut_track_handle() {
-                        if (seconds as u64) < track_handle.get_duration().seconds {
-                            track_handle.seek(seconds)?;
-                        } else {
-                            player_handle_guard.next_track()?;
-                        }
-                    }
+                for track in tracks {
+                    player_handle_guard.enqueue_track(track)?;
                 }
+            };
+            emit_queue(player_handle, app_handle);
+        }
+        PlayerCommand::PlayNext(track) => {
+            if let Ok(mut player_handle_guard) = player_handle.lock() {
+                player_handle_guard.audio_queue.play_next(track);
+            }
+            emit_queue(player_handle, app_handle);
+        }
+        PlayerCommand::QueueChanged => {
+            emit_queue(player_handle, app_handle);
+        }
+        // Listeners are told by the change events that follow every command.
+        PlayerCommand::VolumeChanged => {}
+        PlayerCommand::Pause => {
+            pause(stream, player_handle)?;
+        }
+        PlayerCommand::Stop => {
+            stop(stream, player_handle)?;
+        }
+        PlayerCommand::Resume => {
+            if stream.is_none() {
+                reopen_stream(stream, player_handle)?;
             }
-            PlayerCommand::Skip => {
+            play(stream, player_handle)?;
+        }
+        PlayerCommand::Seek(seconds) => {
+            if !seek(stream, player_handle, seconds)? {
                 if let Ok(mut player_handle_guard) = player_handle.lock() {
                     player_handle_guard.next_track()?;
                 }
-                handle_play_command(&mut stream, &player_handle)?;
+                handle_play_command(stream, player_handle)?;
+                emit_queue(player_handle, app_handle);
             }
-            PlayerCommand::Tick => {
-                let Ok(player_handle_guard) = player_handle.lock() else {
-                    continue;
-                };
-                if !player_handle_guard.is_playing {
-                    continue;
-                }
-                if let Some(track_handle) = player_handle_guard.get_track_handle() {
-                    let track_status = track_handle.get_status();
-                    app_handle.emit_event("player:tick", track_status)?;
+        }
+        PlayerCommand::Skip => {
+            if let Ok(mut player_handle_guard) = player_handle.lock() {
+                player_handle_guard.next_track()?;
+            }
+            handle_play_command(stream, player_handle)?;
+            emit_queue(player_handle, app_handle);
+        }
+        PlayerCommand::JumpInQueue(index) => {
+            if let Ok(mut player_handle_guard) = player_handle.lock() {
+                player_handle_guard.jump_in_queue(index)?;
+            }
+            handle_play_command(stream, player_handle)?;
+            emit_queue(player_handle, app_handle);
+        }
+        PlayerCommand::TrackEnded => {
+            let Ok(mut player_handle_guard) = player_handle.lock() else {
+                return Ok(());
+            };
+            player_handle_guard.finish_listen(ListenOutcome::Completed);
+            let has_track = match player_handle_guard.audio_queue.get_repeat() {
+                RepeatMode::One => player_handle_guard.replay_track()?,
+                RepeatMode::Off | RepeatMode::All => player_handle_guard.next_track()?,
+            };
+            drop(player_handle_guard);
+            handle_play_command(stream, player_handle)?;
+            emit_queue(player_handle, app_handle);
+            if !has_track {
+                emit(app_handle, "player:ended", ());
+            }
+        }
+        PlayerCommand::Previous => {
+            let Ok(mut player_handle_guard) = player_handle.lock() else {
+                return Ok(());
+            };
+            if player_handle_guard.should_restart_track() {
+                drop(player_handle_guard);
+                seek(stream, player_handle, 0.0)?;
+                return Ok(());
+            }
+            player_handle_guard.previous_track()?;
+            drop(player_handle_guard);
+            handle_play_command(stream, player_handle)?;
+            emit_queue(player_handle, app_handle);
+        }
+        PlayerCommand::SwitchOutput(output) => {
+            if let Ok(mut player_handle_guard) = player_handle.lock() {
+                player_handle_guard.output = output;
+            }
+            restart_stream(stream, player_handle)?;
+        }
+        PlayerCommand::SetReplayGain(config) => {
+            if let Ok(mut player_handle_guard) = player_handle.lock() {
+                player_handle_guard.set_replay_gain(config);
+            }
+        }
+        PlayerCommand::SetEqualizer(settings) => {
+            if let Ok(mut player_handle_guard) = player_handle.lock() {
+                player_handle_guard.set_equalizer(settings);
+            }
+        }
+        PlayerCommand::StreamError {
+            track_generation,
+            message,
+        } => {
+            let Ok(mut player_handle_guard) = player_handle.lock() else {
+                return Ok(());
+            };
+            if player_handle_guard.get_track_generation() != track_generation {
+                return Ok(());
+            }
+            player_handle_guard.is_playing = false;
+            drop(player_handle_guard);
+            *stream = None;
+
+            eprintln!("an error occurred on the output audio stream: {}", message);
+            emit(app_handle, "player:error", CommandError::NoDevice(message));
+            if let Err(err) = reopen_on_default_device(stream, player_handle) {
+                let message = format!("Couldn't reopen the default output device: {err}");
+                emit(app_handle, "player:error", CommandError::NoDevice(message));
+            }
+        }
+        PlayerCommand::Tick => {
+            observed_state.emit_changes(player_handle, app_handle);
+            let Ok(mut player_handle_guard) = player_handle.lock() else {
+                return Ok(());
+            };
+            *ticks_since_save += 1;
+            if *ticks_since_save >= SESSION_SAVE_TICKS {
+                *ticks_since_save = 0;
+                if let Err(err) = player_handle_guard.save_session() {
+                    eprintln!("Couldn't save player session: {:?}", err);
                 }
             }
-        };
-    }
+            if !player_handle_guard.is_playing {
+                return Ok(());
+            }
+            if let Some(track_status) = player_handle_guard.get_status() {
+                emit(app_handle, "player:tick", track_status);
+            }
+        }
+    };
     Ok(())
 }
 
+fn emit_queue<T>(player_handle: &Arc<Mutex<PlayerHandle>>, app_handle: &T)
+where
+    T: EventEmitter,
+{
+    let Ok(player_handle_guard) = player_handle.lock() else {
+        return;
+    };
+    let queue = player_handle_guard.get_queue();
+    drop(player_handle_guard);
+    emit(app_handle, "player:queue-changed", queue);
+}
+
+/// Emits an event, logging failures: listeners going away doesn't concern the player.
+fn emit<T, D>(app_handle: &T, event: &str, data: D)
+where
+    T: EventEmitter,
+    D: serde::Serialize + Clone,
+{
+    if let Err(err) = app_handle.emit_event(event, data) {
+        eprintln!("Couldn't emit {event}: {:?}", err);
+    }
+}
+
 fn play(
-    stream: &mut Option<Stream>,
+    stream: &mut Option<Box<dyn OutputStream>>,
     player_handle: &Arc<Mutex<PlayerHandle>>,
 ) -> anyhow::Result<()> {
     let Ok(mut player_handle_guard) = player_handle.lock() else {
         anyhow::bail!("Could not play track");
     };
     player_handle_guard.is_playing = true;
+    player_handle_guard.is_stopped = false;
+    if player_handle_guard.listen.is_none() {
+        player_handle_guard.start_listen();
+    }
     if let Some(stream) = stream {
         stream.play()?;
     }
@@ -136,7 +464,7 @@ fn play(
 }
 
 fn pause(
-    stream: &mut Option<Stream>,
+    stream: &mut Option<Box<dyn OutputStream>>,
     player_handle: &Arc<Mutex<PlayerHandle>>,
 ) -> anyhow::Result<()> {
     let Ok(mut player_handle_guard) = player_handle.lock() else {
@@ -149,8 +477,27 @@ fn pause(
     Ok(())
 }
 
+/// Pauses and rewinds the current track. The stream is dropped, so playing again rebuilds it
+/// from the start.
+fn stop(
+    stream: &mut Option<Box<dyn OutputStream>>,
+    player_handle: &Arc<Mutex<PlayerHandle>>,
+) -> anyhow::Result<()> {
+    pause(stream, player_handle)?;
+    *stream = None;
+    let Ok(mut player_handle_guard) = player_handle.lock() else {
+        anyhow::bail!("Could not stop track");
+    };
+    if player_handle_guard.current_track.is_none() {
+        return Ok(());
+    }
+    player_handle_guard.is_stopped = true;
+    player_handle_guard.seek(0.0)?;
+    Ok(())
+}
+
 fn handle_play_command(
-    stream: &mut Option<Stream>,
+    stream: &mut Option<Box<dyn OutputStream>>,
     player_handle: &Arc<Mutex<PlayerHandle>>,
 ) -> anyhow::Result<()> {
     if stream.is_some() {
@@ -175,29 +522,150 @@ fn handle_play_command(
     Ok(())
 }
 
+/// Rebuilds the output stream on the current device, resuming from the played position.
+fn restart_stream(
+    stream: &mut Option<Box<dyn OutputStream>>,
+    player_handle: &Arc<Mutex<PlayerHandle>>,
+) -> anyhow::Result<()> {
+    if stream.is_none() {
+        return Ok(());
+    }
+    *stream = None;
+    reopen_stream(stream, player_handle)
+}
+
+/// Builds a new output stream for the current track, keeping the play/pause state.
+fn reopen_stream(
+    stream: &mut Option<Box<dyn OutputStream>>,
+    player_handle: &Arc<Mutex<PlayerHandle>>,
+) -> anyhow::Result<()> {
+    let Ok(mut player_handle_guard) = player_handle.lock() else {
+        anyhow::bail!("Could not restart stream");
+    };
+    if player_handle_guard.current_track.is_none() {
+        return Ok(());
+    }
+    player_handle_guard.rewind_to_played_position()?;
+    drop(player_handle_guard);
+    open_stream(stream, player_handle)
+}
+
+/// Seeks the current track and rebuilds the stream, so nothing buffered before the seek is
+/// played. Returns `false` when `seconds` is past the end of the track.
+///
+/// When the seek fails, playback goes on from where it was.
+fn seek(
+    stream: &mut Option<Box<dyn OutputStream>>,
+    player_handle: &Arc<Mutex<PlayerHandle>>,
+    seconds: f64,
+) -> anyhow::Result<bool> {
+    let Ok(mut player_handle_guard) = player_handle.lock() else {
+        anyhow::bail!("Could not seek track");
+    };
+    let result = player_handle_guard.seek(seconds);
+    drop(player_handle_guard);
+    match result {
+        Ok(true) => {
+            if stream.take().is_some() {
+                open_stream(stream, player_handle)?;
+            }
+            Ok(true)
+        }
+        Ok(false) => Ok(false),
+        Err(err) => {
+            // The reader may have moved before failing.
+            if let Err(err) = restart_stream(stream, player_handle) {
+                eprintln!("Couldn't resume after a failed seek: {:?}", err);
+            }
+            Err(err)
+        }
+    }
+}
+
+/// Builds the output stream from the current decoder position, keeping the play/pause state.
+fn open_stream(
+    stream: &mut Option<Box<dyn OutputStream>>,
+    player_handle: &Arc<Mutex<PlayerHandle>>,
+) -> anyhow::Result<()> {
+    let Ok(player_handle_guard) = player_handle.lock() else {
+        anyhow::bail!("Could not open stream");
+    };
+    let is_playing = player_handle_guard.is_playing;
+    drop(player_handle_guard);
+
+    *stream = Some(stream_audio(player_handle)?);
+    match is_playing {
+        true => play(stream, player_handle),
+        false => pause(stream, player_handle),
+    }
+}
+
+/// Moves playback to the system's default device after the current one failed.
+fn reopen_on_default_device(
+    stream: &mut Option<Box<dyn OutputStream>>,
+    player_handle: &Arc<Mutex<PlayerHandle>>,
+) -> anyhow::Result<()> {
+    if let Ok(mut player_handle_guard) = player_handle.lock() {
+        player_handle_guard.output.fall_back_to_default()?;
+    }
+    reopen_stream(stream, player_handle)
+}
+
 pub struct PlayerHandle {
-    device: Device,
+    output: Box<dyn OutputBackend>,
     player_tx: Sender<PlayerCommand>,
+    current_file: Option<AudioFile>,
     current_track: Option<AudioHandle>,
-    audio_queue: Vec<AudioFile>,
-    volume: f64,
+    /// Incremented whenever a different track is loaded, or playback stops.
+    track_changes: u64,
+    track_generation: u64,
+    audio_queue: PlayQueue,
+    history: PlaybackHistory,
+    /// Started when the current track starts playing.
+    listen: Option<CurrentListen>,
+    listen_tx: Option<Sender<Listen>>,
+    session_store: Option<Box<dyn SessionStore>>,
+    /// Last session saved, to skip writing it again while nothing changes.
+    saved_session: String,
+    output_state: Arc<OutputState>,
+    dsp: DspChain,
+    replay_gain: ReplayGainConfig,
+    is_album_context: bool,
     is_playing: bool,
+    /// Set by a stop until playback resumes, so the rewound track is reported as stopped.
+    is_stopped: bool,
 }
 
 impl PlayerHandle {
-    pub fn new(device: Device, player_tx: Sender<PlayerCommand>) -> Self {
+    pub fn new(
+        output: Box<dyn OutputBackend>,
+        player_tx: Sender<PlayerCommand>,
+        equalizer: EqualizerSettings,
+    ) -> Self {
         PlayerHandle {
-            device,
+            output,
             player_tx,
+            current_file: None,
             current_track: None,
-            audio_queue: Vec::new(),
-            volume: 0.1,
+            track_changes: 0,
+            track_generation: 0,
+            audio_queue: PlayQueue::default(),
+            history: PlaybackHistory::default(),
+            listen: None,
+            listen_tx: None,
+            session_store: None,
+            saved_session: String::new(),
+            output_state: Arc::new(OutputState::new(0.1)),
+            dsp: DspChain::new(equalizer),
+            replay_gain: ReplayGainConfig::default(),
+            is_album_context: false,
             is_playing: false,
+            is_stopped: false,
         }
     }
 
-    pub fn get_device(&self) -> &Device {
-        &self.device
+    pub(super) fn get_output(&self) -> &dyn OutputBackend {
+        self.output.as_ref()
     }
 
     pub fn get_track_handle(&self) -> Option<&AudioHandle> {
@@ -208,98 +676,539 @@ impl PlayerHandle {
         self.current_track.as_mut()
     }
 
+    /// Incremented every time the current track changes, so stale decoder threads can stop.
+    pub(super) fn get_track_generation(&self) -> u64 {
+        self.track_generation
+    }
+
+    pub(super) fn get_output_state(&self) -> Arc<OutputState> {
+        self.output_state.clone()
+    }
+
+    pub(super) fn get_status(&self) -> Option<AudioPlaybackStatus> {
+        let track_handle = self.current_track.as_ref()?;
+        Some(track_handle.get_status(
+            self.output_state.played_frames(),
+            self.output_state.volume(),
+            self.output_state.underruns(),
+        ))
+    }
+
+    /// The reader of the current track for a decoder thread to decode from, along with how
+    /// many times it has been seeked so far.
+    pub(super) fn get_track_reader(
+        &self,
+    ) -> anyhow::Result<Option<(Arc<Mutex<TrackReader>>, u64)>> {
+        let Some(track_handle) = self.get_track_handle() else {
+            return Ok(None);
+        };
+        let reader = track_handle.get_reader();
+        let Ok(seeks) = reader.lock().map(|reader| reader.seeks()) else {
+            anyhow::bail!("Couldn't acquire reader lock");
+        };
+        Ok(Some((reader, seeks)))
+    }
+
+    /// Runs samples freshly decoded from the current track through the DSP chain and records
+    /// how far the reader got.
+    pub(super) fn publish_decoded(&mut self, samples: &mut [f64], progress: DecodeProgress) {
+        self.dsp.process(samples);
+        if let Some(track_handle) = self.get_mut_track_handle() {
+            track_handle.update_progress(progress);
+        }
+    }
+
+    pub fn set_equalizer(&mut self, settings: EqualizerSettings) {
+        if let Some(equalizer) = self.dsp.get_processor_mut::<Equalizer>() {
+            equalizer.update(settings);
+        }
+    }
+
+    /// Makes the decoder resume from what the output has actually played, dropping anything
+    /// that was decoded but never heard.
+    pub(super) fn rewind_to_played_position(&mut self) -> anyhow::Result<()> {
+        self.track_generation += 1;
+        let played_frames = self.output_state.played_frames();
+        if let Some(track_handle) = self.current_track.as_mut() {
+            track_handle.seek_frame(played_frames)?;
+        }
+        self.dsp.reset();
+        Ok(())
+    }
+
+    /// Seeks the current track, returning `false` when `seconds` is past its end.
+    ///
+    /// This stops the decoder thread; the stream has to be rebuilt to drop the audio it
+    /// buffered from the old position.
+    pub(super) fn seek(&mut self, seconds: f64) -> anyhow::Result<bool> {
+        let Some(track_handle) = self.current_track.as_mut() else {
+            return Ok(true);
+        };
+        let Some(frame) = track_handle.seek(seconds)? else {
+            return Ok(false);
+        };
+        self.track_generation += 1;
+        if let Some(listen) = self.listen.as_mut() {
+            listen.skip_to(self.output_state.played_frames(), frame);
+        }
+        self.output_state.set_played_frames(frame);
+        self.dsp.reset();
+        Ok(true)
+    }
+
     pub fn clear_queue(&mut self) -> anyhow::Result<()> {
         self.audio_queue.clear();
         Ok(())
     }
 
     pub fn enqueue_track(&mut self, track: AudioFile) -> anyhow::Result<()> {
-        self.audio_queue.push(track);
+        self.audio_queue.append(track);
         Ok(())
     }
 
+    pub fn get_queue(&self) -> QueueSnapshot {
+        self.audio_queue.snapshot(self.current_file.as_ref())
+    }
+
     pub fn trigger_next_track(&self) -> anyhow::Result<()> {
-        Ok(self.player_tx.send(PlayerCommand::Skip)?)
+        Ok(self.player_tx.send(PlayerCommand::TrackEnded)?)
+    }
+
+    pub(super) fn get_player_tx(&self) -> Sender<PlayerCommand> {
+        self.player_tx.clone()
     }
 
     pub fn change_volume(&mut self, volume: f64) -> anyhow::Result<()> {
-        self.volume = volume;
-        if let Some(track_handle) = self.get_mut_track_handle() {
-            track_handle.update_volume(volume);
-        }
+        self.output_state.set_volume(volume);
         Ok(())
     }
 
+    pub fn set_replay_gain(&mut self, config: ReplayGainConfig) {
+        self.replay_gain = config;
+        self.update_track_gain();
+    }
+
+    fn update_track_gain(&mut self) {
+        let Some(track_handle) = self.current_track.as_ref() else {
+            self.output_state.set_gain(1.0);
+            return;
+        };
+        let use_album_gain = match self.replay_gain.mode {
+            ReplayGainMode::Album => true,
+            ReplayGainMode::Auto => self.is_album_context,
+            ReplayGainMode::Track | ReplayGainMode::Off => false,
+        };
+        let factor = track_handle
+            .replay_gain
+            .factor(&self.replay_gain, use_album_gain);
+        self.output_state.set_gain(factor);
+    }
+
+    /// Whether the current file is surrounded by files of the same library album.
+    fn is_playing_album(&self, previous_file: Option<&AudioFile>) -> bool {
+        let Some(album_id) = self.current_file.as_ref().and_then(AudioFile::get_album_id) else {
+            return false;
+        };
+        let same_album =
+            |file: Option<&AudioFile>| file.and_then(AudioFile::get_album_id) == Some(album_id);
+        same_album(previous_file) || same_album(self.audio_queue.peek_next())
+    }
+
     pub fn next_track(&mut self) -> anyhow::Result<bool> {
-        let next_audio = self.audio_queue.pop();
-        match next_audio {
+        self.advance(Vec::new())
+    }
+
+    /// Plays the queued track at `index`. The tracks before it go to the history, so
+    /// `previous` goes back through them.
+    pub fn jump_in_queue(&mut self, index: usize) -> anyhow::Result<bool> {
+        let skipped = self.audio_queue.skip_to(index)?;
+        self.advance(skipped)
+    }
+
+    fn advance(&mut self, skipped: Vec<AudioFile>) -> anyhow::Result<bool> {
+        let next_audio = self.audio_queue.pop_next();
+        let previous_file = self.current_file.take();
+        let has_track = self.load_track(next_audio, previous_file.as_ref())?;
+        for file in previous_file.into_iter().chain(skipped) {
+            self.history.push(file);
+        }
+        Ok(has_track)
+    }
+
+    /// Goes back to the last played track, putting the current one back at the front of
+    /// the queue.
+    pub fn previous_track(&mut self) -> anyhow::Result<bool> {
+        let Some(previous_audio) = self.history.pop() else {
+            return Ok(false);
+        };
+        if let Some(current_file) = self.current_file.take() {
+            if !self.audio_queue.step_back() {
+                self.audio_queue.play_next(current_file);
+            }
+        }
+        self.load_track(Some(previous_audio), None)
+    }
+
+    /// Starts the current track over, as another listen.
+    pub fn replay_track(&mut self) -> anyhow::Result<bool> {
+        let current_file = self.current_file.take();
+        self.load_track(current_file.clone(), current_file.as_ref())
+    }
+
+    /// Whether `previous` should restart the current track rather than go back.
+    fn should_restart_track(&self) -> bool {
+        let Some(track_handle) = self.current_track.as_ref() else {
+            return false;
+        };
+        let played_secs =
+            self.output_state.played_frames() as f64 / track_handle.sample_rate() as f64;
+        played_secs > RESTART_THRESHOLD_SECS || self.history.is_empty()
+    }
+
+    fn load_track(
+        &mut self,
+        audio: Option<AudioFile>,
+        previous_file: Option<&AudioFile>,
+    ) -> anyhow::Result<bool> {
+        self.finish_listen(ListenOutcome::Skipped);
+        self.track_generation += 1;
+        self.output_state.set_played_frames(0);
+        let has_track = match audio {
             Some(track) => {
-                self.current_track = Some(track.get_handle(self.volume)?);
-                Ok(true)
+                self.open_track(track)?;
+                true
             }
             None => {
                 self.current_track = None;
-                Ok(false)
+                self.track_changes += 1;
+                false
+            }
+        };
+        self.is_album_context = self.is_playing_album(previous_file);
+        self.update_track_gain();
+        Ok(has_track)
+    }
+
+    fn open_track(&mut self, track: AudioFile) -> anyhow::Result<()> {
+        let track_handle = track.get_handle()?;
+        self.dsp
+            .prepare(track_handle.sample_rate(), track_handle.channel_count());
+        self.current_track = Some(track_handle);
+        self.current_file = Some(track);
+        self.track_changes += 1;
+        Ok(())
+    }
+
+    pub fn get_playback_state(&self) -> PlaybackState {
+        match (self.current_track.is_some(), self.is_playing) {
+            (false, _) => PlaybackState::Stopped,
+            (true, _) if self.is_stopped => PlaybackState::Stopped,
+            (true, true) => PlaybackState::Playing,
+            (true, false) => PlaybackState::Paused,
+        }
+    }
+
+    pub fn get_track_info(&self) -> Option<TrackInfo> {
+        let track_handle = self.current_track.as_ref()?;
+        let file = self.current_file.clone()?;
+        Some(TrackInfo {
+            file,
+            metadata: track_handle.tags.clone(),
+            duration_secs: track_handle.get_duration().map(to_secs),
+        })
+    }
+
+    pub fn get_state(&self) -> PlayerState {
+        PlayerState {
+            state: self.get_playback_state(),
+            track: self.get_track_info(),
+            status: self.get_status(),
+            volume: self.output_state.volume(),
+            queue: self.get_queue(),
+        }
+    }
+
+    pub fn get_session(&self) -> PlayerSession {
+        let position_secs = match self.current_track.as_ref() {
+            Some(track_handle) => {
+                self.output_state.played_frames() as f64 / track_handle.sample_rate() as f64
             }
+            None => 0.0,
+        };
+        PlayerSession {
+            queue: self.audio_queue.clone(),
+            current: self.current_file.clone(),
+            position_secs,
+            volume: self.output_state.volume(),
+        }
+    }
+
+    /// Saves the session, unless it didn't change since the last save.
+    pub fn save_session(&mut self) -> anyhow::Result<()> {
+        let Some(session_store) = self.session_store.as_ref() else {
+            return Ok(());
+        };
+        let session = self.get_session();
+        let serialized = serde_json::to_string(&session)?;
+        if serialized == self.saved_session {
+            return Ok(());
+        }
+        session_store.save_session(&session)?;
+        self.saved_session = serialized;
+        Ok(())
+    }
+
+    /// Loads a saved session, leaving the current track paused at the saved position.
+    fn restore_session(&mut self, session: PlayerSession) {
+        self.audio_queue = session.queue;
+        self.output_state.set_volume(session.volume);
+        let Some(current) = session.current else {
+            return;
+        };
+        if let Err(err) = self.open_track(current) {
+            eprintln!("Couldn't restore the current track: {:?}", err);
+            return;
+        }
+        if let Some(track_handle) = self.current_track.as_ref() {
+            let played_frames = session.position_secs * track_handle.sample_rate() as f64;
+            self.output_state.set_played_frames(played_frames as u64);
+        }
+        self.is_album_context = self.is_playing_album(None);
+        self.update_track_gain();
+    }
+
+    fn start_listen(&mut self) {
+        let (Some(file), Some(track_handle)) = (&self.current_file, &self.current_track) else {
+            return;
+        };
+        let started_at = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |elapsed| elapsed.as_secs() as i64);
+        self.listen = Some(CurrentListen {
+            file: file.clone(),
+            metadata: track_handle.tags.clone(),
+            duration_secs: track_handle.get_duration().map(to_secs),
+            sample_rate: track_handle.sample_rate(),
+            started_at,
+            heard_frames: 0,
+            segment_start: self.output_state.played_frames(),
+        });
+    }
+
+    /// Records the listen of the current track, if it wasn't already and it was more than
+    /// passed through.
+    fn finish_listen(&mut self, outcome: ListenOutcome) {
+        let Some(listen) = self.listen.take() else {
+            return;
+        };
+        let Some(listen_tx) = self.listen_tx.as_ref() else {
+            return;
+        };
+        let listen = listen.finish(self.output_state.played_frames(), outcome);
+        let passed_through = listen
+            .played_secs
+            .map_or(false, |played_secs| played_secs < MIN_SKIP_SECS);
+        if outcome == ListenOutcome::Skipped && passed_through {
+            return;
+        }
+        if listen_tx.send(listen).is_err() {
+            eprintln!("Couldn't record listen: the writer is gone");
+        }
+    }
+}
+
+/// The listen of the current track, recorded when it ends or another track replaces it.
+struct CurrentListen {
+    file: AudioFile,
+    metadata: AudioMetadata,
+    duration_secs: Option<f64>,
+    sample_rate: u32,
+    started_at: i64,
+    /// Frames heard before the last seek.
+    heard_frames: u64,
+    /// Where playback was after the last seek, or when the listen started.
+    segment_start: u64,
+}
+
+impl CurrentListen {
+    fn skip_to(&mut self, played_frames: u64, timestamp: u64) {
+        self.heard_frames += played_frames.saturating_sub(self.segment_start);
+        self.segment_start = timestamp;
+    }
+
+    fn finish(self, played_frames: u64, outcome: ListenOutcome) -> Listen {
+        let heard_frames = self.heard_frames + played_frames.saturating_sub(self.segment_start);
+        Listen {
+            file: self.file,
+            started_at: self.started_at,
+            played_secs: Some(heard_frames as f64 / self.sample_rate as f64),
+            duration_secs: self.duration_secs,
+            metadata: Some(self.metadata),
+            outcome,
         }
     }
 }
 
+#[derive(Clone)]
 pub struct PlayerController {
     player_handle: Arc<Mutex<PlayerHandle>>,
     player_command_tx: Sender<PlayerCommand>,
 }
 
 impl PlayerController {
-    pub fn play_now(&self, path: String) -> anyhow::Result<()> {
-        let audio_file = AudioFile::new(path.to_owned());
-        self.player_command_tx
-            .send(PlayerCommand::PlayNow(audio_file))
-            .expect("Could not play audio");
-        Ok(())
+    /// Replaces the queue with `audio_files` and starts playing the first one.
+    pub fn play_now(&self, audio_files: Vec<AudioFile>) -> anyhow::Result<()> {
+        self.send(PlayerCommand::PlayNow(audio_files))
     }
 
-    pub fn queue(&self, path: String) -> anyhow::Result<()> {
-        let audio_file = AudioFile::new(path.to_owned());
-        self.player_command_tx
-            .send(PlayerCommand::Queue(audio_file))
-            .expect("Could not queue audio");
-        Ok(())
+    pub fn queue(&self, audio_files: Vec<AudioFile>) -> anyhow::Result<()> {
+        self.send(PlayerCommand::Queue(audio_files))
+    }
+
+    pub fn play_next(&self, audio_file: AudioFile) -> anyhow::Result<()> {
+        self.send(PlayerCommand::PlayNext(audio_file))
+    }
+
+    pub fn get_queue(&self) -> anyhow::Result<QueueSnapshot> {
+        let Ok(player_handle) = self.player_handle.lock() else {
+            return Err(player_unavailable().into());
+        };
+        Ok(player_handle.get_queue())
+    }
+
+    pub fn remove_from_queue(&self, index: usize) -> anyhow::Result<()> {
+        self.edit_queue(|queue| queue.remove(index).map(|_| ()))
+    }
+
+    pub fn move_in_queue(&self, from: usize, to: usize) -> anyhow::Result<()> {
+        self.edit_queue(|queue| queue.move_item(from, to))
+    }
+
+    pub fn clear_queue(&self) -> anyhow::Result<()> {
+        self.edit_queue(|queue| {
+            queue.clear_upcoming();
+            Ok(())
+        })
+    }
+
+    pub fn set_shuffle(&self, shuffle: ShuffleMode) -> anyhow::Result<()> {
+        self.edit_queue(|queue| {
+            queue.set_shuffle(shuffle);
+            Ok(())
+        })
+    }
+
+    pub fn set_repeat(&self, repeat: RepeatMode) -> anyhow::Result<()> {
+        self.edit_queue(|queue| {
+            queue.set_repeat(repeat);
+            Ok(())
+        })
+    }
+
+    /// Skips straight to the queued track at `index`, past the ones before it.
+    pub fn jump_in_queue(&self, index: usize) -> anyhow::Result<()> {
+        {
+            let Ok(player_handle) = self.player_handle.lock() else {
+                return Err(player_unavailable().into());
+            };
+            player_handle.audio_queue.check_index(index)?;
+        }
+        self.send(PlayerCommand::JumpInQueue(index))
+    }
+
+    fn edit_queue<F>(&self, edit: F) -> anyhow::Result<()>
+    where
+        F: FnOnce(&mut PlayQueue) -> anyhow::Result<()>,
+    {
+        {
+            let Ok(mut player_handle) = self.player_handle.lock() else {
+                return Err(player_unavailable().into());
+            };
+            edit(&mut player_handle.audio_queue)?;
+        }
+        self.send(PlayerCommand::QueueChanged)
     }
 
     pub fn pause(&self) -> anyhow::Result<()> {
-        self.player_command_tx
-            .send(PlayerCommand::Pause)
-            .expect("Could not pause audio");
-        Ok(())
+        self.send(PlayerCommand::Pause)
+    }
+
+    pub fn stop(&self) -> anyhow::Result<()> {
+        self.send(PlayerCommand::Stop)
     }
 
     pub fn resume(&self) -> anyhow::Result<()> {
-        self.player_command_tx
-            .send(PlayerCommand::Resume)
-            .expect("Could not pause audio");
-        Ok(())
+        self.send(PlayerCommand::Resume)
     }
 
     pub fn skip(&self) -> anyhow::Result<()> {
-        self.player_command_tx
-            .send(PlayerCommand::Skip)
-            .expect("Could not skip audio");
-        Ok(())
+        self.send(PlayerCommand::Skip)
     }
 
-    pub fn seek(&self, seconds: usize) -> anyhow::Result<()> {
-        self.player_command_tx
-            .send(PlayerCommand::Seek(seconds))
-            .unwrap_or_else(|_| panic!("Could not skip to {seconds} seconds"));
-        Ok(())
+    pub fn previous(&self) -> anyhow::Result<()> {
+        self.send(PlayerCommand::Previous)
     }
 
-    pub fn change_volume(&self, volume: f64) -> anyhow::Result<()> {
+    pub fn seek(&self, seconds: f64) -> anyhow::Result<()> {
+        if !seconds.is_finite() || seconds < 0.0 {
+            let message = format!("Can't seek to {seconds} seconds.");
+            return Err(CommandError::InvalidArgument(message).into());
+        }
+        self.send(PlayerCommand::Seek(seconds))
+    }
+
+    pub fn set_equalizer(&self, settings: EqualizerSettings) -> anyhow::Result<()> {
+        self.send(PlayerCommand::SetEqualizer(settings))
+    }
+
+    pub fn set_replay_gain(&self, config: ReplayGainConfig) -> anyhow::Result<()> {
+        self.send(PlayerCommand::SetReplayGain(config))
+    }
+
+    pub fn switch_device(&self, device: Device) -> anyhow::Result<()> {
+        self.switch_output(Box::new(CpalOutput::new(device)))
+    }
+
+    pub fn switch_output(&self, output: Box<dyn OutputBackend>) -> anyhow::Result<()> {
+        self.send(PlayerCommand::SwitchOutput(output))
+    }
+
+    pub fn get_state(&self) -> anyhow::Result<PlayerState> {
+        let Ok(player_handle) = self.player_handle.lock() else {
+            return Err(player_unavailable().into());
+        };
+        Ok(player_handle.get_state())
+    }
+
+    pub fn save_session(&self) -> anyhow::Result<()> {
         let Ok(mut player_handle) = self.player_handle.lock() else {
-            anyhow::bail!("Could not change volume")
+            return Err(player_unavailable().into());
         };
-        player_handle.change_volume(volume)?;
+        player_handle.save_session()
+    }
+
+    pub fn change_volume(&self, volume: f64) -> anyhow::Result<()> {
+        if !(0.0..=1.0).contains(&volume) {
+            let message = format!("Volume must be between 0 and 1, not {volume}.");
+            return Err(CommandError::InvalidArgument(message).into());
+        }
+        {
+            let Ok(mut player_handle) = self.player_handle.lock() else {
+                return Err(player_unavailable().into());
+            };
+            player_handle.change_volume(volume)?;
+        }
+        self.send(PlayerCommand::VolumeChanged)
+    }
+
+    fn send(&self, command: PlayerCommand) -> anyhow::Result<()> {
+        self.player_command_tx
+            .send(command)
+            .map_err(|_| player_unavailable())?;
         Ok(())
     }
 }
+
+fn player_unavailable() -> CommandError {
+    CommandError::PlayerUnavailable("The player stopped running.".to_string())
+}
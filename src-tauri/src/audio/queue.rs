@@ -0,0 +1,459 @@
+use std::collections::{HashSet, VecDeque};
+
+use rand::seq::SliceRandom;
+use rand::Rng;
+
+use super::decoder::AudioFile;
+use crate::error::CommandError;
+
+/// How many of the last played tracks shuffling tries to keep away from the front.
+const RECENTLY_PLAYED: usize = 25;
+
+/// How many played tracks are kept unless repeating all, when the whole pass is.
+const MAX_PLAYED: usize = 200;
+
+#[derive(Debug, serde::Serialize, serde::Deserialize, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "camelCase")]
+pub enum ShuffleMode {
+    #[default]
+    Off,
+    Tracks,
+    /// Shuffles albums, keeping the tracks of each album in order.
+    Albums,
+}
+
+#[derive(Debug, serde::Serialize, serde::Deserialize, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "camelCase")]
+pub enum RepeatMode {
+    #[default]
+    Off,
+    One,
+    All,
+}
+
+#[derive(Debug, serde::Serialize, serde::Deserialize, Clone)]
+#[serde(rename_all = "camelCase")]
+struct QueueEntry {
+    file: AudioFile,
+    /// Place in the unshuffled queue, so turning shuffle off restores the original order.
+    position: i64,
+    /// Added with `play_next` and not played yet, so later ones go after it.
+    #[serde(default)]
+    play_next: bool,
+}
+
+/// Tracks waiting to be played, in play order, plus the ones already played in this pass
+/// so repeat-all can start over.
+#[derive(Debug, serde::Serialize, serde::Deserialize, Clone, Default)]
+#[serde(rename_all = "camelCase")]
+pub(super) struct PlayQueue {
+    upcoming: VecDeque<QueueEntry>,
+    /// Tracks played in this pass, the current one last.
+    played: Vec<QueueEntry>,
+    next_position: i64,
+    shuffle: ShuffleMode,
+    repeat: RepeatMode,
+}
+
+/// The queue as shown to the frontend in `player:queue-changed` events.
+#[derive(Debug, serde::Serialize, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct QueueSnapshot {
+    pub current: Option<AudioFile>,
+    pub upcoming: Vec<AudioFile>,
+    pub shuffle: ShuffleMode,
+    pub repeat: RepeatMode,
+}
+
+impl PlayQueue {
+    pub(super) fn append(&mut self, file: AudioFile) {
+        let entry = self.new_entry(file);
+        match self.shuffle {
+            ShuffleMode::Tracks => {
+                let index = rand::thread_rng().gen_range(0..=self.upcoming.len());
+                self.upcoming.insert(index, entry);
+            }
+            ShuffleMode::Off | ShuffleMode::Albums => self.upcoming.push_back(entry),
+        }
+    }
+
+    /// Puts `file` after the current track and the other tracks added to play next, so they
+    /// play in the order they were added.
+    pub(super) fn play_next(&mut self, file: AudioFile) {
+        let index = self
+            .upcoming
+            .iter()
+            .take_while(|entry| entry.play_next)
+            .count();
+        let lowest = match self.upcoming.iter().map(|entry| entry.position).min() {
+            Some(position) => position,
+            None => self.take_position() + 1,
+        };
+        let entry = QueueEntry {
+            file,
+            position: 0,
+            play_next: true,
+        };
+        self.upcoming.insert(index, entry);
+        // Keeps the tracks to play next first, in order, when shuffle is turned off.
+        let first = lowest - index as i64 - 1;
+        for (offset, entry) in self.upcoming.iter_mut().take(index + 1).enumerate() {
+            entry.position = first + offset as i64;
+        }
+    }
+
+    /// Moves to the next track, starting the queue over when repeating all of it.
+    pub(super) fn pop_next(&mut self) -> Option<AudioFile> {
+        if self.upcoming.is_empty() && self.repeat == RepeatMode::All {
+            self.start_over();
+        }
+        let entry = self.upcoming.pop_front()?;
+        let file = entry.file.clone();
+        self.push_played(entry);
+        Some(file)
+    }
+
+    pub(super) fn peek_next(&self) -> Option<&AudioFile> {
+        self.upcoming.front().map(|entry| &entry.file)
+    }
+
+    /// Puts the current track back at the front of the queue, undoing the last `pop_next`.
+    pub(super) fn step_back(&mut self) -> bool {
+        let Some(entry) = self.played.pop() else {
+            return false;
+        };
+        self.upcoming.push_front(entry);
+        true
+    }
+
+    pub(super) fn remove(&mut self, index: usize) -> anyhow::Result<AudioFile> {
+        let Some(entry) = self.upcoming.remove(index) else {
+            return Err(no_queued_track(index));
+        };
+        Ok(entry.file)
+    }
+
+    pub(super) fn move_item(&mut self, from: usize, to: usize) -> anyhow::Result<()> {
+        if to >= self.upcoming.len() {
+            return Err(no_queued_track(to));
+        }
+        let Some(entry) = self.upcoming.remove(from) else {
+            return Err(no_queued_track(from));
+        };
+        self.upcoming.insert(to, entry);
+        if self.shuffle == ShuffleMode::Off {
+            self.renumber();
+        }
+        Ok(())
+    }
+
+    /// Moves every track queued before `index` to the played ones, so that it's the next one
+    /// to play. Returns the tracks skipped over.
+    pub(super) fn skip_to(&mut self, index: usize) -> anyhow::Result<Vec<AudioFile>> {
+        self.check_index(index)?;
+        let skipped: Vec<QueueEntry> = self.upcoming.drain(..index).collect();
+        let files = skipped.iter().map(|entry| entry.file.clone()).collect();
+        for entry in skipped {
+            self.push_played(entry);
+        }
+        Ok(files)
+    }
+
+    pub(super) fn check_index(&self, index: usize) -> anyhow::Result<()> {
+        match index < self.upcoming.len() {
+            true => Ok(()),
+            false => Err(no_queued_track(index)),
+        }
+    }
+
+    pub(super) fn clear_upcoming(&mut self) {
+        self.upcoming.clear();
+    }
+
+    /// Forgets the whole queue, including what was played in this pass.
+    pub(super) fn clear(&mut self) {
+        self.upcoming.clear();
+        self.played.clear();
+    }
+
+    pub(super) fn get_repeat(&self) -> RepeatMode {
+        self.repeat
+    }
+
+    pub(super) fn set_repeat(&mut self, repeat: RepeatMode) {
+        self.repeat = repeat;
+    }
+
+    pub(super) fn set_shuffle(&mut self, shuffle: ShuffleMode) {
+        if shuffle == self.shuffle {
+            return;
+        }
+        if self.shuffle == ShuffleMode::Off {
+            self.renumber();
+        }
+        self.shuffle = shuffle;
+        self.reshuffle();
+    }
+
+    /// Shuffles the upcoming tracks again, or restores their order when shuffle is off.
+    pub(super) fn reshuffle(&mut self) {
+        let mut entries: Vec<QueueEntry> = self.upcoming.drain(..).collect();
+        entries.sort_by_key(|entry| entry.position);
+        let recent = self.recently_played(entries.len() / 2);
+        self.upcoming = self.arrange(entries, &recent).into();
+    }
+
+    pub(super) fn snapshot(&self, current: Option<&AudioFile>) -> QueueSnapshot {
+        QueueSnapshot {
+            current: current.cloned(),
+            upcoming: self
+                .upcoming
+                .iter()
+                .map(|entry| entry.file.clone())
+                .collect(),
+            shuffle: self.shuffle,
+            repeat: self.repeat,
+        }
+    }
+
+    fn push_played(&mut self, mut entry: QueueEntry) {
+        entry.play_next = false;
+        self.played.push(entry);
+        if self.repeat != RepeatMode::All && self.played.len() > MAX_PLAYED {
+            let excess = self.played.len() - MAX_PLAYED;
+            self.played.drain(..excess);
+        }
+    }
+
+    fn new_entry(&mut self, file: AudioFile) -> QueueEntry {
+        let position = self.take_position();
+        QueueEntry {
+            file,
+            position,
+            play_next: false,
+        }
+    }
+
+    fn take_position(&mut self) -> i64 {
+        self.next_position += 1;
+        self.next_position
+    }
+
+    /// Makes positions follow the current order, after the queue was edited unshuffled.
+    fn renumber(&mut self) {
+        for entry in self.played.iter_mut().chain(self.upcoming.iter_mut()) {
+            entry.position = self.next_position;
+            self.next_position += 1;
+        }
+    }
+
+    fn start_over(&mut self) {
+        let recent = self.recently_played(self.played.len() / 2);
+        let mut entries = std::mem::take(&mut self.played);
+        entries.sort_by_key(|entry| entry.position);
+        self.upcoming = self.arrange(entries, &recent).into();
+    }
+
+    /// Orders entries, given in their original order, for the current shuffle mode. Recently
+    /// played paths are shuffled towards the end.
+    fn arrange(&self, entries: Vec<QueueEntry>, recent: &HashSet<String>) -> Vec<QueueEntry> {
+        let is_recent = |entry: &QueueEntry| recent.contains(entry.file.get_path());
+        let mut rng = rand::thread_rng();
+        match self.shuffle {
+            ShuffleMode::Off => entries,
+            ShuffleMode::Tracks => {
+                let mut entries = entries;
+                entries.shuffle(&mut rng);
+                entries.sort_by_key(|entry| is_recent(entry));
+                entries
+            }
+            ShuffleMode::Albums => {
+                let mut albums = group_by_album(entries);
+                albums.shuffle(&mut rng);
+                albums.sort_by_key(|album| album.iter().any(is_recent));
+                albums.into_iter().flatten().collect()
+            }
+        }
+    }
+
+    fn recently_played(&self, limit: usize) -> HashSet<String> {
+        self.played
+            .iter()
+            .rev()
+            .take(limit.min(RECENTLY_PLAYED))
+            .map(|entry| entry.file.get_path().to_string())
+            .collect()
+    }
+}
+
+fn no_queued_track(index: usize) -> anyhow::Error {
+    CommandError::InvalidArgument(format!("No queued track at position {index}")).into()
+}
+
+/// Splits entries into runs of consecutive tracks from the same album. Tracks outside the
+/// library are runs of their own.
+fn group_by_album(entries: Vec<QueueEntry>) -> Vec<Vec<QueueEntry>> {
+    let mut albums: Vec<Vec<QueueEntry>> = Vec::new();
+    for entry in entries {
+        let album_id = entry.file.get_album_id();
+        match albums.last_mut() {
+            Some(album)
+                if album_id.is_some()
+                    && album.last().and_then(|last| last.file.get_album_id()) == album_id =>
+            {
+                album.push(entry)
+            }
+            _ => albums.push(vec![entry]),
+        }
+    }
+    albums
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn queue_of(paths: &[&str]) -> PlayQueue {
+        let mut queue = PlayQueue::default();
+        for path in paths {
+            queue.append(AudioFile::new(path.to_string()));
+        }
+        queue
+    }
+
+    fn paths(queue: &PlayQueue) -> Vec<String> {
+        queue
+            .snapshot(None)
+            .upcoming
+            .iter()
+            .map(|file| file.get_path().to_string())
+            .collect()
+    }
+
+    #[test]
+    fn test_queue_plays_in_order() {
+        let mut queue = queue_of(&["a", "b", "c"]);
+        queue.play_next(AudioFile::new("next".to_string()));
+        queue.play_next(AudioFile::new("then".to_string()));
+        let played: Vec<String> = std::iter::from_fn(|| queue.pop_next())
+            .map(|file| file.get_path().to_string())
+            .collect();
+        assert_eq!(played, ["next", "then", "a", "b", "c"]);
+    }
+
+    #[test]
+    fn test_play_next_after_playing_one() {
+        let mut queue = queue_of(&["a", "b"]);
+        queue.set_shuffle(ShuffleMode::Tracks);
+        queue.play_next(AudioFile::new("next".to_string()));
+        queue.pop_next();
+        queue.play_next(AudioFile::new("then".to_string()));
+        queue.play_next(AudioFile::new("last".to_string()));
+        assert_eq!(paths(&queue)[..2], ["then", "last"]);
+        queue.set_shuffle(ShuffleMode::Off);
+        assert_eq!(paths(&queue), ["then", "last", "a", "b"]);
+    }
+
+    #[test]
+    fn test_queue_manipulation() {
+        let mut queue = queue_of(&["a", "b", "c", "d"]);
+        queue.move_item(3, 0).unwrap();
+        assert_eq!(paths(&queue), ["d", "a", "b", "c"]);
+        queue.remove(1).unwrap();
+        assert_eq!(paths(&queue), ["d", "b", "c"]);
+        let skipped = queue.skip_to(2).unwrap();
+        assert_eq!(skipped.len(), 2);
+        assert_eq!(paths(&queue), ["c"]);
+        assert!(queue.remove(1).is_err());
+        assert!(queue.move_item(0, 1).is_err());
+        assert_eq!(paths(&queue), ["c"]);
+    }
+
+    #[test]
+    fn test_repeat_all_keeps_skipped_tracks() {
+        let mut queue = queue_of(&["a", "b", "c"]);
+        queue.set_repeat(RepeatMode::All);
+        queue.skip_to(2).unwrap();
+        let played: Vec<String> = (0..4)
+            .filter_map(|_| queue.pop_next())
+            .map(|file| file.get_path().to_string())
+            .collect();
+        assert_eq!(played, ["c", "a", "b", "c"]);
+    }
+
+    #[test]
+    fn test_keeps_the_last_played_tracks() {
+        let names: Vec<String> = (0..MAX_PLAYED + 10).map(|idx| idx.to_string()).collect();
+        let names: Vec<&str> = names.iter().map(String::as_str).collect();
+        let mut queue = queue_of(&names);
+        while queue.pop_next().is_some() {}
+        assert_eq!(queue.played.len(), MAX_PLAYED);
+        assert_eq!(queue.played[0].file.get_path(), "10");
+
+        let mut queue = queue_of(&names);
+        queue.set_repeat(RepeatMode::All);
+        queue.skip_to(names.len() - 1).unwrap();
+        queue.pop_next();
+        assert_eq!(queue.played.len(), names.len());
+    }
+
+    #[test]
+    fn test_unshuffle_restores_order() {
+        let names: Vec<String> = (0..50).map(|idx| idx.to_string()).collect();
+        let names: Vec<&str> = names.iter().map(String::as_str).collect();
+        let mut queue = queue_of(&names);
+        queue.set_shuffle(ShuffleMode::Tracks);
+        queue.set_shuffle(ShuffleMode::Off);
+        assert_eq!(paths(&queue), names);
+    }
+
+    #[test]
+    fn test_saved_queue_can_be_unshuffled() {
+        let names: Vec<String> = (0..50).map(|idx| idx.to_string()).collect();
+        let names: Vec<&str> = names.iter().map(String::as_str).collect();
+        let mut queue = queue_of(&names);
+        queue.set_shuffle(ShuffleMode::Tracks);
+        let saved = serde_json::to_string(&queue).unwrap();
+
+        let mut restored: PlayQueue = serde_json::from_str(&saved).unwrap();
+        assert_eq!(paths(&restored), paths(&queue));
+        restored.set_shuffle(ShuffleMode::Off);
+        assert_eq!(paths(&restored), names);
+    }
+
+    #[test]
+    fn test_album_shuffle_keeps_track_order() {
+        let mut queue = PlayQueue::default();
+        for album_id in 0..10 {
+            for track in 0..3 {
+                let path = format!("{album_id}-{track}");
+                queue.append(AudioFile::new(path).with_album_id(album_id));
+            }
+        }
+        queue.set_shuffle(ShuffleMode::Albums);
+        for album in paths(&queue).chunks(3) {
+            let album_id = album[0].split('-').next().unwrap();
+            let expected: Vec<String> = (0..3).map(|track| format!("{album_id}-{track}")).collect();
+            assert_eq!(album, expected);
+        }
+    }
+
+    #[test]
+    fn test_repeat_all_avoids_recently_played() {
+        let mut queue = queue_of(&["a", "b", "c", "d"]);
+        queue.set_repeat(RepeatMode::All);
+        queue.set_shuffle(ShuffleMode::Tracks);
+        for _ in 0..20 {
+            while !queue.upcoming.is_empty() {
+                queue.pop_next();
+            }
+            let recent: Vec<String> = queue.played[2..]
+                .iter()
+                .map(|entry| entry.file.get_path().to_string())
+                .collect();
+            let first_again = queue.pop_next().unwrap();
+            assert!(!recent.contains(&first_again.get_path().to_string()));
+            assert_eq!(queue.upcoming.len(), 3);
+        }
+    }
+}
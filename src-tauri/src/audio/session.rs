@@ -0,0 +1,17 @@
+use super::decoder::AudioFile;
+use super::queue::PlayQueue;
+
+/// What the player was doing, saved so it can pick up where it left off after a restart.
+#[derive(Debug, serde::Serialize, serde::Deserialize, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct PlayerSession {
+    pub(super) queue: PlayQueue,
+    pub(super) current: Option<AudioFile>,
+    pub(super) position_secs: f64,
+    pub(super) volume: f64,
+}
+
+/// Where the player saves its session.
+pub trait SessionStore: Send {
+    fn save_session(&self, session: &PlayerSession) -> anyhow::Result<()>;
+}
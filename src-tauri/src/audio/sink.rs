@@ -0,0 +1,221 @@
+use std::fs::File;
+use std::io::{BufWriter, Seek, SeekFrom, Write};
+use std::path::Path;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread::JoinHandle;
+use std::time::Duration;
+
+use super::output::{ErrorCallback, OutputBackend, OutputFormat, OutputStream};
+use super::stream::OutputCallback;
+
+/// How much audio a sink pulls at once.
+const PERIOD_MILLIS: u64 = 10;
+
+/// The WAV sample format of 32-bit float samples.
+const WAVE_FORMAT_IEEE_FLOAT: u16 = 3;
+
+/// Picks a sink from the `AMPTREE_OUTPUT` environment variable, `null` or `wav:<path>`, to
+/// run the player without a sound card. Returns `None` when it isn't set.
+pub fn output_from_env() -> anyhow::Result<Option<Box<dyn OutputBackend>>> {
+    let Ok(output) = std::env::var("AMPTREE_OUTPUT") else {
+        return Ok(None);
+    };
+    if output == "null" {
+        return Ok(Some(Box::new(NullOutput::new(1.0))));
+    }
+    if let Some(path) = output.strip_prefix("wav:") {
+        return Ok(Some(Box::new(WavOutput::create(Path::new(path), 1.0)?)));
+    }
+    anyhow::bail!("Unknown output \"{output}\", expected \"null\" or \"wav:<path>\".")
+}
+
+/// Consumes audio without playing it, at `speed` times real time.
+///
+/// Lets the player run where there's no sound card, like CI machines.
+pub struct NullOutput {
+    speed: f64,
+}
+
+impl NullOutput {
+    pub fn new(speed: f64) -> Self {
+        Self { speed }
+    }
+}
+
+impl OutputBackend for NullOutput {
+    fn open_stream(
+        &self,
+        format: OutputFormat,
+        callback: OutputCallback,
+        _on_error: ErrorCallback,
+    ) -> anyhow::Result<Box<dyn OutputStream>> {
+        let stream = ClockedStream::spawn(format, self.speed, callback, |_, _| Ok(()));
+        Ok(Box::new(stream))
+    }
+}
+
+/// Writes everything played to a 32-bit float WAV file, at `speed` times real time.
+///
+/// Every stream appends to the same file, whose format is the one of the first stream.
+pub struct WavOutput {
+    writer: Arc<Mutex<WavWriter>>,
+    speed: f64,
+}
+
+impl WavOutput {
+    pub fn create(path: &Path, speed: f64) -> anyhow::Result<Self> {
+        let writer = WavWriter {
+            file: BufWriter::new(File::create(path)?),
+            format: None,
+            data_len: 0,
+        };
+        Ok(Self {
+            writer: Arc::new(Mutex::new(writer)),
+            speed,
+        })
+    }
+}
+
+impl OutputBackend for WavOutput {
+    fn open_stream(
+        &self,
+        format: OutputFormat,
+        callback: OutputCallback,
+        _on_error: ErrorCallback,
+    ) -> anyhow::Result<Box<dyn OutputStream>> {
+        let writer = self.writer.clone();
+        let stream = ClockedStream::spawn(format, self.speed, callback, move |format, samples| {
+            let Ok(mut writer) = writer.lock() else {
+                anyhow::bail!("Couldn't acquire WAV writer lock");
+            };
+            writer.write(format, samples)
+        });
+        Ok(Box::new(stream))
+    }
+}
+
+struct WavWriter {
+    file: BufWriter<File>,
+    format: Option<OutputFormat>,
+    data_len: u32,
+}
+
+impl WavWriter {
+    fn write(&mut self, format: OutputFormat, samples: &[f32]) -> anyhow::Result<()> {
+        if samples.is_empty() {
+            return Ok(());
+        }
+        if self.format.is_none() {
+            self.format = Some(format);
+            self.write_header()?;
+        }
+        for sample in samples {
+            self.file.write_all(&sample.to_le_bytes())?;
+        }
+        self.data_len += (samples.len() * 4) as u32;
+        self.write_header()
+    }
+
+    /// Rewrites the header with the current data length, so the file is valid at all times.
+    fn write_header(&mut self) -> anyhow::Result<()> {
+        let Some(format) = self.format else {
+            return Ok(());
+        };
+        let header = wav_header(format, WAVE_FORMAT_IEEE_FLOAT, 32, self.data_len);
+        self.file.seek(SeekFrom::Start(0))?;
+        self.file.write_all(&header)?;
+        self.file.seek(SeekFrom::End(0))?;
+        self.file.flush()?;
+        Ok(())
+    }
+}
+
+/// Builds the header of a WAV file holding `data_len` bytes of `bits_per_sample` samples.
+pub(super) fn wav_header(
+    format: OutputFormat,
+    sample_format: u16,
+    bits_per_sample: u16,
+    data_len: u32,
+) -> Vec<u8> {
+    let block_align = format.channels * bits_per_sample / 8;
+    let mut header = Vec::with_capacity(44);
+    header.extend_from_slice(b"RIFF");
+    header.extend_from_slice(&(36 + data_len).to_le_bytes());
+    header.extend_from_slice(b"WAVEfmt ");
+    header.extend_from_slice(&16u32.to_le_bytes());
+    header.extend_from_slice(&sample_format.to_le_bytes());
+    header.extend_from_slice(&format.channels.to_le_bytes());
+    header.extend_from_slice(&format.sample_rate.to_le_bytes());
+    header.extend_from_slice(&(format.sample_rate * block_align as u32).to_le_bytes());
+    header.extend_from_slice(&block_align.to_le_bytes());
+    header.extend_from_slice(&bits_per_sample.to_le_bytes());
+    header.extend_from_slice(b"data");
+    header.extend_from_slice(&data_len.to_le_bytes());
+    header
+}
+
+/// A stream pulled by its own thread on a timer, standing in for a sound card's callback.
+struct ClockedStream {
+    is_playing: Arc<AtomicBool>,
+    is_stopped: Arc<AtomicBool>,
+    thread: Option<JoinHandle<()>>,
+}
+
+impl ClockedStream {
+    /// Spawns the thread pulling a period of audio from `callback` and handing what it got to
+    /// `sink`. The stream starts paused.
+    fn spawn<F>(format: OutputFormat, speed: f64, mut callback: OutputCallback, mut sink: F) -> Self
+    where
+        F: FnMut(OutputFormat, &[f32]) -> anyhow::Result<()> + Send + 'static,
+    {
+        let is_playing = Arc::new(AtomicBool::new(false));
+        let is_stopped = Arc::new(AtomicBool::new(false));
+        let thread_is_playing = is_playing.clone();
+        let thread_is_stopped = is_stopped.clone();
+
+        let period_frames = format.sample_rate as u64 * PERIOD_MILLIS / 1000;
+        let period = Duration::from_millis(PERIOD_MILLIS).div_f64(speed);
+        let thread = std::thread::spawn(move || {
+            let mut data = vec![0f32; period_frames as usize * format.channels as usize];
+            while !thread_is_stopped.load(Ordering::Acquire) {
+                std::thread::sleep(period);
+                if !thread_is_playing.load(Ordering::Acquire) {
+                    continue;
+                }
+                let written = callback.write_data(&mut data);
+                if let Err(err) = sink(format, &data[..written]) {
+                    eprintln!("Error in output sink: {:?}", err);
+                    return;
+                }
+            }
+        });
+        Self {
+            is_playing,
+            is_stopped,
+            thread: Some(thread),
+        }
+    }
+}
+
+impl OutputStream for ClockedStream {
+    fn play(&self) -> anyhow::Result<()> {
+        self.is_playing.store(true, Ordering::Release);
+        Ok(())
+    }
+
+    fn pause(&self) -> anyhow::Result<()> {
+        self.is_playing.store(false, Ordering::Release);
+        Ok(())
+    }
+}
+
+impl Drop for ClockedStream {
+    /// Waits for the thread, so everything it pulled has reached the sink.
+    fn drop(&mut self) {
+        self.is_stopped.store(true, Ordering::Release);
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
+}
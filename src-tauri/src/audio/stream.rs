@@ -1,73 +1,168 @@
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
 use std::sync::{Arc, Mutex};
 
-use cpal::{traits::DeviceTrait, Device, FromSample, Sample, SampleRate, Stream, StreamConfig};
+use cpal::{FromSample, Sample};
+use rtrb::{Consumer, RingBuffer};
 
-use super::player::PlayerHandle;
+use super::output::{OutputFormat, OutputStream};
+use super::player::{PlayerCommand, PlayerHandle};
+use super::worker::spawn_decoder_thread;
 
-pub(super) fn stream_audio(player_handle: &Arc<Mutex<PlayerHandle>>) -> anyhow::Result<Stream> {
+/// How much decoded audio is kept ahead of the output callback.
+const RING_BUFFER_MILLIS: usize = 500;
+
+/// State shared between the player and the realtime output callback.
+///
+/// Everything in here is atomic so the callback never has to take a lock.
+pub(super) struct OutputState {
+    volume: AtomicU64,
+    gain: AtomicU64,
+    played_frames: AtomicU64,
+    underruns: AtomicU64,
+    end_of_stream: AtomicBool,
+}
+
+impl OutputState {
+    pub(super) fn new(volume: f64) -> Self {
+        Self {
+            volume: AtomicU64::new(volume.to_bits()),
+            gain: AtomicU64::new(1f64.to_bits()),
+            played_frames: AtomicU64::new(0),
+            underruns: AtomicU64::new(0),
+            end_of_stream: AtomicBool::new(false),
+        }
+    }
+
+    pub(super) fn volume(&self) -> f64 {
+        f64::from_bits(self.volume.load(Ordering::Relaxed))
+    }
+
+    pub(super) fn set_volume(&self, volume: f64) {
+        self.volume.store(volume.to_bits(), Ordering::Relaxed);
+    }
+
+    /// Linear gain applied on top of the volume, e.g. ReplayGain of the current track.
+    pub(super) fn gain(&self) -> f64 {
+        f64::from_bits(self.gain.load(Ordering::Relaxed))
+    }
+
+    pub(super) fn set_gain(&self, gain: f64) {
+        self.gain.store(gain.to_bits(), Ordering::Relaxed);
+    }
+
+    pub(super) fn played_frames(&self) -> u64 {
+        self.played_frames.load(Ordering::Relaxed)
+    }
+
+    pub(super) fn set_played_frames(&self, frames: u64) {
+        self.played_frames.store(frames, Ordering::Relaxed);
+    }
+
+    pub(super) fn underruns(&self) -> u64 {
+        self.underruns.load(Ordering::Relaxed)
+    }
+
+    pub(super) fn set_end_of_stream(&self, end_of_stream: bool) {
+        self.end_of_stream.store(end_of_stream, Ordering::Release);
+    }
+
+    fn is_end_of_stream(&self) -> bool {
+        self.end_of_stream.load(Ordering::Acquire)
+    }
+}
+
+pub(super) fn stream_audio(
+    player_handle: &Arc<Mutex<PlayerHandle>>,
+) -> anyhow::Result<Box<dyn OutputStream>> {
     let Ok(handle) = player_handle.lock() else {
         anyhow::bail!("Couldn't acquire handle lock")
     };
 
-    let (device, config) = get_config(&handle)?;
-    let handle_clone = player_handle.clone();
-    Ok(device.build_output_stream(
-        &config,
-        move |data: &mut [f32], _: &cpal::OutputCallbackInfo| {
-            write_data(data, handle_clone.clone())
-        },
-        |err| eprintln!("an error occurred on the output audio stream: {}", err),
-        None,
-    )?)
+    let Some(track_handle) = handle.get_track_handle() else {
+        anyhow::bail!("No track to play.");
+    };
+    let format = OutputFormat {
+        channels: track_handle.channel_count() as u16,
+        sample_rate: track_handle.sample_rate(),
+    };
+    let channel_count = format.channels as usize;
+    let capacity = format.sample_rate as usize * channel_count * RING_BUFFER_MILLIS / 1000;
+    let (producer, consumer) = RingBuffer::<f64>::new(capacity);
+
+    let output_state = handle.get_output_state();
+    output_state.set_end_of_stream(false);
+    let track_generation = handle.get_track_generation();
+    let callback = OutputCallback::new(consumer, output_state.clone(), channel_count);
+    let player_tx = handle.get_player_tx();
+    let stream = handle.get_output().open_stream(
+        format,
+        callback,
+        Box::new(move |message| {
+            let _ = player_tx.send(PlayerCommand::StreamError {
+                track_generation,
+                message,
+            });
+        }),
+    )?;
+    drop(handle);
+
+    spawn_decoder_thread(
+        player_handle.clone(),
+        producer,
+        output_state,
+        track_generation,
+    );
+    Ok(stream)
 }
 
-fn write_data<T>(output: &mut [T], player_handle: Arc<Mutex<PlayerHandle>>)
-where
-    T: Sample + FromSample<f64>,
-{
-    let Ok(mut player_handle_guard) = player_handle.lock() else {
-        return;
-    };
+/// Feeds an output stream from the ring buffer the decoder thread fills.
+pub struct OutputCallback {
+    consumer: Consumer<f64>,
+    output_state: Arc<OutputState>,
+    channel_count: usize,
+    has_started: bool,
+}
 
-    let track_handle = player_handle_guard.get_mut_track_handle();
-    if let Some(track_handle) = track_handle {
-        if track_handle.has_finished() {
-            player_handle_guard
-                .trigger_next_track()
-                .expect("Couldn't trigger next track");
-            return;
+impl OutputCallback {
+    fn new(consumer: Consumer<f64>, output_state: Arc<OutputState>, channel_count: usize) -> Self {
+        Self {
+            consumer,
+            output_state,
+            channel_count,
+            has_started: false,
         }
-        for frame in output.chunks_mut(track_handle.track_metadata.channel_count) {
-            let Ok(samples) = track_handle.get_sample_buffer() else {
-                panic!("coulnd't fetch sample buffer");
-            };
-            if samples.len() == track_handle.track_metadata.channel_count {
-                for (channel, sample) in frame.iter_mut().enumerate() {
-                    let value: T = T::from_sample(samples[channel]);
-                    *sample = value;
-                }
+    }
+
+    /// Copies whatever the decoder thread has buffered into `output`, padding with silence,
+    /// and returns how many samples were copied.
+    ///
+    /// This runs on the realtime audio thread, so it must not lock, allocate or block.
+    pub(super) fn write_data<T>(&mut self, output: &mut [T]) -> usize
+    where
+        T: Sample + FromSample<f64>,
+    {
+        let available = self.consumer.slots().min(output.len());
+        let available = available - available % self.channel_count;
+        let volume = self.output_state.volume() * self.output_state.gain();
+
+        if let Ok(chunk) = self.consumer.read_chunk(available) {
+            for (sample, value) in output.iter_mut().zip(chunk) {
+                *sample = T::from_sample(value * volume);
             }
-            track_handle.increment_time();
         }
-    }
-}
+        for sample in output[available..].iter_mut() {
+            *sample = T::EQUILIBRIUM;
+        }
 
-pub(super) fn get_config(player_handle: &PlayerHandle) -> anyhow::Result<(&Device, StreamConfig)> {
-    let Some(track_data) = player_handle.get_track_handle() else {
-        anyhow::bail!("No track to play.");
-    };
-    let device = player_handle.get_device();
-    let channel_count = track_data.track_metadata.channel_count as u16;
-    let supported_configs = device.supported_output_configs()?;
-    for supported_config in supported_configs {
-        if channel_count == supported_config.channels() {
-            return Ok((
-                device,
-                supported_config
-                    .with_sample_rate(SampleRate(track_data.track_metadata.sample_rate))
-                    .into(),
-            ));
+        if available > 0 {
+            self.has_started = true;
+            self.output_state
+                .played_frames
+                .fetch_add((available / self.channel_count) as u64, Ordering::Relaxed);
+        }
+        if available < output.len() && self.has_started && !self.output_state.is_end_of_stream() {
+            self.output_state.underruns.fetch_add(1, Ordering::Relaxed);
         }
+        available
     }
-    anyhow::bail!("Couldn't build configuration")
 }
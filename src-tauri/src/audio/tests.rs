@@ -0,0 +1,631 @@
+//! End-to-end tests driving the player thread, with sinks instead of a sound card.
+
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::{channel, Receiver, Sender};
+use std::sync::{Arc, Condvar, Mutex};
+use std::time::{Duration, Instant};
+
+use serde_json::{json, Value};
+
+use super::clock::TickSource;
+use super::decoder::AudioFile;
+use super::history::{Listen, ListenLog, ListenOutcome};
+use super::output::{ErrorCallback, OutputBackend, OutputFormat, OutputStream};
+use super::player::{boot_player, PlaybackState, PlayerController, PlayerOptions};
+use super::queue::PlayQueue;
+use super::session::PlayerSession;
+use super::sink::{wav_header, NullOutput};
+use super::stream::OutputCallback;
+use crate::event::EventEmitter;
+
+pub(super) const SAMPLE_RATE: u32 = 8000;
+
+/// The WAV sample format of integer samples.
+const WAVE_FORMAT_PCM: u16 = 1;
+
+/// How long a test waits for something before failing, instead of hanging.
+const EVENT_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// How many frames a test pulls from the output at once, like a sound card would.
+const PULL_FRAMES: usize = 80;
+
+/// A directory for the files of one test, removed with everything in it on drop.
+pub(crate) struct TestDir(PathBuf);
+
+impl TestDir {
+    /// Creates the directory, named after `name` so tests running at once don't share it.
+    pub(crate) fn new(name: &str) -> Self {
+        let path = std::env::temp_dir().join(format!("amptree-{}-{name}", std::process::id()));
+        std::fs::create_dir_all(&path).unwrap();
+        Self(path)
+    }
+
+    pub(crate) fn path(&self, name: &str) -> String {
+        self.0.join(name).to_string_lossy().into_owned()
+    }
+}
+
+impl Drop for TestDir {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_dir_all(&self.0);
+    }
+}
+
+/// Writes a mono 16-bit WAV file of `frames` frames in `dir`, each sample made by `sample`
+/// from the index of its frame.
+pub(crate) fn write_test_wav<F>(
+    dir: &TestDir,
+    name: &str,
+    sample_rate: u32,
+    frames: u32,
+    sample: F,
+) -> String
+where
+    F: Fn(u32) -> i16,
+{
+    let path = dir.path(name);
+    let format = OutputFormat {
+        channels: 1,
+        sample_rate,
+    };
+    let mut bytes = wav_header(format, WAVE_FORMAT_PCM, 16, frames * 2);
+    for frame in 0..frames {
+        bytes.extend_from_slice(&sample(frame).to_le_bytes());
+    }
+    std::fs::write(&path, bytes).unwrap();
+    path
+}
+
+/// Writes a WAV file whose samples count up from `first`, so any sample tells where it came
+/// from.
+pub(super) fn write_counting_wav(dir: &TestDir, name: &str, first: i16, frames: u32) -> String {
+    write_test_wav(dir, name, SAMPLE_RATE, frames, |frame| first + frame as i16)
+}
+
+/// Turns samples played back into the integers `write_counting_wav` wrote.
+fn to_counts(samples: &[f32]) -> Vec<i64> {
+    samples
+        .iter()
+        .map(|sample| (sample * 32768.0).round() as i64)
+        .collect()
+}
+
+/// Keeps every event emitted, in order, with its payload.
+#[derive(Default)]
+struct RecordingEmitter {
+    events: Mutex<Vec<(String, Value)>>,
+    emitted: Condvar,
+}
+
+impl RecordingEmitter {
+    /// Waits until `done` holds for the events emitted so far, and returns them.
+    fn wait_until<F>(&self, done: F) -> Vec<(String, Value)>
+    where
+        F: Fn(&[(String, Value)]) -> bool,
+    {
+        let events = self.events.lock().unwrap();
+        let (events, result) = self
+            .emitted
+            .wait_timeout_while(events, EVENT_TIMEOUT, |events| !done(events))
+            .unwrap();
+        assert!(
+            !result.timed_out(),
+            "Timed out waiting for events: {events:?}"
+        );
+        events.clone()
+    }
+
+    /// Waits for the `count`th occurrence of `name`, and returns every event emitted so far.
+    fn wait_for(&self, name: &str, count: usize) -> Vec<(String, Value)> {
+        self.wait_until(|events| has_event(events, name, count))
+    }
+
+    /// Returns the events emitted so far if `done` holds for them.
+    fn check<F>(&self, done: F) -> Option<Vec<(String, Value)>>
+    where
+        F: Fn(&[(String, Value)]) -> bool,
+    {
+        let events = self.events.lock().unwrap();
+        done(&events).then(|| events.clone())
+    }
+
+    fn clear(&self) {
+        self.events.lock().unwrap().clear();
+    }
+}
+
+impl EventEmitter for Arc<RecordingEmitter> {
+    fn emit_event<T>(&self, event: &str, data: T) -> anyhow::Result<()>
+    where
+        T: serde::Serialize + Clone,
+    {
+        let Ok(mut events) = self.events.lock() else {
+            anyhow::bail!("Couldn't acquire events lock");
+        };
+        events.push((event.to_string(), serde_json::to_value(data)?));
+        self.emitted.notify_all();
+        Ok(())
+    }
+}
+
+fn has_event(events: &[(String, Value)], name: &str, count: usize) -> bool {
+    events.iter().filter(|(event, _)| event == name).count() >= count
+}
+
+fn names(events: &[(String, Value)]) -> Vec<&str> {
+    events.iter().map(|(name, _)| name.as_str()).collect()
+}
+
+fn payloads<'a>(events: &'a [(String, Value)], name: &str) -> Vec<&'a Value> {
+    events
+        .iter()
+        .filter(|(event, _)| event == name)
+        .map(|(_, payload)| payload)
+        .collect()
+}
+
+/// Ticks only when the test says so.
+struct ManualTicks(Receiver<()>);
+
+impl TickSource for ManualTicks {
+    fn wait_for_tick(&mut self) -> bool {
+        self.0.recv().is_ok()
+    }
+}
+
+/// The stream the player opened last, with whether it's playing.
+type PulledStream = (OutputFormat, OutputCallback, Arc<AtomicBool>);
+
+/// A sink the test pulls audio from, so playback goes as fast as the test asks for and
+/// doesn't depend on a clock. Only the stream opened last can be pulled from.
+#[derive(Clone, Default)]
+struct ManualOutput {
+    stream: Arc<Mutex<Option<PulledStream>>>,
+}
+
+impl ManualOutput {
+    /// Pulls up to `frames` frames from the stream if it's playing, and returns the samples
+    /// it had buffered.
+    fn pull(&self, frames: usize) -> Vec<f32> {
+        let mut stream = self.stream.lock().unwrap();
+        let Some((format, callback, is_playing)) = stream.as_mut() else {
+            return Vec::new();
+        };
+        if !is_playing.load(Ordering::Acquire) {
+            return Vec::new();
+        }
+        let mut samples = vec![0f32; frames * format.channels as usize];
+        let written = callback.write_data(&mut samples);
+        samples.truncate(written);
+        samples
+    }
+
+    /// Pulls exactly `frames` frames of mono audio, waiting for the decoder when needed.
+    fn pull_frames(&self, frames: usize) -> Vec<f32> {
+        let started_at = Instant::now();
+        let mut samples = Vec::new();
+        while samples.len() < frames {
+            let pulled = self.pull(PULL_FRAMES.min(frames - samples.len()));
+            if pulled.is_empty() {
+                wait_for_decoder(started_at);
+            }
+            samples.extend(pulled);
+        }
+        samples
+    }
+
+    /// Pulls audio until `done` holds for the events emitted, and returns what was pulled
+    /// along with the events.
+    fn play_until<F>(&self, events: &RecordingEmitter, done: F) -> (Vec<f32>, Vec<(String, Value)>)
+    where
+        F: Fn(&[(String, Value)]) -> bool,
+    {
+        let started_at = Instant::now();
+        let mut samples = Vec::new();
+        loop {
+            if let Some(events) = events.check(&done) {
+                return (samples, events);
+            }
+            let pulled = self.pull(PULL_FRAMES);
+            if pulled.is_empty() {
+                wait_for_decoder(started_at);
+            }
+            samples.extend(pulled);
+        }
+    }
+}
+
+/// Gives the decoder and player threads a moment when there's nothing to pull yet.
+fn wait_for_decoder(started_at: Instant) {
+    assert!(
+        started_at.elapsed() < EVENT_TIMEOUT,
+        "Timed out waiting for audio"
+    );
+    std::thread::sleep(Duration::from_millis(1));
+}
+
+impl OutputBackend for ManualOutput {
+    fn open_stream(
+        &self,
+        format: OutputFormat,
+        callback: OutputCallback,
+        _on_error: ErrorCallback,
+    ) -> anyhow::Result<Box<dyn OutputStream>> {
+        let is_playing = Arc::new(AtomicBool::new(false));
+        *self.stream.lock().unwrap() = Some((format, callback, is_playing.clone()));
+        Ok(Box::new(ManualStream {
+            output: self.clone(),
+            is_playing,
+        }))
+    }
+}
+
+struct ManualStream {
+    output: ManualOutput,
+    is_playing: Arc<AtomicBool>,
+}
+
+impl OutputStream for ManualStream {
+    fn play(&self) -> anyhow::Result<()> {
+        self.is_playing.store(true, Ordering::Release);
+        Ok(())
+    }
+
+    fn pause(&self) -> anyhow::Result<()> {
+        self.is_playing.store(false, Ordering::Release);
+        Ok(())
+    }
+}
+
+impl Drop for ManualStream {
+    /// Stops pulling from this stream, unless a newer one already replaced it.
+    fn drop(&mut self) {
+        let mut stream = self.output.stream.lock().unwrap();
+        if matches!(stream.as_ref(), Some((_, _, is_playing)) if Arc::ptr_eq(is_playing, &self.is_playing))
+        {
+            *stream = None;
+        }
+    }
+}
+
+struct TestPlayer {
+    controller: PlayerController,
+    events: Arc<RecordingEmitter>,
+    output: ManualOutput,
+    tick_tx: Sender<()>,
+}
+
+impl TestPlayer {
+    fn boot() -> Self {
+        Self::boot_with(PlayerOptions::default())
+    }
+
+    /// Boots with `options`, playing through a `ManualOutput` and ticking on demand.
+    fn boot_with(options: PlayerOptions) -> Self {
+        let (tx, rx) = channel();
+        let (tick_tx, tick_rx) = channel();
+        let events = Arc::new(RecordingEmitter::default());
+        let output = ManualOutput::default();
+        let options = PlayerOptions {
+            output: Some(Box::new(output.clone())),
+            ticks: Some(Box::new(ManualTicks(tick_rx))),
+            ..options
+        };
+        let controller = boot_player(tx, rx, events.clone(), options).unwrap();
+        controller.change_volume(1.0).unwrap();
+        // Tests only look at what happens after the player is set up.
+        events.wait_for("player:volume-changed", 1);
+        events.clear();
+        Self {
+            controller,
+            events,
+            output,
+            tick_tx,
+        }
+    }
+
+    fn tick(&self) {
+        self.tick_tx.send(()).unwrap();
+    }
+
+    fn play_until<F>(&self, done: F) -> (Vec<f32>, Vec<(String, Value)>)
+    where
+        F: Fn(&[(String, Value)]) -> bool,
+    {
+        self.output.play_until(&self.events, done)
+    }
+}
+
+#[test]
+fn test_plays_queue_to_the_end() {
+    let dir = TestDir::new("plays-queue");
+    let first = write_counting_wav(&dir, "first.wav", 0, 2000);
+    let second = write_counting_wav(&dir, "second.wav", 10_000, 2000);
+    let player = TestPlayer::boot();
+    let controller = &player.controller;
+
+    controller
+        .play_now(vec![AudioFile::new(first), AudioFile::new(second)])
+        .unwrap();
+    let (samples, _) = player.play_until(|events| has_event(events, "player:ended", 1));
+
+    let expected: Vec<i64> = (0..2000).chain(10_000..12_000).collect();
+    assert_eq!(to_counts(&samples), expected);
+    let state = controller.get_state().unwrap();
+    assert_eq!(state.state, PlaybackState::Stopped);
+    assert!(state.track.is_none());
+}
+
+#[test]
+fn test_seek_drops_buffered_audio() {
+    let dir = TestDir::new("seek-drops");
+    let track = write_counting_wav(&dir, "seek.wav", 0, SAMPLE_RATE);
+    let player = TestPlayer::boot();
+    let controller = &player.controller;
+
+    controller.play_now(vec![AudioFile::new(track)]).unwrap();
+    let before_seek = player.output.pull_frames(1000);
+    controller.pause().unwrap();
+    controller.seek(0.5).unwrap();
+    controller.resume().unwrap();
+    player.events.wait_for("player:state-changed", 3);
+    let (after_seek, _) = player.play_until(|events| has_event(events, "player:ended", 1));
+
+    // What played before the pause is followed straight by the second half of the track.
+    let expected: Vec<i64> = (0..1000).chain(4000..8000).collect();
+    assert_eq!(to_counts(&[before_seek, after_seek].concat()), expected);
+}
+
+#[test]
+fn test_null_output_plays_in_real_time() {
+    let dir = TestDir::new("real-time");
+    let track = write_counting_wav(&dir, "real-time.wav", 0, SAMPLE_RATE / 4);
+    let player = TestPlayer::boot();
+    let controller = &player.controller;
+    controller
+        .switch_output(Box::new(NullOutput::new(4.0)))
+        .unwrap();
+
+    let started_at = Instant::now();
+    controller.play_now(vec![AudioFile::new(track)]).unwrap();
+    player.events.wait_for("player:ended", 1);
+
+    // A quarter of a second of audio at four times real time.
+    assert!(started_at.elapsed() >= Duration::from_millis(50));
+}
+
+#[test]
+fn test_play_pause_seek_events() {
+    let dir = TestDir::new("play-pause-seek");
+    let track = write_counting_wav(&dir, "events.wav", 0, 10 * SAMPLE_RATE);
+    let player = TestPlayer::boot();
+    let controller = &player.controller;
+
+    controller
+        .play_now(vec![AudioFile::new(track.clone())])
+        .unwrap();
+    controller.pause().unwrap();
+    controller.seek(4.0).unwrap();
+    controller.resume().unwrap();
+    player.tick();
+    let events = player.events.wait_for("player:tick", 1);
+
+    assert_eq!(
+        names(&events),
+        [
+            "player:queue-changed",
+            "player:track-changed",
+            "player:state-changed",
+            "player:state-changed",
+            "player:state-changed",
+            "player:tick",
+        ]
+    );
+    assert_eq!(
+        payloads(&events, "player:state-changed"),
+        [
+            &json!({ "state": "playing" }),
+            &json!({ "state": "paused" }),
+            &json!({ "state": "playing" }),
+        ]
+    );
+    let track_info = payloads(&events, "player:track-changed")[0];
+    assert_eq!(track_info["file"]["path"], json!(track));
+    assert_eq!(track_info["durationSecs"], json!(10.0));
+
+    // Nothing was pulled, so playback is right where the seek left it.
+    let status = payloads(&events, "player:tick")[0];
+    assert_eq!(status["playedSecs"], json!(4.0));
+    assert_eq!(status["totalDurationSecs"], json!(10.0));
+}
+
+#[test]
+fn test_stop_rewinds_the_track() {
+    let dir = TestDir::new("stop-rewinds");
+    let track = write_counting_wav(&dir, "stop.wav", 0, SAMPLE_RATE);
+    let player = TestPlayer::boot();
+    let controller = &player.controller;
+
+    controller.play_now(vec![AudioFile::new(track)]).unwrap();
+    let before_stop = player.output.pull_frames(1000);
+    controller.stop().unwrap();
+    let events = player.events.wait_for("player:state-changed", 2);
+    assert_eq!(
+        payloads(&events, "player:state-changed")[1],
+        &json!({ "state": "stopped" })
+    );
+    let state = controller.get_state().unwrap();
+    assert!(state.track.is_some());
+    assert_eq!(state.status.unwrap().played_secs, 0.0);
+
+    controller.resume().unwrap();
+    let after_stop = player.output.pull_frames(1000);
+    assert_eq!(to_counts(&before_stop), to_counts(&after_stop));
+}
+
+#[test]
+fn test_volume_changes_are_emitted_while_stopped() {
+    let player = TestPlayer::boot();
+
+    player.controller.change_volume(0.5).unwrap();
+    let events = player.events.wait_for("player:volume-changed", 1);
+
+    assert_eq!(names(&events), ["player:volume-changed"]);
+    assert_eq!(
+        payloads(&events, "player:volume-changed"),
+        [&json!({ "volume": 0.5 })]
+    );
+}
+
+#[test]
+fn test_skip_and_queue_end_events() {
+    let dir = TestDir::new("skip-queue-end");
+    let first = write_counting_wav(&dir, "skipped.wav", 0, 10 * SAMPLE_RATE);
+    let second = write_counting_wav(&dir, "last.wav", 0, SAMPLE_RATE / 20);
+    let player = TestPlayer::boot();
+    let controller = &player.controller;
+
+    controller
+        .play_now(vec![
+            AudioFile::new(first.clone()),
+            AudioFile::new(second.clone()),
+        ])
+        .unwrap();
+    controller.skip().unwrap();
+    let (_, events) = player.play_until(|events| {
+        matches!(events.last(), Some((name, payload)) if name == "player:state-changed"
+            && payload["state"] == "stopped")
+    });
+
+    assert_eq!(
+        names(&events),
+        [
+            "player:queue-changed",
+            "player:track-changed",
+            "player:state-changed",
+            "player:queue-changed",
+            "player:track-changed",
+            "player:queue-changed",
+            "player:ended",
+            "player:track-changed",
+            "player:state-changed",
+        ]
+    );
+    let tracks: Vec<&Value> = payloads(&events, "player:track-changed")
+        .into_iter()
+        .map(|track_info| &track_info["file"]["path"])
+        .collect();
+    assert_eq!(tracks, [&json!(first), &json!(second), &Value::Null]);
+    let queues = payloads(&events, "player:queue-changed");
+    assert_eq!(queues[0]["upcoming"].as_array().unwrap().len(), 1);
+    assert_eq!(queues[1]["upcoming"], json!([]));
+    assert_eq!(queues[2]["current"], Value::Null);
+}
+
+#[test]
+fn test_previous_goes_back_through_jumped_tracks() {
+    let dir = TestDir::new("previous-jumped");
+    let tracks: Vec<String> = ["a", "b", "c"]
+        .iter()
+        .map(|name| write_counting_wav(&dir, &format!("{name}.wav"), 0, 10 * SAMPLE_RATE))
+        .collect();
+    let player = TestPlayer::boot();
+    let controller = &player.controller;
+
+    let files = tracks.iter().map(|path| AudioFile::new(path.clone()));
+    controller.play_now(files.collect()).unwrap();
+    player.events.wait_for("player:queue-changed", 1);
+    controller.jump_in_queue(1).unwrap();
+    controller.previous().unwrap();
+    let events = player.events.wait_for("player:track-changed", 3);
+
+    let played: Vec<&Value> = payloads(&events, "player:track-changed")
+        .into_iter()
+        .map(|track_info| &track_info["file"]["path"])
+        .collect();
+    assert_eq!(
+        played,
+        [&json!(tracks[0]), &json!(tracks[2]), &json!(tracks[1])]
+    );
+}
+
+/// Fails every emit, like a webview that went away.
+struct FailingEmitter;
+
+impl EventEmitter for FailingEmitter {
+    fn emit_event<T>(&self, _event: &str, _data: T) -> anyhow::Result<()>
+    where
+        T: serde::Serialize + Clone,
+    {
+        anyhow::bail!("Nobody is listening")
+    }
+}
+
+#[test]
+fn test_keeps_playing_when_emitting_fails() {
+    let dir = TestDir::new("failing-emitter");
+    let track = write_counting_wav(&dir, "failing-emitter.wav", 0, SAMPLE_RATE / 20);
+    let (tx, rx) = channel();
+    let (_tick_tx, tick_rx) = channel();
+    let events = Arc::new(RecordingEmitter::default());
+    let output = ManualOutput::default();
+    let options = PlayerOptions {
+        output: Some(Box::new(output.clone())),
+        ticks: Some(Box::new(ManualTicks(tick_rx))),
+        ..Default::default()
+    };
+    let controller = boot_player(tx, rx, (FailingEmitter, events.clone()), options).unwrap();
+
+    controller.play_now(vec![AudioFile::new(track)]).unwrap();
+    let (_, events) = output.play_until(&events, |events| has_event(events, "player:ended", 1));
+    assert_eq!(
+        names(&events)[..2],
+        ["player:queue-changed", "player:track-changed"]
+    );
+}
+
+/// Hands every listen recorded over to the test.
+struct ChannelLog(Sender<Listen>);
+
+impl ListenLog for ChannelLog {
+    fn record_listen(&self, listen: &Listen) -> anyhow::Result<()> {
+        self.0
+            .send(listen.clone())
+            .map_err(|_| anyhow::anyhow!("The test is over"))
+    }
+}
+
+#[test]
+fn test_records_only_heard_tracks() {
+    let dir = TestDir::new("heard-tracks");
+    let restored = write_counting_wav(&dir, "restored.wav", 0, SAMPLE_RATE);
+    let passed = write_counting_wav(&dir, "passed.wav", 0, SAMPLE_RATE);
+    let heard = write_counting_wav(&dir, "heard.wav", 0, SAMPLE_RATE / 20);
+    let mut queue = PlayQueue::default();
+    queue.append(AudioFile::new(passed));
+    queue.append(AudioFile::new(heard.clone()));
+    let session = PlayerSession {
+        queue,
+        current: Some(AudioFile::new(restored)),
+        position_secs: 0.5,
+        volume: 1.0,
+    };
+    let (listen_tx, listen_rx) = channel();
+    let player = TestPlayer::boot_with(PlayerOptions {
+        listen_log: Some(Box::new(ChannelLog(listen_tx))),
+        session: Some(session),
+        ..Default::default()
+    });
+    let controller = &player.controller;
+
+    // The restored track is never resumed, and the next one is only passed through.
+    controller.skip().unwrap();
+    controller.skip().unwrap();
+    player.play_until(|events| has_event(events, "player:ended", 1));
+
+    let listen = listen_rx.recv_timeout(EVENT_TIMEOUT).unwrap();
+    assert_eq!(listen.file.get_path(), heard);
+    assert_eq!(listen.outcome, ListenOutcome::Completed);
+    assert!(listen_rx.try_recv().is_err());
+}
@@ -0,0 +1,122 @@
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use rtrb::Producer;
+
+use super::decoder::TrackReader;
+use super::player::PlayerHandle;
+use super::stream::OutputState;
+
+/// How long the decoder thread sleeps when the ring buffer has no free slots.
+const FILL_INTERVAL: Duration = Duration::from_millis(5);
+
+/// Spawns the thread that decodes the current track into the output ring buffer.
+///
+/// The thread exits once the stream consuming the buffer is dropped or the player moves on
+/// to another track. When the track is fully decoded and played, it asks the player for the
+/// next one. It only holds the player while handing over decoded samples, so reading from
+/// disk doesn't hold up commands or status updates.
+pub(super) fn spawn_decoder_thread(
+    player_handle: Arc<Mutex<PlayerHandle>>,
+    producer: Producer<f64>,
+    output_state: Arc<OutputState>,
+    track_generation: u64,
+) {
+    std::thread::spawn(move || {
+        let result = run_decoder(player_handle, producer, output_state, track_generation);
+        if let Err(err) = result {
+            eprintln!("Error in decoder thread: {:?}", err);
+        }
+    });
+}
+
+fn run_decoder(
+    player_handle: Arc<Mutex<PlayerHandle>>,
+    mut producer: Producer<f64>,
+    output_state: Arc<OutputState>,
+    track_generation: u64,
+) -> anyhow::Result<()> {
+    let Some((reader, seeks)) = get_track_reader(&player_handle, track_generation)? else {
+        return Ok(());
+    };
+    let mut pending: Vec<f64> = Vec::new();
+    loop {
+        if producer.is_abandoned() {
+            return Ok(());
+        }
+
+        if pending.is_empty() {
+            let (has_more, progress) = {
+                let Ok(mut reader) = reader.lock() else {
+                    anyhow::bail!("Couldn't acquire reader lock");
+                };
+                // A seek moved the reader for the decoder thread of the next generation
+                if reader.seeks() != seeks {
+                    return Ok(());
+                }
+                (reader.decode_next(&mut pending)?, reader.progress())
+            };
+
+            let Ok(mut player_handle_guard) = player_handle.lock() else {
+                anyhow::bail!("Couldn't acquire handle lock");
+            };
+            if player_handle_guard.get_track_generation() != track_generation {
+                return Ok(());
+            }
+            player_handle_guard.publish_decoded(&mut pending, progress);
+            if !has_more {
+                drop(player_handle_guard);
+                output_state.set_end_of_stream(true);
+                return finish_track(&player_handle, &producer, track_generation);
+            }
+        }
+
+        let count = producer.slots().min(pending.len());
+        if count == 0 {
+            std::thread::sleep(FILL_INTERVAL);
+            continue;
+        }
+        if let Ok(chunk) = producer.write_chunk_uninit(count) {
+            chunk.fill_from_iter(pending.drain(..count));
+        }
+    }
+}
+
+/// Returns the reader of the current track, or `None` when the player already moved on from
+/// `track_generation`.
+///
+/// Reads the seek count under the player lock, as seeks bump the generation under it too.
+fn get_track_reader(
+    player_handle: &Arc<Mutex<PlayerHandle>>,
+    track_generation: u64,
+) -> anyhow::Result<Option<(Arc<Mutex<TrackReader>>, u64)>> {
+    let Ok(player_handle_guard) = player_handle.lock() else {
+        anyhow::bail!("Couldn't acquire handle lock");
+    };
+    if player_handle_guard.get_track_generation() != track_generation {
+        return Ok(None);
+    }
+    player_handle_guard.get_track_reader()
+}
+
+/// Waits for the output to drain the ring buffer and then skips to the next track.
+fn finish_track(
+    player_handle: &Arc<Mutex<PlayerHandle>>,
+    producer: &Producer<f64>,
+    track_generation: u64,
+) -> anyhow::Result<()> {
+    while producer.slots() < producer.buffer().capacity() {
+        if producer.is_abandoned() {
+            return Ok(());
+        }
+        std::thread::sleep(FILL_INTERVAL);
+    }
+
+    let Ok(player_handle_guard) = player_handle.lock() else {
+        anyhow::bail!("Couldn't acquire handle lock");
+    };
+    if player_handle_guard.get_track_generation() == track_generation {
+        player_handle_guard.trigger_next_track()?;
+    }
+    Ok(())
+}
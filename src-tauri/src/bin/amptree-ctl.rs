@@ -0,0 +1,189 @@
+//! Controls a running instance over its local socket, printing JSON for scripts.
+//!
+//! Failures exit with a non-zero status. When the player turns a request down, its JSON error
+//! object is printed on stderr as is.
+
+const USAGE: &str = "Usage: amptree-ctl [--socket <path>] <command>
+
+Commands:
+  play [path]        Play a file now, or resume
+  pause              Pause playback
+  resume             Resume playback
+  toggle             Pause or resume
+  next               Skip to the next track
+  previous           Go back a track
+  seek <seconds>     Seek in the current track
+  volume <volume>    Set the volume, from 0 to 1
+  queue [path]       Queue a file, or print the queue
+  play-next <path>   Play a file after the current one
+  clear              Clear the upcoming tracks
+  status             Print the player state
+  search <query>     Print the library tracks matching a query
+  events             Print player events as they happen";
+
+#[cfg(unix)]
+fn main() {
+    if let Err(err) = run() {
+        eprintln!("{err}");
+        std::process::exit(1);
+    }
+}
+
+#[cfg(unix)]
+fn run() -> anyhow::Result<()> {
+    use std::io::{BufRead, BufReader, Write};
+    use std::os::unix::net::UnixStream;
+
+    use serde_json::{json, Value};
+
+    use app_lib::control::default_socket_path;
+
+    let mut socket_path = default_socket_path();
+    let mut args: Vec<String> = std::env::args().skip(1).collect();
+    if args.first().map(String::as_str) == Some("--socket") {
+        if args.len() < 2 {
+            anyhow::bail!("--socket needs a path.");
+        }
+        socket_path = args.remove(1).into();
+        args.remove(0);
+    }
+    let Some((command, args)) = args.split_first() else {
+        println!("{USAGE}");
+        return Ok(());
+    };
+    let Some(request) = to_request(command, args)? else {
+        println!("{USAGE}");
+        return Ok(());
+    };
+
+    let stream = UnixStream::connect(&socket_path)
+        .map_err(|err| anyhow::anyhow!("Couldn't connect to {}: {}", socket_path.display(), err))?;
+    let mut writer = stream.try_clone()?;
+    let mut lines = BufReader::new(stream).lines();
+    let mut send = |request: &Value| -> anyhow::Result<Value> {
+        writeln!(writer, "{request}")?;
+        let Some(line) = lines.next().transpose()? else {
+            anyhow::bail!("The player hung up.");
+        };
+        let response: Value = serde_json::from_str(&line)?;
+        if let Some(error) = response.get("error") {
+            anyhow::bail!("{error}");
+        }
+        Ok(response["ok"].clone())
+    };
+
+    let result = send(&request)?;
+    match command.as_str() {
+        "toggle" => {
+            let command = match result["state"].as_str() {
+                Some("playing") => "pause",
+                _ => "resume",
+            };
+            send(&json!({ "command": command }))?;
+        }
+        "events" => {
+            for line in lines {
+                println!("{}", line?);
+            }
+        }
+        _ if !result.is_null() => println!("{}", serde_json::to_string_pretty(&result)?),
+        _ => {}
+    }
+    Ok(())
+}
+
+/// The request a command sends, or `None` when it asks for help. Paths are made absolute, as
+/// the player doesn't run in the same directory.
+#[cfg(unix)]
+fn to_request(command: &str, args: &[String]) -> anyhow::Result<Option<serde_json::Value>> {
+    use serde_json::json;
+
+    let number = |name: &str| -> anyhow::Result<f64> {
+        match args {
+            [value] => Ok(value.parse()?),
+            _ => anyhow::bail!("{command} needs {name}."),
+        }
+    };
+    let absolute = |path: &str| -> anyhow::Result<String> {
+        let path = std::fs::canonicalize(path)
+            .map_err(|err| anyhow::anyhow!("Couldn't find {}: {}", path, err))?;
+        Ok(path.to_string_lossy().into_owned())
+    };
+    let request = match (command, args) {
+        ("play", []) | ("resume", []) => json!({ "command": "resume" }),
+        ("play", [path]) => json!({ "command": "play", "path": absolute(path)? }),
+        ("pause", []) => json!({ "command": "pause" }),
+        ("next", []) => json!({ "command": "skip" }),
+        ("previous", []) => json!({ "command": "previous" }),
+        ("seek", _) => json!({ "command": "seek", "seconds": number("a number of seconds")? }),
+        ("volume", _) => json!({ "command": "setVolume", "volume": number("a volume")? }),
+        ("queue", []) => json!({ "command": "getQueue" }),
+        ("queue", [path]) => json!({ "command": "queue", "path": absolute(path)? }),
+        ("play-next", [path]) => json!({ "command": "playNext", "path": absolute(path)? }),
+        ("clear", []) => json!({ "command": "clearQueue" }),
+        ("status", []) | ("toggle", []) => json!({ "command": "getState" }),
+        ("search", [_, ..]) => json!({ "command": "search", "query": args.join(" ") }),
+        ("events", []) => json!({ "command": "subscribe" }),
+        ("help" | "--help" | "-h", _) => return Ok(None),
+        _ => anyhow::bail!("Unknown command or arguments: {}\n\n{USAGE}", command),
+    };
+    Ok(Some(request))
+}
+
+#[cfg(not(unix))]
+fn main() -> anyhow::Result<()> {
+    anyhow::bail!("amptree-ctl only talks to Unix sockets for now.\n\n{USAGE}")
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+
+    fn request(command: &str, args: &[&str]) -> anyhow::Result<Option<serde_json::Value>> {
+        let args: Vec<String> = args.iter().map(|arg| arg.to_string()).collect();
+        to_request(command, &args)
+    }
+
+    #[test]
+    fn test_maps_commands_to_requests() {
+        let resume = json!({ "command": "resume" });
+        assert_eq!(request("play", &[]).unwrap(), Some(resume));
+        let seek = json!({ "command": "seek", "seconds": 42.5 });
+        assert_eq!(request("seek", &["42.5"]).unwrap(), Some(seek));
+        let search = json!({ "command": "search", "query": "low sunflower" });
+        assert_eq!(
+            request("search", &["low", "sunflower"]).unwrap(),
+            Some(search)
+        );
+        assert_eq!(request("--help", &[]).unwrap(), None);
+        assert!(request("seek", &[]).is_err());
+        assert!(request("volume", &["loud"]).is_err());
+        assert!(request("pause", &["now"]).is_err());
+    }
+
+    #[test]
+    fn test_sends_absolute_paths() {
+        let directory = std::env::temp_dir().join(format!("amptree-ctl-{}", std::process::id()));
+        std::fs::create_dir_all(&directory).unwrap();
+        std::fs::write(directory.join("song.flac"), "").unwrap();
+        let relative = directory
+            .join("..")
+            .join(directory.file_name().unwrap())
+            .join("song.flac");
+        let absolute = std::fs::canonicalize(directory.join("song.flac")).unwrap();
+        let absolute = absolute.to_string_lossy();
+
+        for (command, name) in [
+            ("play", "play"),
+            ("queue", "queue"),
+            ("play-next", "playNext"),
+        ] {
+            let request = request(command, &[&relative.to_string_lossy()]).unwrap();
+            assert_eq!(request, Some(json!({ "command": name, "path": absolute })));
+        }
+        assert!(request("play", &["/nowhere/song.flac"]).is_err());
+        std::fs::remove_dir_all(directory).unwrap();
+    }
+}
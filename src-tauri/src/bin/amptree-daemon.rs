@@ -0,0 +1,123 @@
+//! Runs the player without a window, controlled over a local socket.
+
+#[cfg(unix)]
+fn main() -> anyhow::Result<()> {
+    use std::sync::Arc;
+
+    use app_lib::audio::boot_player;
+    use app_lib::control::{
+        default_socket_path, serve, serve_mpd, serve_remote, serve_subsonic, Services,
+    };
+    use app_lib::database::get_connection;
+    use app_lib::event::EventBus;
+    use app_lib::library::{Library, ListenRecorder};
+    use app_lib::load_player_options;
+    use app_lib::settings::Settings;
+    use futures_util::future::{select, Either};
+    use tokio::signal::unix::{signal, SignalKind};
+
+    let mut socket_path = default_socket_path();
+    let mut mpd_address = None;
+    let mut args = std::env::args().skip(1);
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--socket" => {
+                let Some(path) = args.next() else {
+                    anyhow::bail!("--socket needs a path.");
+                };
+                socket_path = path.into();
+            }
+            "--mpd" => {
+                let Some(address) = args.next() else {
+                    anyhow::bail!("--mpd needs an address, like 127.0.0.1:6600.");
+                };
+                mpd_address = Some(address);
+            }
+            "--help" | "-h" => {
+                println!("Usage: amptree-daemon [--socket <path>] [--mpd <address>]");
+                return Ok(());
+            }
+            _ => anyhow::bail!("Unknown argument: {}", arg),
+        }
+    }
+
+    let events = EventBus::default();
+    let settings = Settings::new(get_connection()?);
+    let subsonic = settings.get_subsonic()?;
+    let remote = settings.get_remote()?;
+    // `--mpd` serves MPD even when the settings leave it off.
+    let mpd = settings.get_mpd()?;
+    let mpd_address = mpd_address.or_else(|| mpd.enabled.then(|| mpd.address));
+    let (tx, rx) = std::sync::mpsc::channel();
+    let controller = boot_player(tx, rx, events.clone(), load_player_options(&settings)?)?;
+    let library = Library::new(get_connection()?);
+    library.analyze_loudness(events.clone());
+    let services = Services {
+        controller: controller.clone(),
+        library: Arc::new(library),
+        settings: Arc::new(settings),
+        events,
+    };
+
+    println!("Listening on {}", socket_path.display());
+    let runtime = tokio::runtime::Runtime::new()?;
+    let result = runtime.block_on(async {
+        if let Some(address) = mpd_address {
+            let services = services.clone();
+            println!("MPD clients can connect to {}", address);
+            tokio::spawn(async move {
+                if let Err(err) = serve_mpd(&address, services).await {
+                    eprintln!("Error in MPD server: {:?}", err);
+                }
+            });
+        }
+        if remote.enabled {
+            let services = services.clone();
+            println!("Remotes can connect to {}", remote.address());
+            tokio::spawn(async move {
+                if let Err(err) = serve_remote(remote, services).await {
+                    eprintln!("Error in remote control server: {:?}", err);
+                }
+            });
+        }
+        if subsonic.enabled {
+            let services = services.clone();
+            let listens = ListenRecorder::new(get_connection()?);
+            println!("Subsonic clients can connect to {}", subsonic.address);
+            tokio::spawn(async move {
+                if let Err(err) = serve_subsonic(subsonic, services, listens).await {
+                    eprintln!("Error in Subsonic server: {:?}", err);
+                }
+            });
+        }
+        #[cfg(target_os = "linux")]
+        {
+            let services = services.clone();
+            tokio::spawn(async move {
+                if let Err(err) = app_lib::control::serve_mpris(services).await {
+                    eprintln!("Couldn't serve MPRIS: {:?}", err);
+                }
+            });
+        }
+        // Stopping the daemon shouldn't lose the session, so a signal ends the control server
+        // like an error would and the session is saved below.
+        let mut terminate = signal(SignalKind::terminate())?;
+        let server = Box::pin(serve(&socket_path, services));
+        let interrupted = Box::pin(tokio::signal::ctrl_c());
+        let terminated = Box::pin(terminate.recv());
+        let result = match select(server, select(interrupted, terminated)).await {
+            Either::Left((result, _)) => result,
+            Either::Right(_) => Ok(()),
+        };
+        result
+    });
+    if let Err(err) = controller.save_session() {
+        eprintln!("Couldn't save player session: {:?}", err);
+    }
+    result
+}
+
+#[cfg(not(unix))]
+fn main() -> anyhow::Result<()> {
+    anyhow::bail!("The daemon only listens on Unix sockets for now.")
+}
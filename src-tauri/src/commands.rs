@@ -1,63 +1,350 @@
-use tauri::State;
+use tauri::{AppHandle, State};
 
-use crate::{audio::PlayerController, library::Library};
+use crate::{
+    audio::{
+        self, DeviceSelection, EqualizerSettings, OutputDeviceInfo, PlayerController, PlayerState,
+        QueueSnapshot, RepeatMode, ReplayGainConfig, ShuffleMode,
+    },
+    control::{
+        handle_request, new_pairing_token, ControlRequest, MpdConfig, RemoteConfig, Services,
+        SubsonicConfig,
+    },
+    error::CommandError,
+    library::{Library, ListenEntry, Scrobble, ScrobbleFormat, TrackPlays, TrackSelection},
+    settings::{EqualizerPreset, Settings},
+};
 
 #[tauri::command]
-pub async fn play_audio(
-    path: String,
+pub async fn play_audio(path: String, services: State<'_, Services>) -> Result<(), CommandError> {
+    handle(&services, ControlRequest::Play { path }).await
+}
+
+#[tauri::command]
+pub async fn queue(path: String, services: State<'_, Services>) -> Result<(), CommandError> {
+    handle(&services, ControlRequest::Queue { path }).await
+}
+
+#[tauri::command]
+pub async fn play_selection(
+    selection: TrackSelection,
+    services: State<'_, Services>,
+) -> Result<(), CommandError> {
+    handle(&services, ControlRequest::PlaySelection { selection }).await
+}
+
+#[tauri::command]
+pub async fn queue_selection(
+    selection: TrackSelection,
+    services: State<'_, Services>,
+) -> Result<(), CommandError> {
+    handle(&services, ControlRequest::QueueSelection { selection }).await
+}
+
+#[tauri::command]
+pub async fn play_next(path: String, services: State<'_, Services>) -> Result<(), CommandError> {
+    handle(&services, ControlRequest::PlayNext { path }).await
+}
+
+#[tauri::command]
+pub async fn get_player_state(
     controller: State<'_, PlayerController>,
-    library: State<'_, Library>,
-) -> Result<(), String> {
-    let result = controller.play_now(path);
-    let a = library.scan("/Users/pedrovietro/Downloads").await;
-    convert_anyhow_result(result)
+) -> Result<PlayerState, CommandError> {
+    controller.get_state().map_err(CommandError::from)
 }
 
 #[tauri::command]
-pub async fn queue(path: String, controller: State<'_, PlayerController>) -> Result<(), String> {
-    let result = controller.queue(path);
-    convert_anyhow_result(result)
+pub async fn get_queue(
+    controller: State<'_, PlayerController>,
+) -> Result<QueueSnapshot, CommandError> {
+    controller.get_queue().map_err(CommandError::from)
+}
+
+#[tauri::command]
+pub async fn remove_from_queue(
+    index: usize,
+    services: State<'_, Services>,
+) -> Result<(), CommandError> {
+    handle(&services, ControlRequest::RemoveFromQueue { index }).await
+}
+
+#[tauri::command]
+pub async fn move_in_queue(
+    from: usize,
+    to: usize,
+    services: State<'_, Services>,
+) -> Result<(), CommandError> {
+    handle(&services, ControlRequest::MoveInQueue { from, to }).await
+}
+
+#[tauri::command]
+pub async fn clear_queue(services: State<'_, Services>) -> Result<(), CommandError> {
+    handle(&services, ControlRequest::ClearQueue).await
+}
+
+#[tauri::command]
+pub async fn jump_in_queue(
+    index: usize,
+    services: State<'_, Services>,
+) -> Result<(), CommandError> {
+    handle(&services, ControlRequest::JumpInQueue { index }).await
+}
+
+#[tauri::command]
+pub async fn set_shuffle(
+    shuffle: ShuffleMode,
+    services: State<'_, Services>,
+) -> Result<(), CommandError> {
+    handle(&services, ControlRequest::SetShuffle { shuffle }).await
+}
+
+#[tauri::command]
+pub async fn set_repeat(
+    repeat: RepeatMode,
+    services: State<'_, Services>,
+) -> Result<(), CommandError> {
+    handle(&services, ControlRequest::SetRepeat { repeat }).await
+}
+
+#[tauri::command]
+pub async fn skip(services: State<'_, Services>) -> Result<(), CommandError> {
+    handle(&services, ControlRequest::Skip).await
+}
+
+#[tauri::command]
+pub async fn previous(services: State<'_, Services>) -> Result<(), CommandError> {
+    handle(&services, ControlRequest::Previous).await
+}
+
+#[tauri::command]
+pub async fn pause(services: State<'_, Services>) -> Result<(), CommandError> {
+    handle(&services, ControlRequest::Pause).await
+}
+
+#[tauri::command]
+pub async fn resume(services: State<'_, Services>) -> Result<(), CommandError> {
+    handle(&services, ControlRequest::Resume).await
+}
+
+#[tauri::command]
+pub async fn seek(seconds: f64, services: State<'_, Services>) -> Result<(), CommandError> {
+    handle(&services, ControlRequest::Seek { seconds }).await
+}
+
+#[tauri::command]
+pub async fn change_volume(volume: f64, services: State<'_, Services>) -> Result<(), CommandError> {
+    handle(&services, ControlRequest::SetVolume { volume }).await
+}
+
+#[tauri::command]
+pub async fn list_audio_hosts() -> Result<Vec<String>, CommandError> {
+    Ok(audio::list_hosts())
+}
+
+#[tauri::command]
+pub async fn list_output_devices(
+    host: Option<String>,
+) -> Result<Vec<OutputDeviceInfo>, CommandError> {
+    audio::list_output_devices(host.as_deref()).map_err(CommandError::from)
 }
 
 #[tauri::command]
-pub async fn skip(controller: State<'_, PlayerController>) -> Result<(), String> {
-    let result = controller.skip();
+pub async fn select_output_device(
+    host: Option<String>,
+    name: String,
+    controller: State<'_, PlayerController>,
+    settings: State<'_, Settings>,
+) -> Result<(), CommandError> {
+    let selection = DeviceSelection { host, name };
+    let result = audio::find_output_device(&selection)
+        .and_then(|device| controller.switch_device(device))
+        .and_then(|_| settings.set_output_device(&selection));
     convert_anyhow_result(result)
 }
 
 #[tauri::command]
-pub async fn pause(controller: State<'_, PlayerController>) -> Result<(), String> {
-    let result = controller.pause();
+pub async fn get_replay_gain(
+    settings: State<'_, Settings>,
+) -> Result<ReplayGainConfig, CommandError> {
+    settings.get_replay_gain().map_err(CommandError::from)
+}
+
+#[tauri::command]
+pub async fn set_replay_gain(
+    config: ReplayGainConfig,
+    controller: State<'_, PlayerController>,
+    settings: State<'_, Settings>,
+) -> Result<(), CommandError> {
+    let result = controller
+        .set_replay_gain(config)
+        .and_then(|_| settings.set_replay_gain(&config));
     convert_anyhow_result(result)
 }
 
 #[tauri::command]
-pub async fn resume(controller: State<'_, PlayerController>) -> Result<(), String> {
-    let result = controller.resume();
+pub async fn get_subsonic_settings(
+    settings: State<'_, Settings>,
+) -> Result<SubsonicConfig, CommandError> {
+    settings.get_subsonic().map_err(CommandError::from)
+}
+
+/// Saves the Subsonic server setup, used from the next start.
+#[tauri::command]
+pub async fn set_subsonic_settings(
+    config: SubsonicConfig,
+    settings: State<'_, Settings>,
+) -> Result<(), CommandError> {
+    settings.set_subsonic(&config).map_err(CommandError::from)
+}
+
+#[tauri::command]
+pub async fn get_remote_settings(
+    settings: State<'_, Settings>,
+) -> Result<RemoteConfig, CommandError> {
+    settings.get_remote().map_err(CommandError::from)
+}
+
+/// Saves the remote control setup, used from the next start.
+#[tauri::command]
+pub async fn set_remote_settings(
+    config: RemoteConfig,
+    settings: State<'_, Settings>,
+) -> Result<(), CommandError> {
+    settings.set_remote(&config).map_err(CommandError::from)
+}
+
+#[tauri::command]
+pub async fn get_mpd_settings(settings: State<'_, Settings>) -> Result<MpdConfig, CommandError> {
+    settings.get_mpd().map_err(CommandError::from)
+}
+
+/// Saves the MPD server setup, used from the next start.
+#[tauri::command]
+pub async fn set_mpd_settings(
+    config: MpdConfig,
+    settings: State<'_, Settings>,
+) -> Result<(), CommandError> {
+    settings.set_mpd(&config).map_err(CommandError::from)
+}
+
+/// Unpairs every remote by making up a new token, which is returned.
+#[tauri::command]
+pub async fn reset_remote_token(settings: State<'_, Settings>) -> Result<String, CommandError> {
+    let result = settings.get_remote().and_then(|mut config| {
+        config.token = new_pairing_token();
+        settings.set_remote(&config)?;
+        Ok(config.token)
+    });
+    result.map_err(CommandError::from)
+}
+
+#[tauri::command]
+pub async fn get_equalizer(
+    settings: State<'_, Settings>,
+) -> Result<EqualizerSettings, CommandError> {
+    settings.get_equalizer().map_err(CommandError::from)
+}
+
+#[tauri::command]
+pub async fn set_equalizer(
+    equalizer: EqualizerSettings,
+    controller: State<'_, PlayerController>,
+    settings: State<'_, Settings>,
+) -> Result<(), CommandError> {
+    let result = controller
+        .set_equalizer(equalizer.clone())
+        .and_then(|_| settings.set_equalizer(&equalizer));
     convert_anyhow_result(result)
 }
+
+#[tauri::command]
+pub async fn list_equalizer_presets(
+    settings: State<'_, Settings>,
+) -> Result<Vec<EqualizerPreset>, CommandError> {
+    settings
+        .list_equalizer_presets()
+        .map_err(CommandError::from)
+}
+
 #[tauri::command]
-pub async fn seek(seconds: usize, controller: State<'_, PlayerController>) -> Result<(), String> {
-    let result = controller.seek(seconds);
+pub async fn save_equalizer_preset(
+    name: String,
+    equalizer: EqualizerSettings,
+    settings: State<'_, Settings>,
+) -> Result<(), CommandError> {
+    let result = settings.save_equalizer_preset(&name, &equalizer);
     convert_anyhow_result(result)
 }
 
 #[tauri::command]
-pub async fn change_volume(
-    volume: f64,
-    controller: State<'_, PlayerController>,
-) -> Result<(), String> {
-    let result = controller.change_volume(volume);
+pub async fn delete_equalizer_preset(
+    name: String,
+    settings: State<'_, Settings>,
+) -> Result<(), CommandError> {
+    let result = settings.delete_equalizer_preset(&name);
     convert_anyhow_result(result)
 }
 
 #[tauri::command]
-pub async fn scan_folder(path: String, state: State<'_, PlayerController>) -> Result<(), String> {
-    // let result = state.scan_folder(path);
-    // convert_anyhow_result(result)
+pub async fn analyze_loudness(
+    app_handle: AppHandle,
+    library: State<'_, Library>,
+) -> Result<bool, CommandError> {
+    Ok(library.analyze_loudness(app_handle))
+}
+
+#[tauri::command]
+pub async fn cancel_loudness_analysis(library: State<'_, Library>) -> Result<(), CommandError> {
+    library.cancel_loudness_analysis();
     Ok(())
 }
 
-fn convert_anyhow_result(result: anyhow::Result<()>) -> Result<(), String> {
-    result.map_err(|e| e.to_string())
+#[tauri::command]
+pub async fn get_recently_played(
+    limit: Option<usize>,
+    library: State<'_, Library>,
+) -> Result<Vec<ListenEntry>, CommandError> {
+    Ok(library.get_recently_played(limit.unwrap_or(50)).await?)
+}
+
+#[tauri::command]
+pub async fn get_most_played(
+    since: i64,
+    until: Option<i64>,
+    limit: Option<usize>,
+    library: State<'_, Library>,
+) -> Result<Vec<TrackPlays>, CommandError> {
+    let tracks = library
+        .get_most_played(since, until, limit.unwrap_or(50))
+        .await?;
+    Ok(tracks)
+}
+
+#[tauri::command]
+pub async fn get_scrobbles(
+    include_exported: Option<bool>,
+    library: State<'_, Library>,
+) -> Result<Vec<Scrobble>, CommandError> {
+    Ok(library
+        .get_scrobbles(include_exported.unwrap_or(false))
+        .await?)
+}
+
+#[tauri::command]
+pub async fn export_scrobbles(
+    format: ScrobbleFormat,
+    path: String,
+    library: State<'_, Library>,
+) -> Result<usize, CommandError> {
+    Ok(library
+        .export_scrobbles(format, std::path::Path::new(&path))
+        .await?)
+}
+
+/// Runs a command the way the other frontends do, so they all behave the same.
+async fn handle(services: &Services, request: ControlRequest) -> Result<(), CommandError> {
+    handle_request(services, request).await.map(|_| ())
+}
+
+fn convert_anyhow_result(result: anyhow::Result<()>) -> Result<(), CommandError> {
+    result.map_err(CommandError::from)
 }
@@ -0,0 +1,177 @@
+//! Just enough HTTP/1.1 to answer one request per connection.
+
+use tokio::io::{AsyncBufRead, AsyncBufReadExt, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+
+/// Requests with a longer head are refused, so a client can't make us buffer forever.
+const MAX_HEAD_BYTES: u64 = 16 * 1024;
+/// Bodies only carry parameters or a command, which never need more.
+const MAX_BODY_BYTES: usize = 64 * 1024;
+
+pub(super) struct Request {
+    pub method: String,
+    pub path: String,
+    headers: Vec<(String, String)>,
+    /// Query string and form parameters, in order. Some, like `id`, can repeat.
+    params: Vec<(String, String)>,
+    pub body: Vec<u8>,
+}
+
+impl Request {
+    pub fn header(&self, name: &str) -> Option<&str> {
+        self.headers
+            .iter()
+            .find(|(header, _)| header.eq_ignore_ascii_case(name))
+            .map(|(_, value)| value.as_str())
+    }
+
+    pub fn param(&self, name: &str) -> Option<&str> {
+        self.params
+            .iter()
+            .find(|(param, _)| param == name)
+            .map(|(_, value)| value.as_str())
+    }
+
+    pub fn params<'a>(&'a self, name: &'a str) -> impl Iterator<Item = &'a str> + 'a {
+        self.params
+            .iter()
+            .filter(move |(param, _)| param == name)
+            .map(|(_, value)| value.as_str())
+    }
+}
+
+pub(super) async fn read_request<R>(reader: &mut R) -> anyhow::Result<Request>
+where
+    R: AsyncBufRead + Unpin,
+{
+    let mut head = (&mut *reader).take(MAX_HEAD_BYTES);
+    let mut request_line = String::new();
+    head.read_line(&mut request_line).await?;
+    let mut parts = request_line.split_whitespace();
+    let (Some(method), Some(target)) = (parts.next(), parts.next()) else {
+        anyhow::bail!("Malformed request line: {request_line:?}");
+    };
+    let (path, query) = target.split_once('?').unwrap_or((target, ""));
+    let mut request = Request {
+        method: method.to_string(),
+        path: path.to_string(),
+        headers: Vec::new(),
+        params: form_urlencoded::parse(query.as_bytes())
+            .into_owned()
+            .collect(),
+        body: Vec::new(),
+    };
+    loop {
+        let mut line = String::new();
+        if head.read_line(&mut line).await? == 0 {
+            anyhow::bail!("Request head too long or cut short");
+        }
+        let line = line.trim_end();
+        if line.is_empty() {
+            break;
+        }
+        if let Some((name, value)) = line.split_once(':') {
+            let header = (name.trim().to_string(), value.trim().to_string());
+            request.headers.push(header);
+        }
+    }
+
+    let body_length = request
+        .header("Content-Length")
+        .and_then(|length| length.parse().ok());
+    if let Some(length) = body_length {
+        if length > MAX_BODY_BYTES {
+            anyhow::bail!("Request body too long: {length} bytes");
+        }
+        request.body = vec![0; length];
+        reader.read_exact(&mut request.body).await?;
+    }
+    let is_form = request
+        .header("Content-Type")
+        .map_or(false, |content_type| {
+            content_type.starts_with("application/x-www-form-urlencoded")
+        });
+    if is_form {
+        let form = form_urlencoded::parse(&request.body).into_owned();
+        request.params.extend(form);
+    }
+    Ok(request)
+}
+
+pub(super) enum Body {
+    Bytes(Vec<u8>),
+    /// `length` bytes read from where the file is at.
+    File {
+        file: tokio::fs::File,
+        length: u64,
+    },
+}
+
+pub(super) struct Response {
+    pub status: u16,
+    pub headers: Vec<(&'static str, String)>,
+    pub body: Body,
+}
+
+impl Response {
+    pub fn new(status: u16, content_type: &str, body: Vec<u8>) -> Self {
+        Self {
+            status,
+            headers: vec![("Content-Type", content_type.to_string())],
+            body: Body::Bytes(body),
+        }
+    }
+}
+
+pub(super) async fn write_response<W>(writer: &mut W, response: Response) -> anyhow::Result<()>
+where
+    W: AsyncWrite + Unpin,
+{
+    let reason = match response.status {
+        200 => "OK",
+        204 => "No Content",
+        206 => "Partial Content",
+        400 => "Bad Request",
+        401 => "Unauthorized",
+        404 => "Not Found",
+        405 => "Method Not Allowed",
+        416 => "Range Not Satisfiable",
+        _ => "Internal Server Error",
+    };
+    let length = match &response.body {
+        Body::Bytes(bytes) => bytes.len() as u64,
+        Body::File { length, .. } => *length,
+    };
+    let mut head = format!("HTTP/1.1 {} {reason}\r\n", response.status);
+    for (name, value) in &response.headers {
+        head.push_str(&format!("{name}: {value}\r\n"));
+    }
+    head.push_str(&format!(
+        "Content-Length: {length}\r\nConnection: close\r\n\r\n"
+    ));
+    writer.write_all(head.as_bytes()).await?;
+    match response.body {
+        Body::Bytes(bytes) => writer.write_all(&bytes).await?,
+        Body::File { file, length } => {
+            tokio::io::copy(&mut file.take(length), writer).await?;
+        }
+    }
+    writer.flush().await?;
+    Ok(())
+}
+
+/// The first and last byte of a `Range: bytes=...` header within `length` bytes. Only single
+/// ranges are supported, which is what players send when seeking.
+pub(super) fn parse_range(header: &str, length: u64) -> Option<(u64, u64)> {
+    let last = length.checked_sub(1)?;
+    let range = header.strip_prefix("bytes=")?;
+    let (start, end) = range.split_once('-')?;
+    let (start, end) = match (start.trim(), end.trim()) {
+        ("", suffix) => (length - suffix.parse::<u64>().ok()?.min(length), last),
+        (start, "") => (start.parse().ok()?, last),
+        (start, end) => (start.parse().ok()?, end.parse::<u64>().ok()?.min(last)),
+    };
+    match start <= end {
+        true => Some((start, end)),
+        false => None,
+    }
+}
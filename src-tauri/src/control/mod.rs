@@ -0,0 +1,185 @@
+use std::sync::Arc;
+
+use serde_json::Value;
+
+use crate::audio::{PlayerController, RepeatMode, ShuffleMode};
+use crate::error::CommandError;
+use crate::event::{Event, EventBus};
+use crate::library::{Library, TagMatch, TrackFilter, TrackSelection, TrackTag};
+use crate::settings::Settings;
+
+mod http;
+mod mpd;
+#[cfg(target_os = "linux")]
+mod mpris;
+mod remote;
+#[cfg(unix)]
+mod socket;
+mod subsonic;
+
+pub use mpd::{serve_mpd, MpdConfig};
+#[cfg(target_os = "linux")]
+pub use mpris::serve_mpris;
+pub use remote::{new_pairing_token, serve_remote, RemoteConfig};
+#[cfg(unix)]
+pub use socket::{default_socket_path, serve};
+pub use subsonic::{serve_subsonic, SubsonicConfig};
+
+/// Everything control requests act on.
+#[derive(Clone)]
+pub struct Services {
+    pub controller: PlayerController,
+    pub library: Arc<Library>,
+    pub settings: Arc<Settings>,
+    pub events: EventBus,
+}
+
+/// A request sent to a running instance, as one JSON object per line, e.g.
+/// `{"command": "seek", "seconds": 42.5}`.
+#[derive(Debug, serde::Deserialize)]
+#[serde(tag = "command", rename_all = "camelCase")]
+pub enum ControlRequest {
+    Play {
+        path: String,
+    },
+    PlaySelection {
+        selection: TrackSelection,
+    },
+    Queue {
+        path: String,
+    },
+    QueueSelection {
+        selection: TrackSelection,
+    },
+    PlayNext {
+        path: String,
+    },
+    RemoveFromQueue {
+        index: usize,
+    },
+    MoveInQueue {
+        from: usize,
+        to: usize,
+    },
+    ClearQueue,
+    JumpInQueue {
+        index: usize,
+    },
+    SetShuffle {
+        shuffle: ShuffleMode,
+    },
+    SetRepeat {
+        repeat: RepeatMode,
+    },
+    Pause,
+    Resume,
+    Skip,
+    Previous,
+    Seek {
+        seconds: f64,
+    },
+    SetVolume {
+        volume: f64,
+    },
+    GetState,
+    GetQueue,
+    /// Library tracks with `query` in any of their tags.
+    Search {
+        query: String,
+    },
+    /// Adds the audio files under `path` to the library.
+    Scan {
+        path: String,
+    },
+    /// Turns the connection into a stream of player events.
+    Subscribe,
+}
+
+/// A line sent back to the client.
+#[derive(Debug, serde::Serialize)]
+#[serde(rename_all = "camelCase")]
+pub enum ControlResponse {
+    Ok(Value),
+    Error(CommandError),
+    Event(Event),
+}
+
+impl From<Result<Value, CommandError>> for ControlResponse {
+    fn from(result: Result<Value, CommandError>) -> Self {
+        match result {
+            Ok(value) => ControlResponse::Ok(value),
+            Err(err) => ControlResponse::Error(err),
+        }
+    }
+}
+
+/// Runs a request and returns its result as JSON. `Subscribe` is up to the transport.
+pub async fn handle_request(
+    services: &Services,
+    request: ControlRequest,
+) -> Result<Value, CommandError> {
+    let result = run_request(services, request).await;
+    result.map_err(CommandError::from)
+}
+
+async fn run_request(services: &Services, request: ControlRequest) -> anyhow::Result<Value> {
+    let controller = &services.controller;
+    let library = &services.library;
+    match request {
+        ControlRequest::Play { path } => {
+            let audio_file = library.get_audio_file(&path).await?;
+            controller.play_now(vec![audio_file])?;
+        }
+        ControlRequest::PlaySelection { selection } => {
+            controller.play_now(library.get_selection(&selection).await?)?;
+        }
+        ControlRequest::Queue { path } => {
+            let audio_file = library.get_audio_file(&path).await?;
+            controller.queue(vec![audio_file])?;
+        }
+        ControlRequest::QueueSelection { selection } => {
+            controller.queue(library.get_selection(&selection).await?)?;
+        }
+        ControlRequest::PlayNext { path } => {
+            let audio_file = library.get_audio_file(&path).await?;
+            controller.play_next(audio_file)?;
+        }
+        ControlRequest::RemoveFromQueue { index } => controller.remove_from_queue(index)?,
+        ControlRequest::MoveInQueue { from, to } => controller.move_in_queue(from, to)?,
+        ControlRequest::ClearQueue => controller.clear_queue()?,
+        ControlRequest::JumpInQueue { index } => controller.jump_in_queue(index)?,
+        ControlRequest::SetShuffle { shuffle } => controller.set_shuffle(shuffle)?,
+        ControlRequest::SetRepeat { repeat } => controller.set_repeat(repeat)?,
+        ControlRequest::Pause => controller.pause()?,
+        ControlRequest::Resume => controller.resume()?,
+        ControlRequest::Skip => controller.skip()?,
+        ControlRequest::Previous => controller.previous()?,
+        ControlRequest::Seek { seconds } => controller.seek(seconds)?,
+        ControlRequest::SetVolume { volume } => controller.change_volume(volume)?,
+        ControlRequest::GetState => return Ok(serde_json::to_value(controller.get_state()?)?),
+        ControlRequest::GetQueue => return Ok(serde_json::to_value(controller.get_queue()?)?),
+        ControlRequest::Search { query } => {
+            let filter = TrackFilter {
+                tag: TrackTag::Any,
+                matching: TagMatch::Contains,
+                value: query,
+            };
+            return Ok(serde_json::to_value(library.find_tracks(&[filter]).await?)?);
+        }
+        ControlRequest::Scan { path } => library.scan(&path).await?,
+        ControlRequest::Subscribe => {
+            let message = "Subscriptions aren't supported here.".to_string();
+            return Err(CommandError::InvalidArgument(message).into());
+        }
+    }
+    Ok(Value::Null)
+}
+
+/// Compares a secret a client sent without giving away how much of it matched.
+fn secrets_match(given: &[u8], expected: &[u8]) -> bool {
+    let difference = given
+        .iter()
+        .zip(expected)
+        .fold(0, |difference, (a, b)| difference | (a ^ b));
+    given.len() == expected.len() && difference == 0
+}
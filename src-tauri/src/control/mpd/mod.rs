@@ -0,0 +1,629 @@
+//! Enough of the MPD protocol for MPD clients to browse the library and control playback.
+//!
+//! Songs are identified by their queue position, which also serves as their id: the queue
+//! only keeps the current track and the upcoming ones, with no stable ids.
+
+use std::collections::BTreeSet;
+use std::sync::atomic::{AtomicU32, Ordering};
+use std::sync::Arc;
+
+use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::broadcast::error::RecvError;
+use tokio::sync::mpsc;
+
+use super::Services;
+use crate::audio::{PlaybackState, RepeatMode, ShuffleMode};
+use crate::library::{TagMatch, TrackFilter, TrackSelection, TrackTag};
+use protocol::{Ack, Response, ACK_ERROR_NO_EXIST, ACK_ERROR_UNKNOWN};
+
+mod protocol;
+
+/// The protocol version announced to clients, the first to support filter expressions.
+const PROTOCOL_VERSION: &str = "0.21.0";
+
+const COMMANDS: &[&str] = &[
+    "add",
+    "clear",
+    "close",
+    "command_list_begin",
+    "command_list_end",
+    "command_list_ok_begin",
+    "commands",
+    "currentsong",
+    "find",
+    "idle",
+    "list",
+    "next",
+    "noidle",
+    "pause",
+    "ping",
+    "play",
+    "playid",
+    "playlistinfo",
+    "plchanges",
+    "previous",
+    "random",
+    "repeat",
+    "search",
+    "seekcur",
+    "setvol",
+    "status",
+    "tagtypes",
+];
+
+/// How the MPD server is set up, saved in the settings.
+#[derive(Debug, Clone, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct MpdConfig {
+    pub enabled: bool,
+    /// Where to listen, only this machine by default as MPD clients don't authenticate.
+    pub address: String,
+}
+
+impl Default for MpdConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            address: "127.0.0.1:6600".to_string(),
+        }
+    }
+}
+
+/// Accepts MPD clients on `address`, e.g. `127.0.0.1:6600`, until the listener fails.
+pub async fn serve_mpd(address: &str, services: Services) -> anyhow::Result<()> {
+    let listener = TcpListener::bind(address).await?;
+    accept_clients(listener, services).await
+}
+
+async fn accept_clients(listener: TcpListener, services: Services) -> anyhow::Result<()> {
+    let playlist_version = Arc::new(AtomicU32::new(1));
+    tokio::spawn(count_playlist_versions(
+        services.clone(),
+        playlist_version.clone(),
+    ));
+    loop {
+        let (stream, _) = listener.accept().await?;
+        let connection = Connection {
+            services: services.clone(),
+            playlist_version: playlist_version.clone(),
+        };
+        tokio::spawn(async move {
+            if let Err(err) = connection.run(stream).await {
+                eprintln!("Error in MPD connection: {:?}", err);
+            }
+        });
+    }
+}
+
+/// Clients poll the playlist version to know when to fetch the queue again.
+async fn count_playlist_versions(services: Services, playlist_version: Arc<AtomicU32>) {
+    let mut events = services.events.subscribe();
+    loop {
+        match events.recv().await {
+            Ok(event) if event.name == "player:queue-changed" => {
+                playlist_version.fetch_add(1, Ordering::Relaxed);
+            }
+            Ok(_) => {}
+            Err(RecvError::Lagged(_)) => {
+                playlist_version.fetch_add(1, Ordering::Relaxed);
+            }
+            Err(RecvError::Closed) => return,
+        }
+    }
+}
+
+/// The MPD subsystem a player event changes, as reported by `idle`.
+fn subsystem(event: &str) -> Option<&'static str> {
+    match event {
+        "player:state-changed" | "player:track-changed" | "player:ended" => Some("player"),
+        "player:volume-changed" => Some("mixer"),
+        "player:queue-changed" => Some("playlist"),
+        _ => None,
+    }
+}
+
+enum Input {
+    Line(String),
+    Changed(&'static str),
+}
+
+/// A batch of commands sent between `command_list_begin` and `command_list_end`.
+struct CommandList {
+    /// Whether each command's success is acknowledged with `list_OK`.
+    acknowledge_each: bool,
+    commands: Vec<Vec<String>>,
+}
+
+struct Connection {
+    services: Services,
+    playlist_version: Arc<AtomicU32>,
+}
+
+impl Connection {
+    async fn run(&self, stream: TcpStream) -> anyhow::Result<()> {
+        let (reader, mut writer) = stream.into_split();
+        writer
+            .write_all(format!("OK MPD {PROTOCOL_VERSION}\n").as_bytes())
+            .await?;
+
+        // Lines and events come through one channel, so idling clients can be woken by either.
+        let (tx, mut rx) = mpsc::unbounded_channel();
+        let lines_tx = tx.clone();
+        let read_lines = tokio::spawn(async move {
+            let mut lines = BufReader::new(reader).lines();
+            while let Ok(Some(line)) = lines.next_line().await {
+                if lines_tx.send(Input::Line(line)).is_err() {
+                    return;
+                }
+            }
+        });
+        let mut events = self.services.events.subscribe();
+        let forward_events = tokio::spawn(async move {
+            loop {
+                let name = match events.recv().await {
+                    Ok(event) => event.name,
+                    Err(RecvError::Lagged(_)) => continue,
+                    Err(RecvError::Closed) => return,
+                };
+                let Some(subsystem) = subsystem(&name) else {
+                    continue;
+                };
+                if tx.send(Input::Changed(subsystem)).is_err() {
+                    return;
+                }
+            }
+        });
+
+        let result = self.handle_input(&mut rx, &mut writer).await;
+        read_lines.abort();
+        forward_events.abort();
+        result
+    }
+
+    async fn handle_input<W>(
+        &self,
+        rx: &mut mpsc::UnboundedReceiver<Input>,
+        writer: &mut W,
+    ) -> anyhow::Result<()>
+    where
+        W: AsyncWriteExt + Unpin,
+    {
+        let mut changed = BTreeSet::new();
+        // The subsystems an idling client waits for, all of them when empty.
+        let mut idle: Option<Vec<String>> = None;
+        let mut command_list: Option<CommandList> = None;
+
+        while let Some(input) = rx.recv().await {
+            let line = match input {
+                Input::Changed(subsystem) => {
+                    changed.insert(subsystem);
+                    if let Some(subsystems) = &idle {
+                        if let Some(response) = take_changes(&mut changed, subsystems) {
+                            writer.write_all(response.as_bytes()).await?;
+                            idle = None;
+                        }
+                    }
+                    continue;
+                }
+                Input::Line(line) => line,
+            };
+            if let Some(subsystems) = &idle {
+                if line.trim() != "noidle" {
+                    // Only noidle may interrupt idle.
+                    return Ok(());
+                }
+                let response = take_changes(&mut changed, subsystems);
+                let response = response.unwrap_or_else(|| "OK\n".to_string());
+                writer.write_all(response.as_bytes()).await?;
+                idle = None;
+                continue;
+            }
+
+            let args = match protocol::split_args(&line) {
+                Ok(args) if args.is_empty() => continue,
+                Ok(args) => args,
+                Err(ack) => {
+                    writer.write_all(ack.format(0, "").as_bytes()).await?;
+                    continue;
+                }
+            };
+            let command = args[0].as_str();
+            if let Some(list) = &mut command_list {
+                if command == "command_list_end" {
+                    let response = self.run_command_list(list).await;
+                    writer.write_all(response.as_bytes()).await?;
+                    command_list = None;
+                } else {
+                    list.commands.push(args);
+                }
+                continue;
+            }
+            match command {
+                "command_list_begin" | "command_list_ok_begin" => {
+                    command_list = Some(CommandList {
+                        acknowledge_each: command == "command_list_ok_begin",
+                        commands: Vec::new(),
+                    });
+                }
+                "idle" => {
+                    let subsystems = args[1..].to_vec();
+                    match take_changes(&mut changed, &subsystems) {
+                        Some(response) => writer.write_all(response.as_bytes()).await?,
+                        None => idle = Some(subsystems),
+                    }
+                }
+                // Ignored when not idling, like MPD does.
+                "noidle" => {}
+                "close" => return Ok(()),
+                _ => {
+                    let response = match self.run_command(&args).await {
+                        Ok(response) => format!("{response}OK\n"),
+                        Err(ack) => ack.format(0, command),
+                    };
+                    writer.write_all(response.as_bytes()).await?;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Runs the commands of a list until one fails.
+    async fn run_command_list(&self, list: &CommandList) -> String {
+        let mut response = String::new();
+        for (index, args) in list.commands.iter().enumerate() {
+            match self.run_command(args).await {
+                Ok(output) => response.push_str(&output),
+                Err(ack) => {
+                    response.push_str(&ack.format(index, &args[0]));
+                    return response;
+                }
+            }
+            if list.acknowledge_each {
+                response.push_str("list_OK\n");
+            }
+        }
+        response.push_str("OK\n");
+        response
+    }
+
+    async fn run_command(&self, args: &[String]) -> Result<String, Ack> {
+        let controller = &self.services.controller;
+        let library = &self.services.library;
+        let mut response = Response::default();
+        match (args[0].as_str(), &args[1..]) {
+            ("ping", []) => {}
+            ("commands", []) => {
+                for command in COMMANDS {
+                    response.field("command", command);
+                }
+            }
+            ("tagtypes", []) => {
+                for tag in [TrackTag::Artist, TrackTag::Album, TrackTag::Title] {
+                    response.field("tagtype", protocol::tag_name(tag));
+                }
+            }
+            ("status", []) => {
+                let state = controller.get_state()?;
+                let queue = &state.queue;
+                let length = queue.current.is_some() as usize + queue.upcoming.len();
+                response.field("volume", (state.volume * 100.0).round());
+                response.field("repeat", flag(queue.repeat != RepeatMode::Off));
+                response.field("random", flag(queue.shuffle != ShuffleMode::Off));
+                response.field("single", flag(queue.repeat == RepeatMode::One));
+                response.field("consume", 1);
+                response.field("playlist", self.playlist_version.load(Ordering::Relaxed));
+                response.field("playlistlength", length);
+                let playback_state = match state.state {
+                    PlaybackState::Playing => "play",
+                    PlaybackState::Paused => "pause",
+                    PlaybackState::Stopped => "stop",
+                };
+                response.field("state", playback_state);
+                if queue.current.is_some() {
+                    response.field("song", 0);
+                    response.field("songid", 0);
+                }
+                if let Some(status) = &state.status {
+                    response.field("elapsed", format!("{:.3}", status.played_secs));
+                    if let Some(total) = status.total_duration_secs {
+                        response.field("duration", format!("{total:.3}"));
+                        let time = format!("{}:{}", status.played_secs as u64, total as u64);
+                        response.field("time", time);
+                    }
+                }
+            }
+            ("currentsong", []) => {
+                let state = controller.get_state()?;
+                if let Some(track) = &state.track {
+                    let path = track.file.get_path();
+                    let library_track = self.find_library_track(path).await?;
+                    response.song(path, library_track.as_ref(), Some(0));
+                    if let Some(duration) = track.duration_secs {
+                        response.field("duration", format!("{duration:.3}"));
+                    }
+                }
+            }
+            ("playlistinfo", positions) | ("plchanges", positions) => {
+                // Changes are always the whole queue: it's rebuilt rather than diffed.
+                let only = match (args[0].as_str(), positions) {
+                    ("playlistinfo", [position]) => Some(parse_number::<usize>(position)?),
+                    _ => None,
+                };
+                let queue = controller.get_queue()?;
+                let songs = queue.current.iter().chain(queue.upcoming.iter());
+                for (position, file) in songs.enumerate() {
+                    if only.map_or(true, |only| only == position) {
+                        let library_track = self.find_library_track(file.get_path()).await?;
+                        response.song(file.get_path(), library_track.as_ref(), Some(position));
+                    }
+                }
+            }
+            ("play", position) | ("playid", position) => match position {
+                [] => controller.resume()?,
+                [position] => self.play_position(parse_number(position)?)?,
+                _ => return Err(Ack::arg("Too many arguments")),
+            },
+            ("pause", paused) => {
+                let pause = match paused {
+                    [] => controller.get_state()?.state == PlaybackState::Playing,
+                    [paused] => paused == "1",
+                    _ => return Err(Ack::arg("Too many arguments")),
+                };
+                match pause {
+                    true => controller.pause()?,
+                    false => controller.resume()?,
+                }
+            }
+            ("next", []) => controller.skip()?,
+            ("previous", []) => controller.previous()?,
+            ("seekcur", [time]) => {
+                let seconds: f64 = parse_number(time)?;
+                let seconds = if time.starts_with('+') || time.starts_with('-') {
+                    let state = controller.get_state()?;
+                    let played_secs = state.status.map_or(0.0, |status| status.played_secs);
+                    (played_secs + seconds).max(0.0)
+                } else {
+                    seconds
+                };
+                controller.seek(seconds)?;
+            }
+            ("setvol", [volume]) => {
+                let volume: u32 = parse_number(volume)?;
+                if volume > 100 {
+                    return Err(Ack::arg("Volume must be between 0 and 100"));
+                }
+                controller.change_volume(volume as f64 / 100.0)?;
+            }
+            ("random", [random]) => {
+                let shuffle = match random.as_str() {
+                    "1" => ShuffleMode::Tracks,
+                    _ => ShuffleMode::Off,
+                };
+                controller.set_shuffle(shuffle)?;
+            }
+            ("repeat", [repeat]) => {
+                let repeat = match repeat.as_str() {
+                    "1" => RepeatMode::All,
+                    _ => RepeatMode::Off,
+                };
+                controller.set_repeat(repeat)?;
+            }
+            ("clear", []) => controller.clear_queue()?,
+            ("add", [uri]) => {
+                let tracks = match self.find_library_track(uri).await? {
+                    Some(track) => vec![track],
+                    None => {
+                        let folder = format!("{}/", uri.trim_end_matches('/'));
+                        let filter = path_filter(TagMatch::StartsWith, &folder);
+                        library.find_tracks(&[filter]).await?
+                    }
+                };
+                if tracks.is_empty() {
+                    return Err(Ack::new(ACK_ERROR_NO_EXIST, "No such song"));
+                }
+                let ids = tracks.iter().map(|track| track.id).collect();
+                let selection = TrackSelection::Tracks { ids };
+                controller.queue(library.get_selection(&selection).await?)?;
+            }
+            ("find", filters) | ("search", filters) => {
+                let matching = match args[0].as_str() {
+                    "find" => TagMatch::Equals,
+                    _ => TagMatch::Contains,
+                };
+                let filters = protocol::parse_filters(filters, matching)?;
+                if filters.is_empty() {
+                    return Err(Ack::arg("Missing filter"));
+                }
+                for track in library.find_tracks(&filters).await? {
+                    response.song(&track.path, Some(&track), None);
+                }
+            }
+            ("list", [tag, filters @ ..]) => {
+                let tag = protocol::parse_tag(tag)?;
+                // A lone value after `list album` is the artist, for old clients.
+                let filters = match filters {
+                    [artist] if tag == TrackTag::Album && !artist.starts_with('(') => {
+                        vec![TrackFilter {
+                            tag: TrackTag::Artist,
+                            matching: TagMatch::Equals,
+                            value: artist.clone(),
+                        }]
+                    }
+                    _ => protocol::parse_filters(filters, TagMatch::Equals)?,
+                };
+                for value in library.list_tag(tag, &filters).await? {
+                    response.field(protocol::tag_name(tag), value);
+                }
+            }
+            (command, _) if COMMANDS.contains(&command) => {
+                return Err(Ack::arg(format!(
+                    "Wrong number of arguments for \"{command}\""
+                )));
+            }
+            (command, _) => {
+                let message = format!("unknown command \"{command}\"");
+                return Err(Ack::new(ACK_ERROR_UNKNOWN, message));
+            }
+        }
+        Ok(response.into_string())
+    }
+
+    /// Plays the queued song at `position`, restarting the current one for 0.
+    fn play_position(&self, position: usize) -> anyhow::Result<()> {
+        let controller = &self.services.controller;
+        match position {
+            0 => controller.seek(0.0)?,
+            position => controller.jump_in_queue(position - 1)?,
+        }
+        controller.resume()
+    }
+
+    async fn find_library_track(
+        &self,
+        path: &str,
+    ) -> anyhow::Result<Option<crate::library::LibraryTrack>> {
+        let filter = path_filter(TagMatch::Equals, path);
+        let tracks = self.services.library.find_tracks(&[filter]).await?;
+        Ok(tracks.into_iter().next())
+    }
+}
+
+/// Reports the changes an idling client waits for, if there are any, and forgets them.
+fn take_changes(changed: &mut BTreeSet<&'static str>, subsystems: &[String]) -> Option<String> {
+    let mut response = Response::default();
+    let mut any_changed = false;
+    changed.retain(|subsystem| {
+        let waited_for = subsystems.is_empty() || subsystems.iter().any(|s| s == subsystem);
+        if waited_for {
+            response.field("changed", subsystem);
+            any_changed = true;
+        }
+        !waited_for
+    });
+    match any_changed {
+        true => Some(format!("{}OK\n", response.into_string())),
+        false => None,
+    }
+}
+
+fn path_filter(matching: TagMatch, path: &str) -> TrackFilter {
+    TrackFilter {
+        tag: TrackTag::Path,
+        matching,
+        value: path.to_string(),
+    }
+}
+
+fn flag(enabled: bool) -> u8 {
+    match enabled {
+        true => 1,
+        false => 0,
+    }
+}
+
+fn parse_number<T: std::str::FromStr>(value: &str) -> Result<T, Ack> {
+    value
+        .trim_start_matches('+')
+        .parse()
+        .map_err(|_| Ack::arg(format!("Not a number: {value}")))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+    use tokio::net::TcpStream;
+
+    use super::*;
+    use crate::audio::{boot_player, NullOutput, PlayerOptions};
+    use crate::database::init_test_db;
+    use crate::event::EventBus;
+    use crate::library::Library;
+    use crate::settings::Settings;
+
+    /// Sends a command and reads the response up to its final `OK` or `ACK` line.
+    async fn request(stream: &mut BufReader<TcpStream>, command: &str) -> Vec<String> {
+        stream
+            .write_all(format!("{command}\n").as_bytes())
+            .await
+            .unwrap();
+        read_response(stream).await
+    }
+
+    async fn read_response(stream: &mut BufReader<TcpStream>) -> Vec<String> {
+        let mut lines = Vec::new();
+        loop {
+            let mut line = String::new();
+            stream.read_line(&mut line).await.unwrap();
+            let line = line.trim_end().to_string();
+            let done = line == "OK" || line.starts_with("ACK");
+            lines.push(line);
+            if done {
+                return lines;
+            }
+        }
+    }
+
+    async fn connect(address: &str) -> BufReader<TcpStream> {
+        let stream = TcpStream::connect(address).await.unwrap();
+        let mut stream = BufReader::new(stream);
+        let mut greeting = String::new();
+        stream.read_line(&mut greeting).await.unwrap();
+        assert!(greeting.starts_with("OK MPD "));
+        stream
+    }
+
+    #[test]
+    fn test_mpd_commands_and_idle() {
+        let events = EventBus::default();
+        let (tx, rx) = std::sync::mpsc::channel();
+        let options = PlayerOptions {
+            output: Some(Box::new(NullOutput::new(1.0))),
+            ..Default::default()
+        };
+        let services = Services {
+            controller: boot_player(tx, rx, events.clone(), options).unwrap(),
+            library: Arc::new(Library::new(init_test_db().unwrap())),
+            settings: Arc::new(Settings::new(init_test_db().unwrap())),
+            events,
+        };
+
+        let runtime = tokio::runtime::Runtime::new().unwrap();
+        runtime.block_on(async {
+            let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+            let address = listener.local_addr().unwrap().to_string();
+            tokio::spawn(accept_clients(listener, services));
+            let mut client = connect(&address).await;
+            let mut idler = connect(&address).await;
+
+            let status = request(&mut client, "status").await;
+            assert!(status.contains(&"state: stop".to_string()));
+            assert!(status.contains(&"playlistlength: 0".to_string()));
+
+            idler.write_all(b"idle mixer\n").await.unwrap();
+            assert_eq!(request(&mut client, "setvol 40").await, ["OK"]);
+            assert_eq!(read_response(&mut idler).await, ["changed: mixer", "OK"]);
+            let status = request(&mut client, "status").await;
+            assert!(status.contains(&"volume: 40".to_string()));
+
+            let list = "command_list_ok_begin\nping\nsetvol 101\nping\ncommand_list_end";
+            assert_eq!(
+                request(&mut client, list).await,
+                [
+                    "list_OK",
+                    "ACK [2@1] {setvol} Volume must be between 0 and 100"
+                ]
+            );
+            assert_eq!(
+                request(&mut client, "add \"/nowhere.flac\"").await,
+                ["ACK [50@0] {add} No such song"]
+            );
+            assert_eq!(
+                request(&mut client, "dance").await,
+                ["ACK [5@0] {dance} unknown command \"dance\""]
+            );
+        });
+    }
+}
@@ -0,0 +1,277 @@
+//! Parsing and formatting for the text protocol MPD clients speak.
+
+use std::fmt::Write;
+use std::iter::Peekable;
+
+use crate::error::CommandError;
+use crate::library::{LibraryTrack, TagMatch, TrackFilter, TrackTag};
+
+pub(super) const ACK_ERROR_ARG: u32 = 2;
+pub(super) const ACK_ERROR_UNKNOWN: u32 = 5;
+pub(super) const ACK_ERROR_NO_EXIST: u32 = 50;
+pub(super) const ACK_ERROR_SYSTEM: u32 = 52;
+
+/// A failed command, sent as `ACK [code@index] {command} message`.
+#[derive(Debug, PartialEq, Eq)]
+pub(super) struct Ack {
+    pub code: u32,
+    pub message: String,
+}
+
+impl Ack {
+    pub fn new(code: u32, message: impl Into<String>) -> Self {
+        Self {
+            code,
+            message: message.into(),
+        }
+    }
+
+    pub fn arg(message: impl Into<String>) -> Self {
+        Self::new(ACK_ERROR_ARG, message)
+    }
+
+    pub fn format(&self, index: usize, command: &str) -> String {
+        format!(
+            "ACK [{}@{index}] {{{command}}} {}\n",
+            self.code, self.message
+        )
+    }
+}
+
+impl From<anyhow::Error> for Ack {
+    fn from(err: anyhow::Error) -> Self {
+        let err = CommandError::from(err);
+        let code = match err {
+            CommandError::InvalidArgument(_) => ACK_ERROR_ARG,
+            CommandError::FileNotFound(_) => ACK_ERROR_NO_EXIST,
+            _ => ACK_ERROR_SYSTEM,
+        };
+        Ack::new(code, err.to_string())
+    }
+}
+
+/// Splits a command line into words, unquoting `"quoted \"words\""`.
+pub(super) fn split_args(line: &str) -> Result<Vec<String>, Ack> {
+    let mut args = Vec::new();
+    let mut chars = line.chars().peekable();
+    loop {
+        while chars.next_if(|c| c.is_whitespace()).is_some() {}
+        let Some(first) = chars.next() else {
+            return Ok(args);
+        };
+        let mut arg = String::new();
+        if first == '"' {
+            loop {
+                match chars.next() {
+                    Some('"') => break,
+                    Some('\\') => arg.extend(chars.next()),
+                    Some(c) => arg.push(c),
+                    None => return Err(Ack::arg("Missing closing '\"'")),
+                }
+            }
+        } else {
+            arg.push(first);
+            while let Some(c) = chars.next_if(|c| !c.is_whitespace()) {
+                arg.push(c);
+            }
+        }
+        args.push(arg);
+    }
+}
+
+pub(super) fn parse_tag(name: &str) -> Result<TrackTag, Ack> {
+    match name.to_lowercase().as_str() {
+        "artist" | "albumartist" => Ok(TrackTag::Artist),
+        "album" => Ok(TrackTag::Album),
+        "title" => Ok(TrackTag::Title),
+        "file" => Ok(TrackTag::Path),
+        "any" => Ok(TrackTag::Any),
+        _ => Err(Ack::arg(format!("Unknown tag type: {name}"))),
+    }
+}
+
+/// The name MPD gives a tag in responses.
+pub(super) fn tag_name(tag: TrackTag) -> &'static str {
+    match tag {
+        TrackTag::Artist => "Artist",
+        TrackTag::Album => "Album",
+        TrackTag::Title => "Title",
+        TrackTag::Path | TrackTag::Any => "file",
+    }
+}
+
+/// Parses the filters of `find`, `search` and `list`: either `TAG VALUE` pairs, matched with
+/// `pairs_match`, or expressions like `((Artist == "Low") AND (Album contains "lost"))`.
+pub(super) fn parse_filters(
+    args: &[String],
+    pairs_match: TagMatch,
+) -> Result<Vec<TrackFilter>, Ack> {
+    let mut filters = Vec::new();
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        if arg.starts_with('(') {
+            let tokens = tokenize_expression(arg)?;
+            let mut tokens = tokens.iter().peekable();
+            parse_expression(&mut tokens, &mut filters)?;
+            if tokens.next().is_some() {
+                return Err(Ack::arg(format!("Unexpected text after filter: {arg}")));
+            }
+            continue;
+        }
+        let Some(value) = args.next() else {
+            return Err(Ack::arg(format!("Missing value for {arg}")));
+        };
+        filters.push(TrackFilter {
+            tag: parse_tag(arg)?,
+            matching: pairs_match,
+            value: value.clone(),
+        });
+    }
+    Ok(filters)
+}
+
+fn tokenize_expression(expression: &str) -> Result<Vec<String>, Ack> {
+    let mut tokens = Vec::new();
+    let mut chars = expression.chars().peekable();
+    while let Some(c) = chars.next() {
+        match c {
+            '(' | ')' => tokens.push(c.to_string()),
+            '"' | '\'' => {
+                let mut token = String::new();
+                loop {
+                    match chars.next() {
+                        Some(quote) if quote == c => break,
+                        Some('\\') => token.extend(chars.next()),
+                        Some(c) => token.push(c),
+                        None => return Err(Ack::arg("Unterminated string in filter")),
+                    }
+                }
+                tokens.push(token);
+            }
+            c if c.is_whitespace() => {}
+            c => {
+                let mut token = c.to_string();
+                while let Some(c) = chars.next_if(|c| !c.is_whitespace() && *c != ')') {
+                    token.push(c);
+                }
+                tokens.push(token);
+            }
+        }
+    }
+    Ok(tokens)
+}
+
+type Tokens<'a> = Peekable<std::slice::Iter<'a, String>>;
+
+/// Parses one parenthesized expression, adding the filters it requires.
+fn parse_expression(tokens: &mut Tokens, filters: &mut Vec<TrackFilter>) -> Result<(), Ack> {
+    if tokens.next().map(String::as_str) != Some("(") {
+        return Err(Ack::arg("Expected '(' in filter"));
+    }
+    if tokens.peek().map(|token| token.as_str()) == Some("(") {
+        loop {
+            parse_expression(tokens, filters)?;
+            match tokens.next().map(String::as_str) {
+                Some("AND") => continue,
+                Some(")") => return Ok(()),
+                other => return Err(Ack::arg(format!("Unsupported filter operator: {other:?}"))),
+            }
+        }
+    }
+    let mut next = || tokens.next().map(String::as_str);
+    let (Some(tag), Some(operator), Some(value), Some(")")) = (next(), next(), next(), next())
+    else {
+        return Err(Ack::arg("Expected (TAG OPERATOR 'VALUE') in filter"));
+    };
+    let matching = match operator {
+        "==" => TagMatch::Equals,
+        "contains" => TagMatch::Contains,
+        "starts_with" => TagMatch::StartsWith,
+        _ => return Err(Ack::arg(format!("Unsupported filter operator: {operator}"))),
+    };
+    filters.push(TrackFilter {
+        tag: parse_tag(tag)?,
+        matching,
+        value: value.to_string(),
+    });
+    Ok(())
+}
+
+/// Collects the `key: value` lines of a response.
+#[derive(Default)]
+pub(super) struct Response(String);
+
+impl Response {
+    pub fn field(&mut self, key: &str, value: impl std::fmt::Display) {
+        let _ = writeln!(self.0, "{key}: {value}");
+    }
+
+    /// Describes a queued or library song. `position` is its place in the queue, if queued.
+    pub fn song(&mut self, path: &str, track: Option<&LibraryTrack>, position: Option<usize>) {
+        self.field("file", path);
+        if let Some(track) = track {
+            self.field("Title", &track.title);
+            self.field("Artist", &track.artist);
+            self.field("Album", &track.album);
+            self.field("Track", track.album_order);
+            if let Some(disc_number) = track.disc_number {
+                self.field("Disc", disc_number);
+            }
+        }
+        if let Some(position) = position {
+            self.field("Pos", position);
+            self.field("Id", position);
+        }
+    }
+
+    pub fn into_string(self) -> String {
+        self.0
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn strings(args: &[&str]) -> Vec<String> {
+        args.iter().map(|arg| arg.to_string()).collect()
+    }
+
+    #[test]
+    fn test_splits_quoted_arguments() {
+        let args = split_args(r#"find  artist "The \"Low\" band" album x"#).unwrap();
+        assert_eq!(args, ["find", "artist", "The \"Low\" band", "album", "x"]);
+        assert!(split_args("find \"artist").is_err());
+    }
+
+    #[test]
+    fn test_parses_filter_pairs_and_expressions() {
+        let filter = |tag, matching, value: &str| TrackFilter {
+            tag,
+            matching,
+            value: value.to_string(),
+        };
+
+        let pairs = parse_filters(&strings(&["Artist", "Low", "any", "x"]), TagMatch::Contains);
+        assert_eq!(
+            pairs.unwrap(),
+            [
+                filter(TrackTag::Artist, TagMatch::Contains, "Low"),
+                filter(TrackTag::Any, TagMatch::Contains, "x"),
+            ]
+        );
+
+        let expression = r#"((Artist == "Low") AND (album contains 'it\'s'))"#;
+        let expressions = parse_filters(&strings(&[expression]), TagMatch::Equals);
+        assert_eq!(
+            expressions.unwrap(),
+            [
+                filter(TrackTag::Artist, TagMatch::Equals, "Low"),
+                filter(TrackTag::Album, TagMatch::Contains, "it's"),
+            ]
+        );
+
+        let negated = parse_filters(&strings(&["(Artist != 'Low')"]), TagMatch::Equals);
+        assert_eq!(negated.unwrap_err().code, ACK_ERROR_ARG);
+    }
+}
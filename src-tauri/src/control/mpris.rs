@@ -0,0 +1,496 @@
+//! The MPRIS D-Bus interfaces, so media keys, desktop media widgets and `playerctl` can see
+//! and control the player.
+
+use std::collections::HashMap;
+
+use tokio::sync::broadcast::error::RecvError;
+use zbus::connection::Builder;
+use zbus::fdo;
+use zbus::object_server::SignalContext;
+use zbus::zvariant::{ObjectPath, Value};
+
+use super::Services;
+use crate::audio::{PlaybackState, PlayerState, RepeatMode, ShuffleMode};
+
+const BUS_NAME: &str = "org.mpris.MediaPlayer2.amptree";
+const OBJECT_PATH: &str = "/org/mpris/MediaPlayer2";
+/// The track id of a file that isn't in the library.
+const UNKNOWN_TRACK_ID: &str = "/org/amptree/track/unknown";
+const NO_TRACK_ID: &str = "/org/mpris/MediaPlayer2/TrackList/NoTrack";
+
+/// Takes the player's name on the session bus and answers for it until the bus goes away.
+pub async fn serve_mpris(services: Services) -> anyhow::Result<()> {
+    serve_on(Builder::session()?, services).await
+}
+
+async fn serve_on(builder: Builder<'_>, services: Services) -> anyhow::Result<()> {
+    let mut events = services.events.subscribe();
+    let connection = builder
+        .name(BUS_NAME)?
+        .serve_at(OBJECT_PATH, Root)?
+        .serve_at(OBJECT_PATH, Player { services })?
+        .build()
+        .await?;
+    let player = connection
+        .object_server()
+        .interface::<_, Player>(OBJECT_PATH)
+        .await?;
+    loop {
+        let event = match events.recv().await {
+            Ok(event) => event,
+            Err(RecvError::Lagged(_)) => continue,
+            Err(RecvError::Closed) => return Ok(()),
+        };
+        let context = player.signal_context();
+        let player = player.get().await;
+        match event.name.as_str() {
+            "player:track-changed" => {
+                player.metadata_changed(context).await?;
+                player.can_play_changed(context).await?;
+                player.can_seek_changed(context).await?;
+            }
+            "player:state-changed" => player.playback_status_changed(context).await?,
+            "player:volume-changed" => player.volume_changed(context).await?,
+            "player:queue-changed" => {
+                player.loop_status_changed(context).await?;
+                player.shuffle_changed(context).await?;
+                player.can_go_next_changed(context).await?;
+            }
+            _ => {}
+        }
+    }
+}
+
+/// `org.mpris.MediaPlayer2`, about the app itself.
+struct Root;
+
+#[zbus::interface(name = "org.mpris.MediaPlayer2")]
+impl Root {
+    fn raise(&self) {}
+
+    fn quit(&self) {}
+
+    #[zbus(property)]
+    fn can_raise(&self) -> bool {
+        false
+    }
+
+    #[zbus(property)]
+    fn can_quit(&self) -> bool {
+        false
+    }
+
+    #[zbus(property)]
+    fn has_track_list(&self) -> bool {
+        false
+    }
+
+    #[zbus(property)]
+    fn identity(&self) -> &str {
+        "amptree"
+    }
+
+    #[zbus(property)]
+    fn desktop_entry(&self) -> &str {
+        "amptree"
+    }
+
+    #[zbus(property)]
+    fn supported_uri_schemes(&self) -> Vec<String> {
+        vec!["file".to_string()]
+    }
+
+    #[zbus(property)]
+    fn supported_mime_types(&self) -> Vec<String> {
+        Vec::new()
+    }
+}
+
+/// `org.mpris.MediaPlayer2.Player`, mapped onto the player controller.
+struct Player {
+    services: Services,
+}
+
+impl Player {
+    fn state(&self) -> fdo::Result<PlayerState> {
+        self.services.controller.get_state().map_err(failed)
+    }
+
+    fn position_secs(&self) -> fdo::Result<f64> {
+        let state = self.state()?;
+        Ok(state.status.map_or(0.0, |status| status.played_secs))
+    }
+
+    async fn seek_to(&self, context: &SignalContext<'_>, seconds: f64) -> fdo::Result<()> {
+        let state = self.state()?;
+        let duration = state.track.and_then(|track| track.duration_secs);
+        // Seeking past the end goes to the next track, as the spec asks.
+        if duration.map_or(false, |duration| seconds >= duration) {
+            return self.services.controller.skip().map_err(failed);
+        }
+        let seconds = seconds.max(0.0);
+        self.services.controller.seek(seconds).map_err(failed)?;
+        Player::seeked(context, to_micros(seconds)).await?;
+        Ok(())
+    }
+}
+
+#[zbus::interface(name = "org.mpris.MediaPlayer2.Player")]
+impl Player {
+    fn next(&self) -> fdo::Result<()> {
+        self.services.controller.skip().map_err(failed)
+    }
+
+    fn previous(&self) -> fdo::Result<()> {
+        self.services.controller.previous().map_err(failed)
+    }
+
+    fn pause(&self) -> fdo::Result<()> {
+        self.services.controller.pause().map_err(failed)
+    }
+
+    fn play_pause(&self) -> fdo::Result<()> {
+        match self.state()?.state {
+            PlaybackState::Playing => self.services.controller.pause(),
+            PlaybackState::Paused | PlaybackState::Stopped => self.services.controller.resume(),
+        }
+        .map_err(failed)
+    }
+
+    fn stop(&self) -> fdo::Result<()> {
+        self.services.controller.stop().map_err(failed)
+    }
+
+    fn play(&self) -> fdo::Result<()> {
+        self.services.controller.resume().map_err(failed)
+    }
+
+    /// Moves by `offset` microseconds from the current position.
+    async fn seek(
+        &self,
+        offset: i64,
+        #[zbus(signal_context)] context: SignalContext<'_>,
+    ) -> fdo::Result<()> {
+        let seconds = self.position_secs()? + offset as f64 / 1_000_000.0;
+        self.seek_to(&context, seconds).await
+    }
+
+    /// Moves to `position` microseconds, unless the track changed since the client looked.
+    async fn set_position(
+        &self,
+        track_id: ObjectPath<'_>,
+        position: i64,
+        #[zbus(signal_context)] context: SignalContext<'_>,
+    ) -> fdo::Result<()> {
+        if track_id.as_str() != current_track_id(&self.state()?) || position < 0 {
+            return Ok(());
+        }
+        self.seek_to(&context, position as f64 / 1_000_000.0).await
+    }
+
+    fn open_uri(&self, _uri: &str) -> fdo::Result<()> {
+        let message = "Open files from the library instead.".to_string();
+        Err(fdo::Error::NotSupported(message))
+    }
+
+    #[zbus(signal)]
+    async fn seeked(context: &SignalContext<'_>, position: i64) -> zbus::Result<()>;
+
+    #[zbus(property)]
+    fn playback_status(&self) -> fdo::Result<&str> {
+        let status = match self.state()?.state {
+            PlaybackState::Playing => "Playing",
+            PlaybackState::Paused => "Paused",
+            PlaybackState::Stopped => "Stopped",
+        };
+        Ok(status)
+    }
+
+    #[zbus(property)]
+    fn loop_status(&self) -> fdo::Result<&str> {
+        let status = match self.state()?.queue.repeat {
+            RepeatMode::Off => "None",
+            RepeatMode::One => "Track",
+            RepeatMode::All => "Playlist",
+        };
+        Ok(status)
+    }
+
+    #[zbus(property)]
+    fn set_loop_status(&mut self, status: &str) -> fdo::Result<()> {
+        let repeat = match status {
+            "None" => RepeatMode::Off,
+            "Track" => RepeatMode::One,
+            "Playlist" => RepeatMode::All,
+            _ => {
+                return Err(fdo::Error::InvalidArgs(format!(
+                    "Unknown loop status: {status}"
+                )))
+            }
+        };
+        self.services.controller.set_repeat(repeat).map_err(failed)
+    }
+
+    #[zbus(property)]
+    fn shuffle(&self) -> fdo::Result<bool> {
+        Ok(self.state()?.queue.shuffle != ShuffleMode::Off)
+    }
+
+    #[zbus(property)]
+    fn set_shuffle(&mut self, shuffle: bool) -> fdo::Result<()> {
+        let shuffle = match shuffle {
+            true => ShuffleMode::Tracks,
+            false => ShuffleMode::Off,
+        };
+        self.services
+            .controller
+            .set_shuffle(shuffle)
+            .map_err(failed)
+    }
+
+    #[zbus(property)]
+    fn rate(&self) -> f64 {
+        1.0
+    }
+
+    #[zbus(property)]
+    fn set_rate(&mut self, _rate: f64) {}
+
+    #[zbus(property)]
+    fn minimum_rate(&self) -> f64 {
+        1.0
+    }
+
+    #[zbus(property)]
+    fn maximum_rate(&self) -> f64 {
+        1.0
+    }
+
+    #[zbus(property)]
+    async fn metadata(&self) -> fdo::Result<HashMap<String, Value<'static>>> {
+        let state = self.state()?;
+        let mut metadata = HashMap::new();
+        let track_id = current_track_id(&state);
+        let track_id = ObjectPath::try_from(track_id).map_err(|err| failed(err.into()))?;
+        metadata.insert("mpris:trackid".to_string(), Value::from(track_id));
+        let Some(track) = state.track else {
+            return Ok(metadata);
+        };
+        if let Some(duration) = track.duration_secs {
+            metadata.insert("mpris:length".to_string(), Value::from(to_micros(duration)));
+        }
+        let tags = track.metadata;
+        if let Some(title) = tags.title {
+            metadata.insert("xesam:title".to_string(), Value::from(title));
+        }
+        if let Some(artist) = tags.artist {
+            metadata.insert("xesam:artist".to_string(), Value::from(vec![artist]));
+        }
+        if let Some(album) = tags.album {
+            metadata.insert("xesam:album".to_string(), Value::from(album));
+        }
+        if let Some(genre) = tags.genre {
+            metadata.insert("xesam:genre".to_string(), Value::from(vec![genre]));
+        }
+        if let Some(track_number) = tags.track_number {
+            metadata.insert(
+                "xesam:trackNumber".to_string(),
+                Value::from(track_number as i32),
+            );
+        }
+        if let Some(disc_number) = tags.disc_number {
+            metadata.insert(
+                "xesam:discNumber".to_string(),
+                Value::from(disc_number as i32),
+            );
+        }
+        if let Ok(url) = url::Url::from_file_path(track.file.get_path()) {
+            metadata.insert("xesam:url".to_string(), Value::from(url.to_string()));
+        }
+        if let Some(album_id) = track.file.get_album_id() {
+            match self.services.library.get_cover_file(album_id).await {
+                Ok(Some(path)) => {
+                    if let Ok(url) = url::Url::from_file_path(path) {
+                        metadata.insert("mpris:artUrl".to_string(), Value::from(url.to_string()));
+                    }
+                }
+                Ok(None) => {}
+                Err(err) => eprintln!("Couldn't find the cover of album {album_id}: {:?}", err),
+            }
+        }
+        Ok(metadata)
+    }
+
+    #[zbus(property)]
+    fn volume(&self) -> fdo::Result<f64> {
+        Ok(self.state()?.volume)
+    }
+
+    #[zbus(property)]
+    fn set_volume(&mut self, volume: f64) -> fdo::Result<()> {
+        let volume = volume.clamp(0.0, 1.0);
+        self.services
+            .controller
+            .change_volume(volume)
+            .map_err(failed)
+    }
+
+    /// In microseconds. Clients poll it, as the spec says no change signal is sent for it.
+    #[zbus(property(emits_changed_signal = "false"))]
+    fn position(&self) -> fdo::Result<i64> {
+        Ok(to_micros(self.position_secs()?))
+    }
+
+    #[zbus(property)]
+    fn can_go_next(&self) -> fdo::Result<bool> {
+        Ok(!self.state()?.queue.upcoming.is_empty())
+    }
+
+    #[zbus(property)]
+    fn can_go_previous(&self) -> bool {
+        true
+    }
+
+    #[zbus(property)]
+    fn can_play(&self) -> fdo::Result<bool> {
+        Ok(self.state()?.track.is_some())
+    }
+
+    #[zbus(property)]
+    fn can_pause(&self) -> bool {
+        true
+    }
+
+    #[zbus(property)]
+    fn can_seek(&self) -> fdo::Result<bool> {
+        Ok(self.state()?.track.is_some())
+    }
+
+    #[zbus(property(emits_changed_signal = "const"))]
+    fn can_control(&self) -> bool {
+        true
+    }
+}
+
+/// The object path standing for the current track, which must change when the track does.
+fn current_track_id(state: &PlayerState) -> String {
+    let Some(track) = &state.track else {
+        return NO_TRACK_ID.to_string();
+    };
+    match track.file.get_track_id() {
+        Some(id) => format!("/org/amptree/track/{id}"),
+        None => UNKNOWN_TRACK_ID.to_string(),
+    }
+}
+
+fn to_micros(seconds: f64) -> i64 {
+    (seconds * 1_000_000.0) as i64
+}
+
+fn failed(err: anyhow::Error) -> fdo::Error {
+    fdo::Error::Failed(format!("{err:#}"))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::{BufRead, BufReader};
+    use std::process::{Command, Stdio};
+    use std::sync::Arc;
+
+    use futures_util::StreamExt;
+    use zbus::fdo::PropertiesProxy;
+    use zbus::names::InterfaceName;
+
+    use super::*;
+    use crate::audio::{boot_player, NullOutput, PlayerOptions};
+    use crate::database::init_test_db;
+    use crate::event::EventBus;
+    use crate::library::Library;
+    use crate::settings::Settings;
+
+    #[test]
+    fn test_mpris_on_private_bus() {
+        let daemon = Command::new("dbus-daemon")
+            .args(["--session", "--nofork", "--print-address"])
+            .stdout(Stdio::piped())
+            .spawn();
+        let Ok(mut daemon) = daemon else {
+            eprintln!("dbus-daemon isn't installed, skipping");
+            return;
+        };
+        let mut address = String::new();
+        let stdout = daemon.stdout.take().unwrap();
+        BufReader::new(stdout).read_line(&mut address).unwrap();
+        let address = address.trim().to_string();
+
+        let events = EventBus::default();
+        let (tx, rx) = std::sync::mpsc::channel();
+        let options = PlayerOptions {
+            output: Some(Box::new(NullOutput::new(1.0))),
+            ..Default::default()
+        };
+        let services = Services {
+            controller: boot_player(tx, rx, events.clone(), options).unwrap(),
+            library: Arc::new(Library::new(init_test_db().unwrap())),
+            settings: Arc::new(Settings::new(init_test_db().unwrap())),
+            events,
+        };
+
+        let runtime = tokio::runtime::Runtime::new().unwrap();
+        runtime.block_on(async {
+            let builder = Builder::address(address.as_str()).unwrap();
+            tokio::spawn(serve_on(builder, services));
+
+            let client = Builder::address(address.as_str())
+                .unwrap()
+                .build()
+                .await
+                .unwrap();
+            let proxy = loop {
+                let proxy = PropertiesProxy::builder(&client)
+                    .destination(BUS_NAME)
+                    .unwrap()
+                    .path(OBJECT_PATH)
+                    .unwrap()
+                    .build()
+                    .await
+                    .unwrap();
+                let interface = InterfaceName::from_static_str_unchecked("org.mpris.MediaPlayer2");
+                if proxy.get(interface, "Identity").await.is_ok() {
+                    break proxy;
+                }
+                tokio::task::yield_now().await;
+            };
+            let interface =
+                InterfaceName::from_static_str_unchecked("org.mpris.MediaPlayer2.Player");
+
+            let status = proxy
+                .get(interface.clone(), "PlaybackStatus")
+                .await
+                .unwrap();
+            assert_eq!(status, Value::from("Stopped").try_into().unwrap());
+            let metadata = proxy.get(interface.clone(), "Metadata").await.unwrap();
+            let metadata: HashMap<String, zbus::zvariant::OwnedValue> =
+                metadata.try_into().unwrap();
+            let track_id = ObjectPath::try_from(NO_TRACK_ID).unwrap();
+            assert_eq!(
+                metadata["mpris:trackid"],
+                Value::from(track_id).try_into().unwrap()
+            );
+
+            let mut changes = proxy.receive_properties_changed().await.unwrap();
+            proxy
+                .set(interface.clone(), "Volume", &Value::from(0.5))
+                .await
+                .unwrap();
+            let volume = proxy.get(interface.clone(), "Volume").await.unwrap();
+            assert_eq!(volume, Value::from(0.5).try_into().unwrap());
+
+            let change = changes.next().await.unwrap();
+            let args = change.args().unwrap();
+            assert_eq!(args.interface_name, interface);
+            assert_eq!(args.changed_properties["Volume"], Value::from(0.5));
+        });
+        daemon.kill().unwrap();
+    }
+}
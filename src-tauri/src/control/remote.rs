@@ -0,0 +1,347 @@
+//! A JSON API for phone remotes and home automation: commands over HTTP, and the events the
+//! webview gets over a WebSocket.
+//!
+//! - `POST /api/command` runs a control request, e.g. `{"command": "pause"}`.
+//! - `GET /api/state` and `GET /api/queue` return the player state and the queue.
+//! - `GET /api/events` upgrades to a WebSocket sending each event as `{"event": {...}}`.
+//!
+//! Answers are the same `{"ok": ...}` or `{"error": ...}` objects as on the control socket.
+//! Every request needs the pairing token as `Authorization: Bearer <token>`. Browsers can't set
+//! headers on a WebSocket, so the handshake may pass it as `?token=<token>` instead.
+
+use std::sync::Arc;
+
+use futures_util::{SinkExt, StreamExt};
+use rand::RngCore;
+use tokio::io::{AsyncWriteExt, BufReader};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::broadcast::error::RecvError;
+use tokio::sync::mpsc;
+use tokio_tungstenite::tungstenite::handshake::derive_accept_key;
+use tokio_tungstenite::tungstenite::protocol::Role;
+use tokio_tungstenite::tungstenite::Message;
+use tokio_tungstenite::WebSocketStream;
+
+use super::http::{self, Request, Response};
+use super::{handle_request, secrets_match, ControlRequest, ControlResponse, Services};
+use crate::error::CommandError;
+use crate::event::Event;
+
+/// How the remote control API is set up, saved in the settings.
+#[derive(Debug, Clone, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct RemoteConfig {
+    pub enabled: bool,
+    /// Listens on all interfaces instead of only this machine, for phones on the LAN.
+    pub lan: bool,
+    pub port: u16,
+    /// What clients pair with. Made up the first time the settings are read.
+    pub token: String,
+}
+
+impl Default for RemoteConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            lan: false,
+            port: 7373,
+            token: String::new(),
+        }
+    }
+}
+
+impl RemoteConfig {
+    pub fn address(&self) -> String {
+        let host = match self.lan {
+            true => "0.0.0.0",
+            false => "127.0.0.1",
+        };
+        format!("{host}:{}", self.port)
+    }
+}
+
+/// A new random pairing token, as 32 hex characters.
+pub fn new_pairing_token() -> String {
+    let mut bytes = [0; 16];
+    rand::thread_rng().fill_bytes(&mut bytes);
+    bytes.iter().map(|byte| format!("{byte:02x}")).collect()
+}
+
+/// Accepts remote clients until the listener fails.
+pub async fn serve_remote(config: RemoteConfig, services: Services) -> anyhow::Result<()> {
+    if config.token.is_empty() {
+        anyhow::bail!("The remote control API needs a pairing token.");
+    }
+    let listener = TcpListener::bind(config.address()).await?;
+    accept_clients(listener, config.token, services).await
+}
+
+async fn accept_clients(
+    listener: TcpListener,
+    token: String,
+    services: Services,
+) -> anyhow::Result<()> {
+    let token = Arc::new(token);
+    loop {
+        let (stream, _) = listener.accept().await?;
+        let token = token.clone();
+        let services = services.clone();
+        tokio::spawn(async move {
+            if let Err(err) = handle_connection(stream, &token, services).await {
+                eprintln!("Error in remote connection: {:?}", err);
+            }
+        });
+    }
+}
+
+async fn handle_connection(
+    stream: TcpStream,
+    token: &str,
+    services: Services,
+) -> anyhow::Result<()> {
+    let mut reader = BufReader::new(stream);
+    let request = http::read_request(&mut reader).await;
+    let mut stream = reader.into_inner();
+    let request = match request {
+        Ok(request) => request,
+        Err(err) => {
+            let response = Response::new(400, "text/plain", err.to_string().into_bytes());
+            return http::write_response(&mut stream, response).await;
+        }
+    };
+    if request.method == "OPTIONS" {
+        let mut response = Response::new(204, "text/plain", Vec::new());
+        response.headers.extend([
+            ("Access-Control-Allow-Methods", "GET, POST".to_string()),
+            (
+                "Access-Control-Allow-Headers",
+                "Authorization, Content-Type".to_string(),
+            ),
+        ]);
+        return write_response(&mut stream, response).await;
+    }
+    if !is_paired(&request, token) {
+        let message = "Pair with the token shown in the app's settings.".to_string();
+        let error = ControlResponse::Error(CommandError::InvalidArgument(message));
+        return write_response(&mut stream, json_response(401, &error)?).await;
+    }
+
+    let result = match (request.method.as_str(), request.path.as_str()) {
+        ("GET", "/api/events") => return stream_events(stream, &request, services).await,
+        ("GET", "/api/state") => handle_request(&services, ControlRequest::GetState).await,
+        ("GET", "/api/queue") => handle_request(&services, ControlRequest::GetQueue).await,
+        ("POST", "/api/command") => match serde_json::from_slice(&request.body) {
+            Ok(ControlRequest::Subscribe) => {
+                let message = "Subscribe with a WebSocket on /api/events.".to_string();
+                Err(CommandError::InvalidArgument(message))
+            }
+            Ok(control_request) => handle_request(&services, control_request).await,
+            Err(err) => Err(CommandError::InvalidArgument(err.to_string())),
+        },
+        (_, "/api/events" | "/api/state" | "/api/queue" | "/api/command") => {
+            let response = Response::new(405, "text/plain", b"Method not allowed".to_vec());
+            return write_response(&mut stream, response).await;
+        }
+        _ => {
+            let response = Response::new(404, "text/plain", b"Not found".to_vec());
+            return write_response(&mut stream, response).await;
+        }
+    };
+    let status = match &result {
+        Ok(_) => 200,
+        Err(CommandError::InvalidArgument(_)) => 400,
+        Err(CommandError::FileNotFound(_)) => 404,
+        Err(_) => 500,
+    };
+    write_response(&mut stream, json_response(status, &result.into())?).await
+}
+
+fn is_paired(request: &Request, token: &str) -> bool {
+    let bearer = request
+        .header("Authorization")
+        .and_then(|value| value.strip_prefix("Bearer "));
+    // Tokens in URLs end up in logs and history, so only the WebSocket handshake takes one.
+    let query = match request.path.as_str() {
+        "/api/events" => request.param("token"),
+        _ => None,
+    };
+    let Some(given) = bearer.or(query) else {
+        return false;
+    };
+    secrets_match(given.as_bytes(), token.as_bytes())
+}
+
+fn json_response(status: u16, response: &ControlResponse) -> anyhow::Result<Response> {
+    Ok(Response::new(
+        status,
+        "application/json",
+        serde_json::to_vec(response)?,
+    ))
+}
+
+/// Writes a response any web page can read, as it takes the token in a header to get anything.
+async fn write_response(stream: &mut TcpStream, mut response: Response) -> anyhow::Result<()> {
+    response
+        .headers
+        .push(("Access-Control-Allow-Origin", "*".to_string()));
+    http::write_response(stream, response).await
+}
+
+/// What the WebSocket writer waits on.
+enum Input {
+    Event(Event),
+    Closed,
+}
+
+/// Upgrades to a WebSocket and sends every player event until the client hangs up.
+async fn stream_events(
+    mut stream: TcpStream,
+    request: &Request,
+    services: Services,
+) -> anyhow::Result<()> {
+    let is_upgrade = request
+        .header("Upgrade")
+        .map_or(false, |upgrade| upgrade.eq_ignore_ascii_case("websocket"));
+    let (true, Some(key)) = (is_upgrade, request.header("Sec-WebSocket-Key")) else {
+        let response = Response::new(400, "text/plain", b"Expected a WebSocket".to_vec());
+        return write_response(&mut stream, response).await;
+    };
+    let head = format!(
+        "HTTP/1.1 101 Switching Protocols\r\nUpgrade: websocket\r\nConnection: Upgrade\r\n\
+         Sec-WebSocket-Accept: {}\r\n\r\n",
+        derive_accept_key(key.as_bytes())
+    );
+    stream.write_all(head.as_bytes()).await?;
+    let socket = WebSocketStream::from_raw_socket(stream, Role::Server, None).await;
+    let (mut sink, mut source) = socket.split();
+
+    // Reading answers pings and notices the client leaving, which has to happen while the
+    // writer is waiting for events, so both feed one channel.
+    let (tx, mut rx) = mpsc::unbounded_channel();
+    let reader_tx = tx.clone();
+    let reader = tokio::spawn(async move {
+        while let Some(Ok(message)) = source.next().await {
+            if message.is_close() {
+                break;
+            }
+        }
+        let _ = reader_tx.send(Input::Closed);
+    });
+    let mut events = services.events.subscribe();
+    let forwarder = tokio::spawn(async move {
+        loop {
+            match events.recv().await {
+                Ok(event) => {
+                    if tx.send(Input::Event(event)).is_err() {
+                        return;
+                    }
+                }
+                Err(RecvError::Lagged(_)) => continue,
+                Err(RecvError::Closed) => {
+                    let _ = tx.send(Input::Closed);
+                    return;
+                }
+            }
+        }
+    });
+
+    let mut result = Ok(());
+    while let Some(Input::Event(event)) = rx.recv().await {
+        let text = serde_json::to_string(&ControlResponse::Event(event))?;
+        if let Err(err) = sink.send(Message::Text(text)).await {
+            result = Err(err.into());
+            break;
+        }
+    }
+    reader.abort();
+    forwarder.abort();
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::{json, Value};
+    use tokio::io::AsyncReadExt;
+
+    use super::*;
+    use crate::audio::{boot_player, NullOutput, PlayerOptions};
+    use crate::database::init_test_db;
+    use crate::event::EventBus;
+    use crate::library::Library;
+    use crate::settings::Settings;
+
+    const TOKEN: &str = "0123456789abcdef0123456789abcdef";
+
+    /// Sends a request and returns the status and the JSON body of the response.
+    async fn send(address: &str, head: &str, body: &str) -> (u16, Value) {
+        let mut stream = TcpStream::connect(address).await.unwrap();
+        let request = format!("{head}\r\nContent-Length: {}\r\n\r\n{body}", body.len());
+        stream.write_all(request.as_bytes()).await.unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).await.unwrap();
+        let status = response[9..12].parse().unwrap();
+        let (_, body) = response.split_once("\r\n\r\n").unwrap();
+        (status, serde_json::from_str(body).unwrap_or(Value::Null))
+    }
+
+    #[test]
+    fn test_remote_commands_and_events() {
+        let events = EventBus::default();
+        let (tx, rx) = std::sync::mpsc::channel();
+        let options = PlayerOptions {
+            output: Some(Box::new(NullOutput::new(1.0))),
+            ..Default::default()
+        };
+        let services = Services {
+            controller: boot_player(tx, rx, events.clone(), options).unwrap(),
+            library: Arc::new(Library::new(init_test_db().unwrap())),
+            settings: Arc::new(Settings::new(init_test_db().unwrap())),
+            events,
+        };
+        let authorization = format!("Authorization: Bearer {TOKEN}");
+
+        let runtime = tokio::runtime::Runtime::new().unwrap();
+        runtime.block_on(async {
+            let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+            let address = listener.local_addr().unwrap().to_string();
+            tokio::spawn(accept_clients(listener, TOKEN.to_string(), services));
+
+            let (status, _) = send(&address, "GET /api/state HTTP/1.1", "").await;
+            assert_eq!(status, 401);
+            let head = format!("GET /api/state?token=nope HTTP/1.1\r\n{authorization}x");
+            assert_eq!(send(&address, &head, "").await.0, 401);
+            let head = format!("GET /api/state?token={TOKEN} HTTP/1.1");
+            assert_eq!(send(&address, &head, "").await.0, 401);
+
+            let head = format!("GET /api/state HTTP/1.1\r\n{authorization}");
+            let (status, state) = send(&address, &head, "").await;
+            assert_eq!(status, 200);
+            assert_eq!(state["ok"]["state"], json!("stopped"));
+
+            let head = format!("POST /api/command HTTP/1.1\r\n{authorization}");
+            let seek = "{\"command\": \"seek\", \"seconds\": -1}";
+            let (status, error) = send(&address, &head, seek).await;
+            assert_eq!(status, 400);
+            assert_eq!(error["error"]["code"], json!("invalidArgument"));
+
+            let url = format!("ws://{address}/api/events?token={TOKEN}");
+            let stream = TcpStream::connect(&address).await.unwrap();
+            let (mut socket, _) = tokio_tungstenite::client_async(url, stream).await.unwrap();
+
+            let volume = "{\"command\": \"setVolume\", \"volume\": 100}";
+            assert_eq!(send(&address, &head, volume).await.0, 400);
+            let volume = "{\"command\": \"setVolume\", \"volume\": 0.25}";
+            let (status, _) = send(&address, &head, volume).await;
+            assert_eq!(status, 200);
+            loop {
+                let message = socket.next().await.unwrap().unwrap();
+                let event: Value = serde_json::from_str(message.to_text().unwrap()).unwrap();
+                if event["event"]["name"] == json!("player:volume-changed")
+                    && event["event"]["payload"]["volume"] == json!(0.25)
+                {
+                    break;
+                }
+            }
+        });
+    }
+}
@@ -0,0 +1,146 @@
+use std::path::{Path, PathBuf};
+
+use tokio::io::{AsyncBufReadExt, AsyncWrite, AsyncWriteExt, BufReader};
+use tokio::net::{UnixListener, UnixStream};
+use tokio::sync::broadcast::error::RecvError;
+
+use super::{handle_request, ControlRequest, ControlResponse, Services};
+use crate::error::CommandError;
+
+/// Where instances listen by default: the user's runtime directory when there is one.
+pub fn default_socket_path() -> PathBuf {
+    dirs::runtime_dir()
+        .unwrap_or_else(std::env::temp_dir)
+        .join("amptree.sock")
+}
+
+/// Accepts control connections on a Unix socket at `path` until the listener fails.
+///
+/// A socket file left behind by an instance that's gone is replaced, but one that still
+/// accepts connections means another instance is running.
+pub async fn serve(path: &Path, services: Services) -> anyhow::Result<()> {
+    if UnixStream::connect(path).await.is_ok() {
+        anyhow::bail!("Another instance is listening on {}.", path.display());
+    }
+    let _ = std::fs::remove_file(path);
+    let listener = UnixListener::bind(path)?;
+    loop {
+        let (stream, _) = listener.accept().await?;
+        let services = services.clone();
+        tokio::spawn(async move {
+            if let Err(err) = handle_connection(stream, services).await {
+                eprintln!("Error in control connection: {:?}", err);
+            }
+        });
+    }
+}
+
+async fn handle_connection(stream: UnixStream, services: Services) -> anyhow::Result<()> {
+    let (reader, mut writer) = stream.into_split();
+    let mut lines = BufReader::new(reader).lines();
+    while let Some(line) = lines.next_line().await? {
+        if line.trim().is_empty() {
+            continue;
+        }
+        let response = match serde_json::from_str::<ControlRequest>(&line) {
+            Ok(ControlRequest::Subscribe) => return stream_events(&mut writer, &services).await,
+            Ok(request) => handle_request(&services, request).await.into(),
+            Err(err) => ControlResponse::Error(CommandError::InvalidArgument(err.to_string())),
+        };
+        write_response(&mut writer, &response).await?;
+    }
+    Ok(())
+}
+
+/// Sends every player event until the client hangs up.
+async fn stream_events<W>(writer: &mut W, services: &Services) -> anyhow::Result<()>
+where
+    W: AsyncWrite + Unpin,
+{
+    let mut events = services.events.subscribe();
+    write_response(writer, &ControlResponse::Ok(serde_json::Value::Null)).await?;
+    loop {
+        match events.recv().await {
+            Ok(event) => write_response(writer, &ControlResponse::Event(event)).await?,
+            Err(RecvError::Lagged(_)) => continue,
+            Err(RecvError::Closed) => return Ok(()),
+        }
+    }
+}
+
+async fn write_response<W>(writer: &mut W, response: &ControlResponse) -> anyhow::Result<()>
+where
+    W: AsyncWrite + Unpin,
+{
+    let mut line = serde_json::to_vec(response)?;
+    line.push(b'\n');
+    writer.write_all(&line).await?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use serde_json::{json, Value};
+
+    use super::*;
+    use crate::audio::{boot_player, NullOutput, PlayerOptions};
+    use crate::database::init_test_db;
+    use crate::event::EventBus;
+    use crate::library::Library;
+    use crate::settings::Settings;
+
+    async fn request(stream: &mut BufReader<UnixStream>, line: &str) -> Value {
+        stream.write_all(line.as_bytes()).await.unwrap();
+        let mut response = String::new();
+        stream.read_line(&mut response).await.unwrap();
+        serde_json::from_str(&response).unwrap()
+    }
+
+    #[test]
+    fn test_control_socket_round_trip() {
+        let events = EventBus::default();
+        let (tx, rx) = std::sync::mpsc::channel();
+        let options = PlayerOptions {
+            output: Some(Box::new(NullOutput::new(1.0))),
+            ..Default::default()
+        };
+        let services = Services {
+            controller: boot_player(tx, rx, events.clone(), options).unwrap(),
+            library: Arc::new(Library::new(init_test_db().unwrap())),
+            settings: Arc::new(Settings::new(init_test_db().unwrap())),
+            events,
+        };
+        let path = std::env::temp_dir().join(format!("amptree-{}.sock", std::process::id()));
+
+        let runtime = tokio::runtime::Runtime::new().unwrap();
+        runtime.block_on(async {
+            let server_path = path.clone();
+            tokio::spawn(async move { serve(&server_path, services).await });
+            let stream = loop {
+                match UnixStream::connect(&path).await {
+                    Ok(stream) => break stream,
+                    Err(_) => tokio::task::yield_now().await,
+                }
+            };
+            let mut stream = BufReader::new(stream);
+
+            let state = request(&mut stream, "{\"command\": \"getState\"}\n").await;
+            assert_eq!(state["ok"]["state"], json!("stopped"));
+
+            let seek = request(&mut stream, "{\"command\": \"seek\", \"seconds\": -1}\n").await;
+            assert_eq!(seek["error"]["code"], json!("invalidArgument"));
+
+            let clear = request(&mut stream, "{\"command\": \"clearQueue\"}\n").await;
+            assert_eq!(clear, json!({ "ok": null }));
+
+            let search = "{\"command\": \"search\", \"query\": \"nothing\"}\n";
+            assert_eq!(request(&mut stream, search).await, json!({ "ok": [] }));
+
+            let unknown = request(&mut stream, "{\"command\": \"dance\"}\n").await;
+            assert_eq!(unknown["error"]["code"], json!("invalidArgument"));
+        });
+        let _ = std::fs::remove_file(path);
+    }
+}
@@ -0,0 +1,141 @@
+//! Subsonic responses, as JSON or as the XML they're derived from.
+
+use serde_json::{Map, Value};
+
+use super::SubsonicError;
+
+/// The API version implemented, as far as the endpoints that exist go.
+const API_VERSION: &str = "1.16.1";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(super) enum Format {
+    Xml,
+    Json,
+}
+
+impl Format {
+    /// Picks the format asked for with `f=`, defaulting to XML like Subsonic does.
+    pub fn from_param(format: Option<&str>) -> Self {
+        match format {
+            Some("json") => Format::Json,
+            _ => Format::Xml,
+        }
+    }
+
+    pub fn content_type(self) -> &'static str {
+        match self {
+            Format::Xml => "text/xml; charset=utf-8",
+            Format::Json => "application/json",
+        }
+    }
+}
+
+pub(super) fn ok(format: Format, payload: Map<String, Value>) -> Vec<u8> {
+    render(format, "ok", payload)
+}
+
+pub(super) fn error(format: Format, error: &SubsonicError) -> Vec<u8> {
+    let mut payload = Map::new();
+    let details = serde_json::json!({ "code": error.code, "message": error.message });
+    payload.insert("error".to_string(), details);
+    render(format, "failed", payload)
+}
+
+fn render(format: Format, status: &str, payload: Map<String, Value>) -> Vec<u8> {
+    let mut response = Map::new();
+    response.insert("status".to_string(), status.into());
+    response.insert("version".to_string(), API_VERSION.into());
+    response.insert("type".to_string(), "amptree".into());
+    response.insert("openSubsonic".to_string(), true.into());
+    response.extend(payload);
+    match format {
+        Format::Json => {
+            let body = serde_json::json!({ "subsonic-response": response });
+            body.to_string().into_bytes()
+        }
+        Format::Xml => {
+            let mut xml = String::from("<?xml version=\"1.0\" encoding=\"UTF-8\"?>");
+            response.insert("xmlns".to_string(), "http://subsonic.org/restapi".into());
+            write_element(&mut xml, "subsonic-response", &Value::Object(response));
+            xml.into_bytes()
+        }
+    }
+}
+
+/// Writes a JSON value the way Subsonic maps its XML to JSON, backwards: scalar fields are
+/// attributes, objects are child elements and arrays are repeated child elements.
+fn write_element(xml: &mut String, name: &str, value: &Value) {
+    xml.push('<');
+    xml.push_str(name);
+    let Value::Object(fields) = value else {
+        xml.push('>');
+        xml.push_str(&escape(&scalar_text(value)));
+        xml.push_str(&format!("</{name}>"));
+        return;
+    };
+    let mut children = Vec::new();
+    for (key, field) in fields {
+        match field {
+            Value::Object(_) => children.push((key, field)),
+            Value::Array(items) => children.extend(items.iter().map(|item| (key, item))),
+            Value::Null => {}
+            _ => xml.push_str(&format!(" {key}=\"{}\"", escape(&scalar_text(field)))),
+        }
+    }
+    if children.is_empty() {
+        xml.push_str("/>");
+        return;
+    }
+    xml.push('>');
+    for (key, child) in children {
+        write_element(xml, key, child);
+    }
+    xml.push_str(&format!("</{name}>"));
+}
+
+fn scalar_text(value: &Value) -> String {
+    match value {
+        Value::String(text) => text.clone(),
+        other => other.to_string(),
+    }
+}
+
+fn escape(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+
+    #[test]
+    fn test_renders_xml_like_json() {
+        let payload = json!({
+            "album": {
+                "id": "al-1",
+                "name": "Rock & Roll",
+                "song": [{ "id": "tr-1", "track": 1 }, { "id": "tr-2", "track": 2 }],
+            },
+        });
+        let Value::Object(payload) = payload else {
+            unreachable!()
+        };
+
+        let xml = String::from_utf8(ok(Format::Xml, payload.clone())).unwrap();
+        assert!(xml.ends_with(
+            "<album id=\"al-1\" name=\"Rock &amp; Roll\">\
+             <song id=\"tr-1\" track=\"1\"/><song id=\"tr-2\" track=\"2\"/>\
+             </album></subsonic-response>"
+        ));
+        assert!(xml.contains(" status=\"ok\""));
+
+        let json: Value = serde_json::from_slice(&ok(Format::Json, payload)).unwrap();
+        assert_eq!(json["subsonic-response"]["status"], "ok");
+        assert_eq!(json["subsonic-response"]["album"]["song"][1]["track"], 2);
+    }
+}
@@ -0,0 +1,644 @@
+//! The core of the Subsonic API, so phone apps on the LAN can browse and stream the library.
+//!
+//! Files are streamed as they are, without transcoding. Ids are prefixed with what they are
+//! the id of, e.g. `al-12` for album 12, as Subsonic clients treat them as opaque strings.
+
+use std::sync::Arc;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use serde_json::{json, Map, Value};
+use tokio::io::{AsyncSeekExt, BufReader};
+use tokio::net::{TcpListener, TcpStream};
+
+use super::http::{self, Body, Request, Response};
+use super::Services;
+use crate::audio::{Listen, ListenLog, ListenOutcome};
+use crate::library::{
+    AlbumFilter, AlbumSummary, ArtistSummary, LibraryTrack, ListenRecorder, TagMatch, TrackFilter,
+    TrackSelection, TrackTag,
+};
+use format::Format;
+
+mod auth;
+mod format;
+
+/// How the Subsonic server is set up, saved in the settings.
+#[derive(Debug, Clone, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct SubsonicConfig {
+    pub enabled: bool,
+    /// Where to listen, all interfaces by default so phones can connect.
+    pub address: String,
+    pub username: String,
+    /// Kept in clear: token authentication hashes it with a salt picked by the client.
+    pub password: String,
+}
+
+impl Default for SubsonicConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            address: "0.0.0.0:4040".to_string(),
+            username: "amptree".to_string(),
+            password: String::new(),
+        }
+    }
+}
+
+#[derive(Debug)]
+pub(super) struct SubsonicError {
+    code: u32,
+    message: String,
+}
+
+impl SubsonicError {
+    fn new(code: u32, message: impl Into<String>) -> Self {
+        Self {
+            code,
+            message: message.into(),
+        }
+    }
+
+    fn missing(param: &str) -> Self {
+        Self::new(10, format!("Required parameter is missing: {param}"))
+    }
+
+    fn not_found(what: &str) -> Self {
+        Self::new(70, format!("{what} not found"))
+    }
+}
+
+impl From<anyhow::Error> for SubsonicError {
+    fn from(err: anyhow::Error) -> Self {
+        Self::new(0, format!("{err:#}"))
+    }
+}
+
+struct Server {
+    config: SubsonicConfig,
+    services: Services,
+    listens: ListenRecorder,
+}
+
+enum Reply {
+    Payload(Map<String, Value>),
+    Picture { media_type: String, data: Vec<u8> },
+    File { path: String },
+}
+
+/// Accepts Subsonic clients on the configured address until the listener fails. Scrobbles are
+/// recorded to `listens`.
+pub async fn serve_subsonic(
+    config: SubsonicConfig,
+    services: Services,
+    listens: ListenRecorder,
+) -> anyhow::Result<()> {
+    if config.password.is_empty() {
+        anyhow::bail!("Set a password before enabling the Subsonic server.");
+    }
+    let listener = TcpListener::bind(&config.address).await?;
+    let server = Server {
+        config,
+        services,
+        listens,
+    };
+    accept_clients(listener, server).await
+}
+
+async fn accept_clients(listener: TcpListener, server: Server) -> anyhow::Result<()> {
+    let server = Arc::new(server);
+    loop {
+        let (stream, _) = listener.accept().await?;
+        let server = server.clone();
+        tokio::spawn(async move {
+            if let Err(err) = server.handle_connection(stream).await {
+                eprintln!("Error in Subsonic connection: {:?}", err);
+            }
+        });
+    }
+}
+
+impl Server {
+    async fn handle_connection(&self, stream: TcpStream) -> anyhow::Result<()> {
+        let (reader, mut writer) = stream.into_split();
+        let request = match http::read_request(&mut BufReader::new(reader)).await {
+            Ok(request) => request,
+            Err(err) => {
+                let response = Response::new(400, "text/plain", err.to_string().into_bytes());
+                return http::write_response(&mut writer, response).await;
+            }
+        };
+        let response = self.respond(&request).await;
+        http::write_response(&mut writer, response).await
+    }
+
+    async fn respond(&self, request: &Request) -> Response {
+        let format = Format::from_param(request.param("f"));
+        let Some(endpoint) = request.path.strip_prefix("/rest/") else {
+            return Response::new(404, "text/plain", b"Not found".to_vec());
+        };
+        let endpoint = endpoint.trim_end_matches(".view");
+        let reply = match auth::authenticate(&self.config, request) {
+            Ok(()) => self.handle(endpoint, request).await,
+            Err(err) => Err(err),
+        };
+        match reply {
+            Ok(Reply::Payload(payload)) => {
+                Response::new(200, format.content_type(), format::ok(format, payload))
+            }
+            Ok(Reply::Picture { media_type, data }) => Response::new(200, &media_type, data),
+            Ok(Reply::File { path }) => file_response(request, &path).await,
+            // Subsonic reports failures in the body, with a successful status.
+            Err(err) => Response::new(200, format.content_type(), format::error(format, &err)),
+        }
+    }
+
+    async fn handle(&self, endpoint: &str, request: &Request) -> Result<Reply, SubsonicError> {
+        let library = &self.services.library;
+        let mut payload = Map::new();
+        match endpoint {
+            "ping" => {}
+            "getLicense" => {
+                payload.insert("license".to_string(), json!({ "valid": true }));
+            }
+            "getArtists" => {
+                let artists = library.get_artists(None).await?;
+                let artists = json!({ "ignoredArticles": "", "index": artist_index(&artists) });
+                payload.insert("artists".to_string(), artists);
+            }
+            "getArtist" => {
+                let id = parse_id(request, "ar")?;
+                let artists = library.get_artists(None).await?;
+                let Some(artist) = artists.iter().find(|artist| artist.id == id) else {
+                    return Err(SubsonicError::not_found("Artist"));
+                };
+                let albums = library.get_albums(&AlbumFilter::Artist(id)).await?;
+                let mut artist = artist_json(artist);
+                artist["album"] = albums.iter().map(album_json).collect();
+                payload.insert("artist".to_string(), artist);
+            }
+            "getAlbum" => {
+                let id = parse_id(request, "al")?;
+                let albums = library.get_albums(&AlbumFilter::Id(id)).await?;
+                let Some(album) = albums.first() else {
+                    return Err(SubsonicError::not_found("Album"));
+                };
+                let tracks = library.get_tracks(&TrackSelection::Album { id }).await?;
+                let mut album = album_json(album);
+                album["song"] = songs_json(&tracks).await.into();
+                payload.insert("album".to_string(), album);
+            }
+            "getCoverArt" => {
+                let id = parse_id(request, "al")?;
+                let Some(picture) = library.get_cover(id).await? else {
+                    return Err(SubsonicError::not_found("Cover art"));
+                };
+                return Ok(Reply::Picture {
+                    media_type: picture.media_type,
+                    data: picture.data,
+                });
+            }
+            "stream" | "download" => {
+                let track = self.get_track(parse_id(request, "tr")?).await?;
+                return Ok(Reply::File { path: track.path });
+            }
+            "search3" => {
+                let query = request.param("query").unwrap_or_default().trim_matches('"');
+                let (count, offset) = paging(request, "artist")?;
+                let artists = library.get_artists(Some(query)).await?;
+                let artists: Vec<Value> = artists
+                    .iter()
+                    .skip(offset)
+                    .take(count)
+                    .map(artist_json)
+                    .collect();
+                let (count, offset) = paging(request, "album")?;
+                let albums = library
+                    .get_albums(&AlbumFilter::NameContains(query.to_string()))
+                    .await?;
+                let albums: Vec<Value> = albums
+                    .iter()
+                    .skip(offset)
+                    .take(count)
+                    .map(album_json)
+                    .collect();
+                let (count, offset) = paging(request, "song")?;
+                let filter = TrackFilter {
+                    tag: TrackTag::Any,
+                    matching: TagMatch::Contains,
+                    value: query.to_string(),
+                };
+                let songs = library.find_tracks(&[filter]).await?;
+                let songs: Vec<LibraryTrack> = songs.into_iter().skip(offset).take(count).collect();
+                let songs = songs_json(&songs).await;
+                let result = json!({ "artist": artists, "album": albums, "song": songs });
+                payload.insert("searchResult3".to_string(), result);
+            }
+            "getPlaylists" => {
+                let playlists = library.get_playlists().await?;
+                let playlists: Vec<Value> = playlists
+                    .iter()
+                    .map(|playlist| {
+                        json!({
+                            "id": format!("pl-{}", playlist.id),
+                            "name": playlist.name,
+                            "songCount": playlist.track_count,
+                            "owner": self.config.username,
+                            "public": false,
+                        })
+                    })
+                    .collect();
+                payload.insert("playlists".to_string(), json!({ "playlist": playlists }));
+            }
+            "getPlaylist" => {
+                let id = parse_id(request, "pl")?;
+                let playlists = library.get_playlists().await?;
+                let Some(playlist) = playlists.iter().find(|playlist| playlist.id == id) else {
+                    return Err(SubsonicError::not_found("Playlist"));
+                };
+                let tracks = library.get_tracks(&TrackSelection::Playlist { id }).await?;
+                let playlist = json!({
+                    "id": format!("pl-{}", playlist.id),
+                    "name": playlist.name,
+                    "songCount": playlist.track_count,
+                    "owner": self.config.username,
+                    "public": false,
+                    "entry": songs_json(&tracks).await,
+                });
+                payload.insert("playlist".to_string(), playlist);
+            }
+            "scrobble" => {
+                // Now playing notifications aren't listens.
+                if request.param("submission") == Some("false") {
+                    return Ok(Reply::Payload(payload));
+                }
+                let ids = request
+                    .params("id")
+                    .map(|id| parse_prefixed(id, "tr"))
+                    .collect::<Result<Vec<i64>, _>>()?;
+                // When each track was played, in milliseconds, or now.
+                let mut times = request.params("time").map(|time| time.parse::<i64>());
+                let now = SystemTime::now()
+                    .duration_since(UNIX_EPOCH)
+                    .map_err(anyhow::Error::from)?;
+                for id in ids {
+                    let started_at = match times.next() {
+                        Some(Ok(time)) => time / 1000,
+                        Some(Err(_)) => return Err(SubsonicError::new(0, "Invalid time")),
+                        None => now.as_secs() as i64,
+                    };
+                    let selection = TrackSelection::Tracks { ids: vec![id] };
+                    for file in library.get_audio_files(&selection).await? {
+                        let listen = Listen {
+                            file,
+                            started_at,
+                            played_secs: None,
+                            duration_secs: None,
+                            metadata: None,
+                            outcome: ListenOutcome::Completed,
+                        };
+                        self.listens.record_listen(&listen)?;
+                    }
+                }
+            }
+            _ => {
+                let message = format!("Unsupported endpoint: {endpoint}");
+                return Err(SubsonicError::new(0, message));
+            }
+        }
+        Ok(Reply::Payload(payload))
+    }
+
+    async fn get_track(&self, id: i64) -> Result<LibraryTrack, SubsonicError> {
+        let selection = TrackSelection::Tracks { ids: vec![id] };
+        let tracks = self.services.library.get_tracks(&selection).await?;
+        let Some(track) = tracks.into_iter().next() else {
+            return Err(SubsonicError::not_found("Song"));
+        };
+        Ok(track)
+    }
+}
+
+/// Serves a file, or the part of it asked for with a `Range` header.
+async fn file_response(request: &Request, path: &str) -> Response {
+    let result = async {
+        let mut file = tokio::fs::File::open(path).await?;
+        let length = file.metadata().await?.len();
+        let mut headers = vec![
+            ("Content-Type", audio_content_type(path).to_string()),
+            ("Accept-Ranges", "bytes".to_string()),
+        ];
+        let Some(range) = request.header("Range") else {
+            let body = Body::File { file, length };
+            return anyhow::Ok(Response {
+                status: 200,
+                headers,
+                body,
+            });
+        };
+        let Some((start, end)) = http::parse_range(range, length) else {
+            let mut response = Response::new(416, "text/plain", Vec::new());
+            let content_range = format!("bytes */{length}");
+            response.headers.push(("Content-Range", content_range));
+            return Ok(response);
+        };
+        file.seek(std::io::SeekFrom::Start(start)).await?;
+        headers.push(("Content-Range", format!("bytes {start}-{end}/{length}")));
+        let body = Body::File {
+            file,
+            length: end - start + 1,
+        };
+        Ok(Response {
+            status: 206,
+            headers,
+            body,
+        })
+    };
+    match result.await {
+        Ok(response) => response,
+        Err(err) => Response::new(404, "text/plain", format!("{err:#}").into_bytes()),
+    }
+}
+
+fn audio_content_type(path: &str) -> &'static str {
+    let extension = std::path::Path::new(path)
+        .extension()
+        .map(|extension| extension.to_string_lossy().to_lowercase());
+    match extension.as_deref() {
+        Some("mp3") => "audio/mpeg",
+        Some("flac") => "audio/flac",
+        Some("ogg" | "oga" | "opus") => "audio/ogg",
+        Some("m4a" | "mp4" | "aac") => "audio/mp4",
+        Some("wav") => "audio/wav",
+        _ => "application/octet-stream",
+    }
+}
+
+fn parse_id(request: &Request, prefix: &str) -> Result<i64, SubsonicError> {
+    let Some(id) = request.param("id") else {
+        return Err(SubsonicError::missing("id"));
+    };
+    parse_prefixed(id, prefix)
+}
+
+fn parse_prefixed(id: &str, prefix: &str) -> Result<i64, SubsonicError> {
+    id.strip_prefix(prefix)
+        .and_then(|id| id.strip_prefix('-'))
+        .and_then(|id| id.parse().ok())
+        .ok_or_else(|| SubsonicError::not_found(&format!("Item {id}")))
+}
+
+fn parse_number(request: &Request, name: &str, default: usize) -> Result<usize, SubsonicError> {
+    match request.param(name) {
+        Some(value) => value
+            .parse()
+            .map_err(|_| SubsonicError::new(0, format!("Invalid {name}: {value}"))),
+        None => Ok(default),
+    }
+}
+
+/// The `<kind>Count` and `<kind>Offset` parameters of a search.
+fn paging(request: &Request, kind: &str) -> Result<(usize, usize), SubsonicError> {
+    let count = parse_number(request, &format!("{kind}Count"), 20)?;
+    let offset = parse_number(request, &format!("{kind}Offset"), 0)?;
+    Ok((count, offset))
+}
+
+/// Groups artists by the first letter of their name, the way clients show them.
+fn artist_index(artists: &[ArtistSummary]) -> Vec<Value> {
+    let mut index: Vec<(String, Vec<Value>)> = Vec::new();
+    for artist in artists {
+        let letter = match artist.name.chars().next() {
+            Some(letter) if letter.is_alphabetic() => letter.to_uppercase().to_string(),
+            _ => "#".to_string(),
+        };
+        match index.iter_mut().find(|(name, _)| *name == letter) {
+            Some((_, entries)) => entries.push(artist_json(artist)),
+            None => index.push((letter, vec![artist_json(artist)])),
+        }
+    }
+    index
+        .into_iter()
+        .map(|(name, artists)| json!({ "name": name, "artist": artists }))
+        .collect()
+}
+
+fn artist_json(artist: &ArtistSummary) -> Value {
+    json!({
+        "id": format!("ar-{}", artist.id),
+        "name": artist.name,
+        "albumCount": artist.album_count,
+    })
+}
+
+fn album_json(album: &AlbumSummary) -> Value {
+    json!({
+        "id": format!("al-{}", album.id),
+        "name": album.name,
+        "artist": album.artist,
+        "artistId": format!("ar-{}", album.artist_id),
+        "coverArt": format!("al-{}", album.id),
+        "songCount": album.track_count,
+    })
+}
+
+async fn songs_json(tracks: &[LibraryTrack]) -> Vec<Value> {
+    let mut songs = Vec::with_capacity(tracks.len());
+    for track in tracks {
+        songs.push(song_json(track).await);
+    }
+    songs
+}
+
+async fn song_json(track: &LibraryTrack) -> Value {
+    let suffix = std::path::Path::new(&track.path)
+        .extension()
+        .map(|extension| extension.to_string_lossy().to_lowercase());
+    let size = tokio::fs::metadata(&track.path)
+        .await
+        .map(|metadata| metadata.len())
+        .ok();
+    let mut song = json!({
+        "id": format!("tr-{}", track.id),
+        "parent": format!("al-{}", track.album_id),
+        "isDir": false,
+        "title": track.title,
+        "album": track.album,
+        "artist": track.artist,
+        "track": track.album_order,
+        "discNumber": track.disc_number,
+        "coverArt": format!("al-{}", track.album_id),
+        "size": size,
+        "suffix": suffix,
+        "contentType": audio_content_type(&track.path),
+        "albumId": format!("al-{}", track.album_id),
+        "playCount": track.play_count,
+        "artistId": format!("ar-{}", track.artist_id),
+        "type": "music",
+    });
+    // Clients expect missing fields rather than nulls.
+    if let Value::Object(fields) = &mut song {
+        fields.retain(|_, value| !value.is_null());
+    }
+    song
+}
+
+#[cfg(test)]
+mod tests {
+    use rusqlite::Connection;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    use super::*;
+    use crate::audio::{boot_player, NullOutput, PlayerOptions};
+    use crate::database::{init_test_db, init_test_db_file};
+    use crate::event::EventBus;
+    use crate::library::Library;
+    use crate::settings::Settings;
+
+    fn server(library: Connection, listens: Connection) -> Server {
+        let events = EventBus::default();
+        let (tx, rx) = std::sync::mpsc::channel();
+        let options = PlayerOptions {
+            output: Some(Box::new(NullOutput::new(1.0))),
+            ..Default::default()
+        };
+        let services = Services {
+            controller: boot_player(tx, rx, events.clone(), options).unwrap(),
+            library: Arc::new(Library::new(library)),
+            settings: Arc::new(Settings::new(init_test_db().unwrap())),
+            events,
+        };
+        let config = SubsonicConfig {
+            enabled: true,
+            address: "127.0.0.1:0".to_string(),
+            username: "amptree".to_string(),
+            password: "sesame".to_string(),
+        };
+        Server {
+            config,
+            services,
+            listens: ListenRecorder::new(listens),
+        }
+    }
+
+    /// Serves on a free port and returns its address.
+    async fn start(server: Server) -> String {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap().to_string();
+        tokio::spawn(accept_clients(listener, server));
+        address
+    }
+
+    async fn send(address: &str, target: &str, headers: &str) -> String {
+        let mut stream = TcpStream::connect(address).await.unwrap();
+        let request = format!("GET {target} HTTP/1.1\r\nHost: {address}\r\n{headers}\r\n");
+        stream.write_all(request.as_bytes()).await.unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).await.unwrap();
+        response
+    }
+
+    async fn get(address: &str, target: &str) -> String {
+        send(address, target, "").await
+    }
+
+    /// Calls an endpoint with the test credentials and returns the JSON response.
+    async fn call(address: &str, endpoint: &str, params: &str) -> Value {
+        let target = format!("/rest/{endpoint}?u=amptree&p=sesame&f=json&{params}");
+        let response = get(address, &target).await;
+        let (_, body) = response.split_once("\r\n\r\n").unwrap();
+        let mut body: Value = serde_json::from_str(body).unwrap();
+        assert_eq!(body["subsonic-response"]["status"], "ok", "{body}");
+        body["subsonic-response"].take()
+    }
+
+    #[test]
+    fn test_subsonic_authentication() {
+        let server = server(init_test_db().unwrap(), init_test_db().unwrap());
+
+        let runtime = tokio::runtime::Runtime::new().unwrap();
+        runtime.block_on(async {
+            let address = start(server).await;
+
+            let ping =
+                "/rest/ping.view?u=amptree&t=26719a1196d2a940705a59634eb18eab&s=c19b2d&f=json";
+            let response = get(&address, ping).await;
+            assert!(response.starts_with("HTTP/1.1 200 OK\r\n"));
+            assert!(response.contains("\"status\":\"ok\""));
+
+            let response = get(&address, "/rest/getArtists?u=amptree&p=enc:736573616d65").await;
+            assert!(response.contains("status=\"ok\""));
+            assert!(response.contains("<artists"));
+
+            let response = get(&address, "/rest/ping?u=amptree&p=wrong&f=json").await;
+            assert!(response.contains("\"status\":\"failed\""));
+            assert!(response.contains("\"code\":40"));
+
+            let response = get(&address, "/rest/ping?u=amptree&f=json").await;
+            assert!(response.contains("\"code\":10"));
+        });
+    }
+
+    #[test]
+    fn test_subsonic_library() {
+        let name = format!("amptree-{}-subsonic", std::process::id());
+        let database = std::env::temp_dir().join(format!("{name}.sqlite"));
+        for suffix in ["", "-wal", "-shm"] {
+            let _ = std::fs::remove_file(format!("{}{suffix}", database.display()));
+        }
+        let song = std::env::temp_dir().join(format!("{name}.mp3"));
+        std::fs::write(&song, "0123456789").unwrap();
+        let library = init_test_db_file(&database).unwrap();
+        library
+            .execute_batch(&format!(
+                "INSERT INTO Artists (id, name) VALUES (1, 'Low');
+                 INSERT INTO Albums (id, name, artist_id) VALUES (1, 'Things We Lost', 1);
+                 INSERT INTO Tracks (id, name, path, album_order, album_id)
+                 VALUES (1, 'Sunflower', '{}', 1, 1), (2, 'Whore', '/nowhere.flac', 2, 1);
+                 INSERT INTO Playlists (id, name) VALUES (1, 'Mix');
+                 INSERT INTO PlaylistTracks (playlist_id, track_id, position) VALUES (1, 2, 0);",
+                song.display()
+            ))
+            .unwrap();
+        let server = server(library, init_test_db_file(&database).unwrap());
+
+        let runtime = tokio::runtime::Runtime::new().unwrap();
+        runtime.block_on(async {
+            let address = start(server).await;
+
+            let album = call(&address, "getAlbum", "id=al-1").await;
+            let songs = album["album"]["song"].as_array().unwrap();
+            assert_eq!(songs.len(), 2);
+            assert_eq!(songs[0]["id"], "tr-1");
+            assert_eq!(songs[0]["size"], 10);
+            assert_eq!(songs[0]["contentType"], "audio/mpeg");
+            assert!(songs[1].get("size").is_none());
+
+            let result = call(&address, "search3", "query=sun").await;
+            let result = &result["searchResult3"];
+            assert_eq!(result["artist"], json!([]));
+            assert_eq!(result["song"][0]["title"], "Sunflower");
+            let result = call(&address, "search3", "query=low&songCount=0").await;
+            assert_eq!(result["searchResult3"]["artist"][0]["id"], "ar-1");
+            assert_eq!(result["searchResult3"]["song"], json!([]));
+
+            let playlists = call(&address, "getPlaylists", "").await;
+            let playlist = &playlists["playlists"]["playlist"][0];
+            assert_eq!(playlist["id"], "pl-1");
+            assert_eq!(playlist["songCount"], 1);
+
+            let target = "/rest/stream?u=amptree&p=sesame&id=tr-1";
+            let response = send(&address, target, "Range: bytes=2-5\r\n").await;
+            assert!(response.starts_with("HTTP/1.1 206 "));
+            assert!(response.contains("Content-Range: bytes 2-5/10\r\n"));
+            assert!(response.ends_with("\r\n\r\n2345"));
+
+            call(&address, "scrobble", "id=tr-1&submission=false").await;
+            let album = call(&address, "getAlbum", "id=al-1").await;
+            assert_eq!(album["album"]["song"][0]["playCount"], 0);
+            call(&address, "scrobble", "id=tr-1&time=1000000000").await;
+            let album = call(&address, "getAlbum", "id=al-1").await;
+            assert_eq!(album["album"]["song"][0]["playCount"], 1);
+        });
+    }
+}
@@ -25,13 +25,22 @@ fn configure_db(conn: &mut Connection) -> Result<()> {
 }
 
 #[cfg(test)]
-fn init_test_db() -> Result<Connection> {
+pub(crate) fn init_test_db() -> Result<Connection> {
     let mut conn = Connection::open_in_memory()?;
     configure_db(&mut conn)?;
     migrations::migrate(&mut conn)?;
     Ok(conn)
 }
 
+/// A test database in a file, for tests where several connections have to share it.
+#[cfg(test)]
+pub(crate) fn init_test_db_file(path: &std::path::Path) -> Result<Connection> {
+    let mut conn = Connection::open(path)?;
+    configure_db(&mut conn)?;
+    migrations::migrate(&mut conn)?;
+    Ok(conn)
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -0,0 +1,110 @@
+use std::fmt;
+use std::io::ErrorKind;
+
+use symphonia::core::errors::Error as SymphoniaError;
+
+/// Error returned by every command, serialized as `{ "code": ..., "message": ... }` so the
+/// frontend can react to the kind of failure instead of parsing messages.
+#[derive(Debug, serde::Serialize, Clone, PartialEq, Eq)]
+#[serde(tag = "code", content = "message", rename_all = "camelCase")]
+pub enum CommandError {
+    FileNotFound(String),
+    UnsupportedFormat(String),
+    NoDevice(String),
+    DecodeFailed(String),
+    DbError(String),
+    /// The player thread stopped and can't take commands anymore.
+    PlayerUnavailable(String),
+    InvalidArgument(String),
+    Internal(String),
+}
+
+impl fmt::Display for CommandError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            CommandError::FileNotFound(message)
+            | CommandError::UnsupportedFormat(message)
+            | CommandError::NoDevice(message)
+            | CommandError::DecodeFailed(message)
+            | CommandError::DbError(message)
+            | CommandError::PlayerUnavailable(message)
+            | CommandError::InvalidArgument(message)
+            | CommandError::Internal(message) => f.write_str(message),
+        }
+    }
+}
+
+impl std::error::Error for CommandError {}
+
+impl From<anyhow::Error> for CommandError {
+    /// Picks the code from the most specific error in the chain.
+    fn from(err: anyhow::Error) -> Self {
+        let message = format!("{err:#}");
+        for cause in err.chain() {
+            if let Some(command_error) = cause.downcast_ref::<CommandError>() {
+                return command_error.clone();
+            }
+            if let Some(io_error) = cause.downcast_ref::<std::io::Error>() {
+                return classify_io_error(io_error, message);
+            }
+            if let Some(symphonia_error) = cause.downcast_ref::<SymphoniaError>() {
+                return match symphonia_error {
+                    SymphoniaError::IoError(io_error) => classify_io_error(io_error, message),
+                    SymphoniaError::Unsupported(_) => CommandError::UnsupportedFormat(message),
+                    _ => CommandError::DecodeFailed(message),
+                };
+            }
+            if cause.is::<rusqlite::Error>() || cause.is::<rusqlite_migration::Error>() {
+                return CommandError::DbError(message);
+            }
+            if cause.is::<cpal::DevicesError>()
+                || cause.is::<cpal::DeviceNameError>()
+                || cause.is::<cpal::DefaultStreamConfigError>()
+                || cause.is::<cpal::SupportedStreamConfigsError>()
+                || cause.is::<cpal::BuildStreamError>()
+                || cause.is::<cpal::PlayStreamError>()
+                || cause.is::<cpal::PauseStreamError>()
+            {
+                return CommandError::NoDevice(message);
+            }
+        }
+        CommandError::Internal(message)
+    }
+}
+
+fn classify_io_error(err: &std::io::Error, message: String) -> CommandError {
+    match err.kind() {
+        ErrorKind::NotFound => CommandError::FileNotFound(message),
+        _ => CommandError::Internal(message),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_errors_are_classified_through_context() {
+        let missing = std::fs::File::open("/does/not/exist").unwrap_err();
+        let err = anyhow::Error::from(missing).context("Couldn't open track");
+        let CommandError::FileNotFound(message) = CommandError::from(err) else {
+            panic!("Expected a FileNotFound error");
+        };
+        assert!(message.starts_with("Couldn't open track: "));
+
+        let err = anyhow::Error::from(CommandError::NoDevice("No device available.".to_string()));
+        assert!(matches!(CommandError::from(err), CommandError::NoDevice(_)));
+
+        let err = anyhow::anyhow!("Something else");
+        assert!(matches!(CommandError::from(err), CommandError::Internal(_)));
+    }
+
+    #[test]
+    fn test_serializes_with_code() {
+        let err = CommandError::DecodeFailed("Bad packet".to_string());
+        assert_eq!(
+            serde_json::to_string(&err).unwrap(),
+            r#"{"code":"decodeFailed","message":"Bad packet"}"#
+        );
+    }
+}
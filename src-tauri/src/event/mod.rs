@@ -1,4 +1,4 @@
-use tauri::{AppHandle, Emitter};
+use tokio::sync::broadcast;
 
 pub trait EventEmitter {
     fn emit_event<T>(&self, event: &str, data: T) -> anyhow::Result<()>
@@ -6,12 +6,76 @@ pub trait EventEmitter {
         T: serde::Serialize + Clone;
 }
 
-impl EventEmitter for AppHandle {
+#[cfg(feature = "gui")]
+impl EventEmitter for tauri::AppHandle {
     fn emit_event<T>(&self, event: &str, data: T) -> anyhow::Result<()>
     where
         T: serde::Serialize + Clone,
     {
+        use tauri::Emitter;
+
         self.emit(event, data)?;
         Ok(())
     }
 }
+
+/// Emits to both, e.g. to the webview and to the other frontends. One failing doesn't keep
+/// the event from the other.
+impl<A, B> EventEmitter for (A, B)
+where
+    A: EventEmitter,
+    B: EventEmitter,
+{
+    fn emit_event<T>(&self, event: &str, data: T) -> anyhow::Result<()>
+    where
+        T: serde::Serialize + Clone,
+    {
+        let first = self.0.emit_event(event, data.clone());
+        let second = self.1.emit_event(event, data);
+        first.and(second)
+    }
+}
+
+/// How many events a slow subscriber can fall behind before it misses some.
+const EVENT_BUS_CAPACITY: usize = 256;
+
+/// An event as sent to subscribers, with its payload already serialized.
+#[derive(Debug, Clone, serde::Serialize)]
+pub struct Event {
+    pub name: String,
+    pub payload: serde_json::Value,
+}
+
+/// Broadcasts events to any number of subscribers, for frontends other than the webview.
+#[derive(Clone)]
+pub struct EventBus {
+    tx: broadcast::Sender<Event>,
+}
+
+impl Default for EventBus {
+    fn default() -> Self {
+        let (tx, _) = broadcast::channel(EVENT_BUS_CAPACITY);
+        Self { tx }
+    }
+}
+
+impl EventBus {
+    pub fn subscribe(&self) -> broadcast::Receiver<Event> {
+        self.tx.subscribe()
+    }
+}
+
+impl EventEmitter for EventBus {
+    fn emit_event<T>(&self, event: &str, data: T) -> anyhow::Result<()>
+    where
+        T: serde::Serialize + Clone,
+    {
+        let event = Event {
+            name: event.to_string(),
+            payload: serde_json::to_value(data)?,
+        };
+        // Nobody listening isn't an error.
+        let _ = self.tx.send(event);
+        Ok(())
+    }
+}
@@ -0,0 +1,171 @@
+pub mod audio;
+#[cfg(feature = "gui")]
+pub mod commands;
+pub mod control;
+pub mod database;
+pub mod error;
+pub mod event;
+pub mod library;
+pub mod settings;
+
+use audio::{output_from_env, PlayerOptions};
+use database::get_connection;
+use library::ListenRecorder;
+use settings::Settings;
+
+/// Reads what the player boots with from the saved settings.
+pub fn load_player_options(settings: &Settings) -> anyhow::Result<PlayerOptions> {
+    Ok(PlayerOptions {
+        device: settings.get_output_device()?,
+        replay_gain: settings.get_replay_gain()?,
+        equalizer: settings.get_equalizer()?,
+        listen_log: Some(Box::new(ListenRecorder::new(get_connection()?))),
+        session: settings.get_player_session()?,
+        session_store: Some(Box::new(Settings::new(get_connection()?))),
+        output: output_from_env()?,
+        ticks: None,
+    })
+}
+
+/// Runs the desktop app.
+#[cfg(feature = "gui")]
+pub fn run() -> anyhow::Result<()> {
+    use std::sync::Arc;
+
+    use audio::{boot_player, PlayerController};
+    use control::{serve_mpd, serve_remote, serve_subsonic, Services};
+    use event::EventBus;
+    use library::Library;
+    use tauri::{Manager, RunEvent};
+
+    let (tx, rx) = std::sync::mpsc::channel();
+
+    let db_connection = get_connection()?;
+    let library = Library::new(db_connection);
+    let settings = Settings::new(get_connection()?);
+    let player_options = load_player_options(&settings)?;
+    let subsonic = settings.get_subsonic()?;
+    let remote = settings.get_remote()?;
+    let mpd = settings.get_mpd()?;
+    tauri::Builder::default()
+        .setup(move |app| {
+            let app_handle = app.handle();
+            let events = EventBus::default();
+            let emitter = (app_handle.clone(), events.clone());
+            let player_controller = boot_player(tx.clone(), rx, emitter, player_options)?;
+            let services = Services {
+                controller: player_controller.clone(),
+                library: Arc::new(Library::new(get_connection()?)),
+                settings: Arc::new(Settings::new(get_connection()?)),
+                events,
+            };
+            #[cfg(unix)]
+            {
+                let services = services.clone();
+                tauri::async_runtime::spawn(async move {
+                    let path = control::default_socket_path();
+                    if let Err(err) = control::serve(&path, services).await {
+                        eprintln!("Couldn't serve the control socket: {:?}", err);
+                    }
+                });
+            }
+            #[cfg(target_os = "linux")]
+            {
+                let services = services.clone();
+                tauri::async_runtime::spawn(async move {
+                    if let Err(err) = control::serve_mpris(services).await {
+                        eprintln!("Couldn't serve MPRIS: {:?}", err);
+                    }
+                });
+            }
+            if remote.enabled {
+                let services = services.clone();
+                tauri::async_runtime::spawn(async move {
+                    if let Err(err) = serve_remote(remote, services).await {
+                        eprintln!("Error in remote control server: {:?}", err);
+                    }
+                });
+            }
+            if mpd.enabled {
+                let services = services.clone();
+                tauri::async_runtime::spawn(async move {
+                    if let Err(err) = serve_mpd(&mpd.address, services).await {
+                        eprintln!("Error in MPD server: {:?}", err);
+                    }
+                });
+            }
+            if subsonic.enabled {
+                let services = services.clone();
+                let listens = ListenRecorder::new(get_connection()?);
+                tauri::async_runtime::spawn(async move {
+                    if let Err(err) = serve_subsonic(subsonic, services, listens).await {
+                        eprintln!("Error in Subsonic server: {:?}", err);
+                    }
+                });
+            }
+            app.manage(player_controller);
+            app.manage(services);
+            app.state::<Library>().analyze_loudness(app_handle.clone());
+            Ok(())
+        })
+        .manage(library)
+        .manage(settings)
+        .invoke_handler(tauri::generate_handler![
+            commands::play_audio,
+            commands::queue,
+            commands::play_selection,
+            commands::queue_selection,
+            commands::play_next,
+            commands::get_queue,
+            commands::get_player_state,
+            commands::remove_from_queue,
+            commands::move_in_queue,
+            commands::clear_queue,
+            commands::jump_in_queue,
+            commands::set_shuffle,
+            commands::set_repeat,
+            commands::skip,
+            commands::previous,
+            commands::pause,
+            commands::resume,
+            commands::seek,
+            commands::change_volume,
+            commands::list_audio_hosts,
+            commands::list_output_devices,
+            commands::select_output_device,
+            commands::get_replay_gain,
+            commands::set_replay_gain,
+            commands::get_equalizer,
+            commands::set_equalizer,
+            commands::list_equalizer_presets,
+            commands::save_equalizer_preset,
+            commands::delete_equalizer_preset,
+            commands::get_subsonic_settings,
+            commands::set_subsonic_settings,
+            commands::get_remote_settings,
+            commands::set_remote_settings,
+            commands::reset_remote_token,
+            commands::get_mpd_settings,
+            commands::set_mpd_settings,
+            commands::analyze_loudness,
+            commands::cancel_loudness_analysis,
+            commands::get_recently_played,
+            commands::get_most_played,
+            commands::get_scrobbles,
+            commands::export_scrobbles,
+        ])
+        .build(tauri::generate_context!())
+        .expect("error while running tauri application")
+        .run(|app, event| {
+            if let RunEvent::Exit = event {
+                let Some(controller) = app.try_state::<PlayerController>() else {
+                    return;
+                };
+                if let Err(err) = controller.save_session() {
+                    eprintln!("Couldn't save player session: {:?}", err);
+                }
+            }
+        });
+
+    Ok(())
+}
@@ -0,0 +1,344 @@
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use ebur128::{EbuR128, Mode};
+use rusqlite::{params, Connection};
+
+use crate::audio::{AudioFile, AudioSource};
+use crate::database::get_connection;
+use crate::event::EventEmitter;
+
+/// EBU R128 loudness of a track or album. Loudness is in LUFS, range in LU and the true peak
+/// is linear (1.0 = full scale).
+#[derive(Debug, Clone, Copy, Default)]
+struct Loudness {
+    integrated: Option<f64>,
+    range: Option<f64>,
+    true_peak: Option<f64>,
+}
+
+#[derive(Debug, serde::Serialize, Clone)]
+#[serde(rename_all = "camelCase")]
+struct AnalysisProgress {
+    analyzed_tracks: usize,
+    /// Tracks that couldn't be decoded, which are marked as failed and not tried again.
+    failed_tracks: usize,
+    total_tracks: usize,
+    path: String,
+}
+
+#[derive(Debug, serde::Serialize, Clone)]
+#[serde(rename_all = "camelCase")]
+struct AnalysisFinished {
+    analyzed_tracks: usize,
+    failed_tracks: usize,
+    cancelled: bool,
+}
+
+/// Background job measuring the loudness of tracks that have no ReplayGain tags.
+///
+/// Results are committed one album at a time, so a cancelled or interrupted job picks up
+/// from the first album that wasn't fully analyzed.
+#[derive(Default)]
+pub struct LoudnessAnalysis {
+    running: Arc<AtomicBool>,
+    cancelled: Arc<AtomicBool>,
+}
+
+impl LoudnessAnalysis {
+    /// Starts the job unless it's already running. Returns whether a new job was started.
+    pub fn start<T>(&self, emitter: T) -> bool
+    where
+        T: EventEmitter + Send + 'static,
+    {
+        if self.running.swap(true, Ordering::AcqRel) {
+            return false;
+        }
+        self.cancelled.store(false, Ordering::Release);
+
+        let running = self.running.clone();
+        let cancelled = self.cancelled.clone();
+        std::thread::spawn(move || {
+            let result = get_connection()
+                .and_then(|mut connection| run_analysis(&mut connection, &emitter, &cancelled));
+            if let Err(err) = result {
+                eprintln!("Error in loudness analysis: {:?}", err);
+            }
+            running.store(false, Ordering::Release);
+        });
+        true
+    }
+
+    pub fn cancel(&self) {
+        self.cancelled.store(true, Ordering::Release);
+    }
+}
+
+fn run_analysis<T>(
+    connection: &mut Connection,
+    emitter: &T,
+    cancelled: &AtomicBool,
+) -> anyhow::Result<()>
+where
+    T: EventEmitter,
+{
+    let album_ids = get_pending_album_ids(connection)?;
+    let mut albums = Vec::new();
+    for album_id in album_ids {
+        albums.push((album_id, get_album_tracks(connection, album_id)?));
+    }
+
+    let total_tracks = albums.iter().map(|(_, tracks)| tracks.len()).sum();
+    let mut analyzed_tracks = 0;
+    let mut failed_tracks = 0;
+    for (album_id, tracks) in albums {
+        let mut meters = Vec::new();
+        let mut results = Vec::new();
+        let mut failures = Vec::new();
+        for (track_id, path) in tracks {
+            if cancelled.load(Ordering::Acquire) {
+                emitter.emit_event(
+                    "library:loudness-finished",
+                    AnalysisFinished {
+                        analyzed_tracks,
+                        failed_tracks,
+                        cancelled: true,
+                    },
+                )?;
+                return Ok(());
+            }
+
+            match analyze_track(&path, cancelled) {
+                Ok((loudness, meter)) => {
+                    meters.push(meter);
+                    results.push((track_id, loudness));
+                    analyzed_tracks += 1;
+                }
+                Err(err) => {
+                    eprintln!("Couldn't analyze {path}: {err}");
+                    failures.push(track_id);
+                    failed_tracks += 1;
+                }
+            }
+            emitter.emit_event(
+                "library:loudness-progress",
+                AnalysisProgress {
+                    analyzed_tracks,
+                    failed_tracks,
+                    total_tracks,
+                    path,
+                },
+            )?;
+        }
+        if cancelled.load(Ordering::Acquire) {
+            continue;
+        }
+        let album_loudness = measure_album(&meters, &results);
+        save_album(connection, album_id, &results, &failures, album_loudness)?;
+    }
+
+    emitter.emit_event(
+        "library:loudness-finished",
+        AnalysisFinished {
+            analyzed_tracks,
+            failed_tracks,
+            cancelled: cancelled.load(Ordering::Acquire),
+        },
+    )?;
+    Ok(())
+}
+
+fn analyze_track(path: &str, cancelled: &AtomicBool) -> anyhow::Result<(Loudness, EbuR128)> {
+    let mut handle = AudioFile::new(path.to_string()).get_handle()?;
+    let channel_count = handle.channel_count() as u32;
+    let mut meter = EbuR128::new(
+        channel_count,
+        handle.sample_rate(),
+        Mode::I | Mode::LRA | Mode::TRUE_PEAK,
+    )?;
+
+    let mut buf = Vec::new();
+    while handle.decode_next(&mut buf)? {
+        if cancelled.load(Ordering::Acquire) {
+            anyhow::bail!("Analysis cancelled");
+        }
+        meter.add_frames_f64(&buf)?;
+        buf.clear();
+    }
+
+    let mut true_peak: f64 = 0.0;
+    for channel in 0..channel_count {
+        true_peak = true_peak.max(meter.true_peak(channel)?);
+    }
+    let loudness = Loudness {
+        integrated: finite(meter.loudness_global()?),
+        range: finite(meter.loudness_range()?),
+        true_peak: Some(true_peak),
+    };
+    Ok((loudness, meter))
+}
+
+fn measure_album(meters: &[EbuR128], results: &[(i64, Loudness)]) -> Loudness {
+    if meters.is_empty() {
+        return Loudness::default();
+    }
+    let true_peak = results
+        .iter()
+        .filter_map(|(_, loudness)| loudness.true_peak)
+        .reduce(f64::max);
+    Loudness {
+        integrated: EbuR128::loudness_global_multiple(meters.iter())
+            .ok()
+            .and_then(finite),
+        range: EbuR128::loudness_range_multiple(meters.iter())
+            .ok()
+            .and_then(finite),
+        true_peak,
+    }
+}
+
+/// Silence measures as -inf LUFS, which is stored as unknown.
+fn finite(value: f64) -> Option<f64> {
+    match value.is_finite() {
+        true => Some(value),
+        false => None,
+    }
+}
+
+/// Albums with at least one track lacking both ReplayGain tags and a loudness measurement,
+/// leaving out tracks that failed to decode before.
+fn get_pending_album_ids(connection: &Connection) -> anyhow::Result<Vec<i64>> {
+    let mut statement = connection.prepare(
+        "SELECT DISTINCT album_id FROM Tracks
+         WHERE replaygain_track_gain IS NULL AND loudness_analyzed_at IS NULL
+         ORDER BY album_id",
+    )?;
+    let album_ids = statement
+        .query_map([], |row| row.get(0))?
+        .collect::<Result<Vec<i64>, _>>()?;
+    Ok(album_ids)
+}
+
+fn get_album_tracks(connection: &Connection, album_id: i64) -> anyhow::Result<Vec<(i64, String)>> {
+    let mut statement = connection
+        .prepare("SELECT id, path FROM Tracks WHERE album_id = ?1 ORDER BY album_order")?;
+    let tracks = statement
+        .query_map(params![album_id], |row| Ok((row.get(0)?, row.get(1)?)))?
+        .collect::<Result<Vec<(i64, String)>, _>>()?;
+    Ok(tracks)
+}
+
+/// Saves the loudness of an album and its tracks, and marks the tracks that failed to decode
+/// so they don't keep the album pending.
+fn save_album(
+    connection: &mut Connection,
+    album_id: i64,
+    tracks: &[(i64, Loudness)],
+    failures: &[i64],
+    album_loudness: Loudness,
+) -> anyhow::Result<()> {
+    let analyzed_at = SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs() as i64;
+    let transaction = connection.transaction()?;
+    for (track_id, loudness) in tracks {
+        transaction.execute(
+            "UPDATE Tracks SET loudness_integrated = ?2, loudness_range = ?3,
+                loudness_true_peak = ?4, loudness_analyzed_at = ?5
+             WHERE id = ?1",
+            params![
+                track_id,
+                loudness.integrated,
+                loudness.range,
+                loudness.true_peak,
+                analyzed_at
+            ],
+        )?;
+    }
+    for track_id in failures {
+        transaction.execute(
+            "UPDATE Tracks SET loudness_analyzed_at = ?2, loudness_failed = 1 WHERE id = ?1",
+            params![track_id, analyzed_at],
+        )?;
+    }
+    transaction.execute(
+        "UPDATE Albums SET loudness_integrated = ?2, loudness_range = ?3, loudness_true_peak = ?4
+         WHERE id = ?1",
+        params![
+            album_id,
+            album_loudness.integrated,
+            album_loudness.range,
+            album_loudness.true_peak
+        ],
+    )?;
+    transaction.commit()?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::f64::consts::PI;
+
+    use super::*;
+    use crate::audio::tests::{write_test_wav, TestDir};
+    use crate::database::init_test_db;
+    use crate::event::EventBus;
+
+    /// Writes a second of a full scale 1 kHz sine.
+    fn write_sine_wav(dir: &TestDir, name: &str) -> String {
+        let sample_rate = 48000;
+        write_test_wav(dir, name, sample_rate, sample_rate, |frame| {
+            let phase = 2.0 * PI * 1000.0 * frame as f64 / sample_rate as f64;
+            (phase.sin() * i16::MAX as f64) as i16
+        })
+    }
+
+    #[test]
+    fn test_marks_undecodable_tracks_as_failed() {
+        let mut connection = init_test_db().unwrap();
+        let dir = TestDir::new("loudness-failed");
+        let sine = write_sine_wav(&dir, "sine.wav");
+        connection
+            .execute_batch(
+                "INSERT INTO Artists (id, name) VALUES (1, 'Artist');
+                 INSERT INTO Albums (id, name, artist_id) VALUES (1, 'Album', 1);",
+            )
+            .unwrap();
+        connection
+            .execute(
+                "INSERT INTO Tracks (id, name, path, album_order, album_id)
+                 VALUES (1, 'Sine', ?1, 1, 1), (2, 'Missing', '/missing.wav', 2, 1)",
+                params![sine],
+            )
+            .unwrap();
+        let events = EventBus::default();
+        let mut received = events.subscribe();
+
+        run_analysis(&mut connection, &events, &AtomicBool::new(false)).unwrap();
+
+        let loudness = |track_id: i64| -> (Option<f64>, bool) {
+            connection
+                .query_row(
+                    "SELECT loudness_integrated, loudness_failed FROM Tracks WHERE id = ?1",
+                    params![track_id],
+                    |row| Ok((row.get(0)?, row.get(1)?)),
+                )
+                .unwrap()
+        };
+        // A full scale sine measures about -3 LUFS.
+        let (integrated, failed) = loudness(1);
+        assert!((integrated.unwrap() + 3.0).abs() < 0.5);
+        assert!(!failed);
+        assert_eq!(loudness(2), (None, true));
+        assert!(get_pending_album_ids(&connection).unwrap().is_empty());
+
+        let mut finished = None;
+        while let Ok(event) = received.try_recv() {
+            if event.name == "library:loudness-finished" {
+                finished = Some(event.payload);
+            }
+        }
+        let finished = finished.unwrap();
+        assert_eq!(finished["analyzedTracks"], 1);
+        assert_eq!(finished["failedTracks"], 1);
+    }
+}
@@ -0,0 +1,107 @@
+use std::path::{Path, PathBuf};
+
+use crate::audio::{AudioFile, Picture};
+
+/// Image files next to an album's tracks taken as its cover, by stem, most likely first.
+const COVER_NAMES: &[&str] = &["cover", "folder", "front", "album"];
+const COVER_EXTENSIONS: &[&str] = &["jpg", "jpeg", "png", "webp"];
+
+/// Finds an album's cover: the image saved with the album, an image next to its first track,
+/// or the picture embedded in that track.
+///
+/// Embedded pictures are cached on disk by album, so the track isn't probed every time. The
+/// cache isn't invalidated when the track changes.
+pub(super) fn find_cover(
+    album_id: i64,
+    cover_path: Option<&str>,
+    first_track: Option<&AudioFile>,
+) -> anyhow::Result<Option<Picture>> {
+    if let Some(path) = find_cover_image(cover_path, first_track) {
+        return Ok(read_image(&path));
+    }
+    let Some(first_track) = first_track else {
+        return Ok(None);
+    };
+    let cached_path = cache_dir().map(|dir| dir.join(album_id.to_string()));
+    if let Some(picture) = cached_path.as_deref().and_then(read_image) {
+        return Ok(Some(picture));
+    }
+    let picture = first_track.get_embedded_picture()?;
+    if let (Some(picture), Some(cached_path)) = (&picture, cached_path) {
+        if let Err(err) = cache_picture(&cached_path, picture) {
+            eprintln!("Couldn't cache the cover of album {album_id}: {:?}", err);
+        }
+    }
+    Ok(picture)
+}
+
+/// Like [`find_cover`], but for an image file, for clients that want a path or a URL.
+/// Embedded pictures are written to the cache for this.
+pub(super) fn find_cover_file(
+    album_id: i64,
+    cover_path: Option<&str>,
+    first_track: Option<&AudioFile>,
+) -> anyhow::Result<Option<PathBuf>> {
+    if let Some(path) = find_cover_image(cover_path, first_track) {
+        return Ok(Some(path));
+    }
+    let (Some(first_track), Some(dir)) = (first_track, cache_dir()) else {
+        return Ok(None);
+    };
+    let cached_path = dir.join(album_id.to_string());
+    if !cached_path.exists() {
+        let Some(picture) = first_track.get_embedded_picture()? else {
+            return Ok(None);
+        };
+        cache_picture(&cached_path, &picture)?;
+    }
+    Ok(Some(cached_path))
+}
+
+/// The image saved with the album, or else one next to its first track.
+fn find_cover_image(cover_path: Option<&str>, first_track: Option<&AudioFile>) -> Option<PathBuf> {
+    if let Some(path) = cover_path.map(PathBuf::from).filter(|path| is_image(path)) {
+        return Some(path);
+    }
+    let folder = Path::new(first_track?.get_path()).parent()?;
+    COVER_NAMES
+        .iter()
+        .flat_map(|name| {
+            COVER_EXTENSIONS
+                .iter()
+                .map(move |extension| folder.join(format!("{name}.{extension}")))
+        })
+        .find(|path| is_image(path))
+}
+
+fn cache_dir() -> Option<PathBuf> {
+    dirs::cache_dir().map(|dir| dir.join("amptree").join("covers"))
+}
+
+fn cache_picture(path: &Path, picture: &Picture) -> anyhow::Result<()> {
+    if let Some(dir) = path.parent() {
+        std::fs::create_dir_all(dir)?;
+    }
+    std::fs::write(path, &picture.data)?;
+    Ok(())
+}
+
+fn is_image(path: &Path) -> bool {
+    match infer::get_from_path(path) {
+        Ok(Some(kind)) => kind.mime_type().starts_with("image/"),
+        _ => false,
+    }
+}
+
+/// Reads an image file, telling its type from its contents.
+fn read_image(path: &Path) -> Option<Picture> {
+    let data = std::fs::read(path).ok()?;
+    let media_type = infer::get(&data)?.mime_type();
+    if !media_type.starts_with("image/") {
+        return None;
+    }
+    Some(Picture {
+        media_type: media_type.to_string(),
+        data,
+    })
+}
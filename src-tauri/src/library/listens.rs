@@ -0,0 +1,241 @@
+use std::sync::Mutex;
+
+use rusqlite::{params, Connection};
+
+use crate::audio::{Listen, ListenLog, ListenOutcome};
+
+use super::scrobbles::queue_scrobble;
+
+/// Persists listens, keeping the play counts of library tracks and the scrobble queue up to
+/// date.
+pub struct ListenRecorder {
+    connection: Mutex<Connection>,
+}
+
+impl ListenRecorder {
+    pub fn new(connection: Connection) -> Self {
+        Self {
+            connection: Mutex::new(connection),
+        }
+    }
+}
+
+impl ListenLog for ListenRecorder {
+    fn record_listen(&self, listen: &Listen) -> anyhow::Result<()> {
+        let Ok(mut connection) = self.connection.lock() else {
+            anyhow::bail!("Couldn't acquire listens lock")
+        };
+        let completed = listen.outcome == ListenOutcome::Completed;
+        let skipped = listen.outcome == ListenOutcome::Skipped;
+        let transaction = connection.transaction()?;
+        transaction.execute(
+            "INSERT INTO Listens
+                 (track_id, path, started_at, played_secs, duration_secs, completed, skipped)
+             VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7)",
+            params![
+                listen.file.get_track_id(),
+                listen.file.get_path(),
+                listen.started_at,
+                listen.played_secs,
+                listen.duration_secs,
+                completed,
+                skipped,
+            ],
+        )?;
+        if listen.is_scrobble() {
+            queue_scrobble(&transaction, transaction.last_insert_rowid(), listen)?;
+        }
+        if let Some(track_id) = listen.file.get_track_id() {
+            transaction.execute(
+                "UPDATE Tracks SET
+                     play_count = play_count + ?2,
+                     skip_count = skip_count + ?3,
+                     last_played = MAX(COALESCE(last_played, 0), ?4)
+                 WHERE id = ?1",
+                params![track_id, completed, skipped, listen.started_at],
+            )?;
+        }
+        transaction.commit()?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::audio::{AudioFile, AudioMetadata};
+    use crate::database::init_test_db;
+
+    fn listen(file: &AudioFile, started_at: i64, outcome: ListenOutcome) -> Listen {
+        Listen {
+            file: file.clone(),
+            started_at,
+            played_secs: Some(12.5),
+            duration_secs: Some(20.0),
+            metadata: None,
+            outcome,
+        }
+    }
+
+    #[test]
+    fn test_record_listen() {
+        let recorder = ListenRecorder::new(init_test_db().unwrap());
+        let outside = AudioFile::new("/music/outside.flac".to_string());
+        recorder
+            .record_listen(&listen(&outside, 10, ListenOutcome::Skipped))
+            .unwrap();
+
+        let connection = recorder.connection.lock().unwrap();
+        let (track_id, path, played_secs): (Option<i64>, String, f64) = connection
+            .query_row(
+                "SELECT track_id, path, played_secs FROM Listens",
+                [],
+                |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?)),
+            )
+            .unwrap();
+        assert_eq!(track_id, None);
+        assert_eq!(path, "/music/outside.flac");
+        assert_eq!(played_secs, 12.5);
+    }
+
+    #[test]
+    fn test_counts_plays_and_skips() {
+        let recorder = ListenRecorder::new(init_test_db().unwrap());
+        {
+            let connection = recorder.connection.lock().unwrap();
+            connection
+                .execute_batch(
+                    "INSERT INTO Artists (id, name) VALUES (1, 'Artist');
+                     INSERT INTO Albums (id, name, artist_id) VALUES (1, 'Album', 1);
+                     INSERT INTO Tracks (id, name, path, album_order, album_id)
+                     VALUES (1, 'Track', '/music/track.flac', 1, 1);",
+                )
+                .unwrap();
+        }
+        let track = AudioFile::new("/music/track.flac".to_string()).with_track_id(1);
+        recorder
+            .record_listen(&listen(&track, 300, ListenOutcome::Completed))
+            .unwrap();
+        recorder
+            .record_listen(&listen(&track, 100, ListenOutcome::Completed))
+            .unwrap();
+        recorder
+            .record_listen(&listen(&track, 200, ListenOutcome::Skipped))
+            .unwrap();
+
+        let connection = recorder.connection.lock().unwrap();
+        let counts: (usize, usize, i64) = connection
+            .query_row(
+                "SELECT play_count, skip_count, last_played FROM Tracks WHERE id = 1",
+                [],
+                |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?)),
+            )
+            .unwrap();
+        assert_eq!(counts, (2, 1, 300));
+    }
+
+    #[test]
+    fn test_queues_scrobbles() {
+        let recorder = ListenRecorder::new(init_test_db().unwrap());
+        let file = AudioFile::new("/music/outside.flac".to_string());
+        let mut metadata = AudioMetadata::new(file.get_path().to_string());
+        metadata.artist = Some("Low".to_string());
+        metadata.title = Some("Sunflower".to_string());
+        let listen = |played_secs, duration_secs, metadata: &AudioMetadata| Listen {
+            file: file.clone(),
+            started_at: 10,
+            played_secs: Some(played_secs),
+            duration_secs,
+            metadata: Some(metadata.clone()),
+            outcome: ListenOutcome::Skipped,
+        };
+        // Half of the track, 4 minutes of a long one, and 4 minutes of an unknown length count.
+        for (played_secs, duration_secs) in
+            [(60.0, Some(120.0)), (240.0, Some(600.0)), (240.0, None)]
+        {
+            let listen = listen(played_secs, duration_secs, &metadata);
+            recorder.record_listen(&listen).unwrap();
+        }
+        // Too little of the track, a track too short, or less than 4 minutes of an unknown
+        // length don't, nor a file without tags.
+        for (played_secs, duration_secs) in [(59.0, Some(120.0)), (30.0, Some(30.0)), (200.0, None)]
+        {
+            let listen = listen(played_secs, duration_secs, &metadata);
+            recorder.record_listen(&listen).unwrap();
+        }
+        let untagged = AudioMetadata::new(file.get_path().to_string());
+        recorder
+            .record_listen(&listen(120.0, Some(120.0), &untagged))
+            .unwrap();
+
+        // Library tracks fill in missing tags, but not with the names given to untagged files.
+        recorder
+            .connection
+            .lock()
+            .unwrap()
+            .execute_batch(
+                "INSERT INTO Artists (id, name) VALUES (1, 'Low'), (2, 'Unknown Artist');
+                 INSERT INTO Albums (id, name, artist_id)
+                 VALUES (1, 'The Great Destroyer', 1), (2, 'Unknown Album', 2);
+                 INSERT INTO Tracks (id, name, path, album_order, album_id)
+                 VALUES (1, 'Monkey', '/music/monkey.flac', 1, 1),
+                        (2, 'untagged', '/music/untagged.flac', 0, 2);",
+            )
+            .unwrap();
+        let library_listen = |track_id, path: &str, metadata: Option<&AudioMetadata>| Listen {
+            file: AudioFile::new(path.to_string()).with_track_id(track_id),
+            started_at: 20,
+            played_secs: Some(120.0),
+            duration_secs: Some(120.0),
+            metadata: metadata.cloned(),
+            outcome: ListenOutcome::Completed,
+        };
+        for listen in [
+            library_listen(1, "/music/monkey.flac", None),
+            library_listen(2, "/music/untagged.flac", Some(&untagged)),
+            library_listen(2, "/music/untagged.flac", Some(&metadata)),
+        ] {
+            recorder.record_listen(&listen).unwrap();
+        }
+
+        let connection = recorder.connection.lock().unwrap();
+        type Row = (String, String, Option<String>, Option<usize>, Option<f64>);
+        let scrobbles = connection
+            .prepare(
+                "SELECT artist, title, album, track_number, duration_secs FROM Scrobbles
+                 ORDER BY id",
+            )
+            .unwrap()
+            .query_map([], |row| {
+                Ok((
+                    row.get(0)?,
+                    row.get(1)?,
+                    row.get(2)?,
+                    row.get(3)?,
+                    row.get(4)?,
+                ))
+            })
+            .unwrap()
+            .collect::<Result<Vec<Row>, _>>()
+            .unwrap();
+        let sunflower =
+            |duration_secs| ("Low".into(), "Sunflower".into(), None, None, duration_secs);
+        let monkey = (
+            "Low".into(),
+            "Monkey".into(),
+            Some("The Great Destroyer".into()),
+            Some(1),
+            Some(120.0),
+        );
+        assert_eq!(
+            scrobbles,
+            [
+                sunflower(Some(120.0)),
+                sunflower(Some(600.0)),
+                sunflower(None),
+                monkey,
+                sunflower(Some(120.0)),
+            ]
+        );
+    }
+}
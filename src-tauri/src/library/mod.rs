@@ -1,30 +1,188 @@
-use std::{collections::HashMap, hash::Hash, path::Path};
+use std::{
+    collections::HashMap,
+    hash::Hash,
+    path::Path,
+    time::{SystemTime, UNIX_EPOCH},
+};
 
 use rusqlite::Connection;
 
-use crate::audio::AudioFile;
+use crate::audio::{AudioFile, Picture, ReplayGain};
+use crate::error::CommandError;
+use crate::event::EventEmitter;
 
+mod analysis;
+mod covers;
+mod listens;
 mod repository;
 pub mod scanner;
+mod scrobbles;
+
+pub use listens::ListenRecorder;
+pub use scrobbles::{Scrobble, ScrobbleFormat};
 
 pub(super) type ScanResult = anyhow::Result<HashMap<Artist, HashMap<Album, Vec<Track>>>>;
 
 pub struct Library {
     repository: repository::LibraryRepository,
+    loudness_analysis: analysis::LoudnessAnalysis,
 }
 
 impl Library {
     pub fn new(connection: Connection) -> Library {
         Library {
             repository: repository::LibraryRepository::new(connection),
+            loudness_analysis: analysis::LoudnessAnalysis::default(),
         }
     }
 
     pub async fn scan(&self, path: &str) -> anyhow::Result<()> {
         let scanned = scanner::scan_directory(path).await?;
-        println!("{scanned:?}");
+        self.repository.save_scan(&scanned).await?;
         Ok(())
     }
+
+    pub async fn get_audio_file(&self, path: &str) -> anyhow::Result<AudioFile> {
+        self.repository.get_audio_file(path).await
+    }
+
+    /// Playable files for the selected tracks, in the order they should be played.
+    pub async fn get_audio_files(
+        &self,
+        selection: &TrackSelection,
+    ) -> anyhow::Result<Vec<AudioFile>> {
+        self.repository.get_audio_files(selection).await
+    }
+
+    /// Like `get_audio_files`, but selecting nothing playable is an error.
+    pub async fn get_selection(
+        &self,
+        selection: &TrackSelection,
+    ) -> anyhow::Result<Vec<AudioFile>> {
+        let audio_files = self.get_audio_files(selection).await?;
+        if audio_files.is_empty() {
+            let message = format!("No tracks found for {selection:?}");
+            return Err(CommandError::InvalidArgument(message).into());
+        }
+        Ok(audio_files)
+    }
+
+    /// Like `get_audio_files`, with the tags of each track instead.
+    pub async fn get_tracks(
+        &self,
+        selection: &TrackSelection,
+    ) -> anyhow::Result<Vec<LibraryTrack>> {
+        self.repository.get_tracks(selection).await
+    }
+
+    /// Artists whose name contains `name`, or all of them, by name.
+    pub async fn get_artists(&self, name: Option<&str>) -> anyhow::Result<Vec<ArtistSummary>> {
+        self.repository.get_artists(name).await
+    }
+
+    pub async fn get_albums(&self, filter: &AlbumFilter) -> anyhow::Result<Vec<AlbumSummary>> {
+        self.repository.get_albums(filter).await
+    }
+
+    pub async fn get_playlists(&self) -> anyhow::Result<Vec<PlaylistSummary>> {
+        self.repository.get_playlists().await
+    }
+
+    /// The cover of an album, if it has one.
+    pub async fn get_cover(&self, album_id: i64) -> anyhow::Result<Option<Picture>> {
+        let albums = self.get_albums(&AlbumFilter::Id(album_id)).await?;
+        let Some(album) = albums.into_iter().next() else {
+            return Ok(None);
+        };
+        let selection = TrackSelection::Album { id: album_id };
+        let first_track = self.get_audio_files(&selection).await?.into_iter().next();
+        let cover = tokio::task::spawn_blocking(move || {
+            covers::find_cover(album.id, album.cover_path.as_deref(), first_track.as_ref())
+        });
+        cover.await?
+    }
+
+    /// The image file of an album's cover, if it has one. See [`Library::get_cover`].
+    pub async fn get_cover_file(
+        &self,
+        album_id: i64,
+    ) -> anyhow::Result<Option<std::path::PathBuf>> {
+        let albums = self.get_albums(&AlbumFilter::Id(album_id)).await?;
+        let Some(album) = albums.into_iter().next() else {
+            return Ok(None);
+        };
+        let selection = TrackSelection::Album { id: album_id };
+        let first_track = self.get_audio_files(&selection).await?.into_iter().next();
+        let cover = tokio::task::spawn_blocking(move || {
+            covers::find_cover_file(album.id, album.cover_path.as_deref(), first_track.as_ref())
+        });
+        cover.await?
+    }
+
+    /// Library tracks matching every filter, ordered by path.
+    pub async fn find_tracks(&self, filters: &[TrackFilter]) -> anyhow::Result<Vec<LibraryTrack>> {
+        self.repository.find_tracks(filters).await
+    }
+
+    /// The latest listens, most recent first.
+    pub async fn get_recently_played(&self, limit: usize) -> anyhow::Result<Vec<ListenEntry>> {
+        self.repository.get_recently_played(limit).await
+    }
+
+    /// The library tracks listened to most between `since` and `until`, in seconds since the
+    /// epoch. Skips don't count.
+    pub async fn get_most_played(
+        &self,
+        since: i64,
+        until: Option<i64>,
+        limit: usize,
+    ) -> anyhow::Result<Vec<TrackPlays>> {
+        self.repository.get_most_played(since, until, limit).await
+    }
+
+    /// The scrobble queue, oldest first, leaving out what was exported unless asked for.
+    pub async fn get_scrobbles(&self, include_exported: bool) -> anyhow::Result<Vec<Scrobble>> {
+        self.repository.get_scrobbles(include_exported).await
+    }
+
+    /// Writes the scrobbles not exported yet to `path`, then marks them exported. Returns how
+    /// many were written.
+    pub async fn export_scrobbles(
+        &self,
+        format: ScrobbleFormat,
+        path: &Path,
+    ) -> anyhow::Result<usize> {
+        let scrobbles = self.get_scrobbles(false).await?;
+        tokio::fs::write(path, format.write(&scrobbles)?).await?;
+        let ids: Vec<i64> = scrobbles.iter().map(|scrobble| scrobble.id).collect();
+        let exported_at = SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs() as i64;
+        self.repository
+            .mark_scrobbles_exported(&ids, exported_at)
+            .await?;
+        Ok(scrobbles.len())
+    }
+
+    /// The distinct values of `tag` among the tracks matching every filter.
+    pub async fn list_tag(
+        &self,
+        tag: TrackTag,
+        filters: &[TrackFilter],
+    ) -> anyhow::Result<Vec<String>> {
+        self.repository.list_tag(tag, filters).await
+    }
+
+    /// Starts measuring the loudness of untagged tracks in the background, resuming any
+    /// previous run. Returns `false` when the analysis is already running.
+    pub fn analyze_loudness<T>(&self, emitter: T) -> bool
+    where
+        T: EventEmitter + Send + 'static,
+    {
+        self.loudness_analysis.start(emitter)
+    }
+
+    pub fn cancel_loudness_analysis(&self) {
+        self.loudness_analysis.cancel();
+    }
 }
 
 #[derive(Debug, Clone, PartialEq, Eq, Hash)]
@@ -55,20 +213,153 @@ pub struct Track {
     path: String,
     name: Option<String>,
     album_order: Option<usize>,
+    disc_number: Option<usize>,
+    replay_gain: ReplayGain,
 }
 
 impl Track {
-    pub fn new(path: String, name: Option<String>, album_order: Option<usize>) -> Self {
+    pub fn new(
+        path: String,
+        name: Option<String>,
+        album_order: Option<usize>,
+        disc_number: Option<usize>,
+        replay_gain: ReplayGain,
+    ) -> Self {
         Self {
             path,
             name,
             album_order,
+            disc_number,
+            replay_gain,
         }
     }
 }
 
+/// A set of library tracks to play or queue at once.
+#[derive(Debug, serde::Deserialize, Clone)]
+#[serde(tag = "kind", rename_all = "camelCase")]
+pub enum TrackSelection {
+    /// An album, in disc and track order.
+    Album {
+        id: i64,
+    },
+    /// Every album of an artist, one after the other.
+    Artist {
+        id: i64,
+    },
+    Playlist {
+        id: i64,
+    },
+    /// Tracks picked by the frontend, e.g. search results, in the given order.
+    Tracks {
+        ids: Vec<i64>,
+    },
+}
+
+/// A library track with the tags clients browse and search by.
+#[derive(Debug, Clone, PartialEq, serde::Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct LibraryTrack {
+    pub id: i64,
+    pub path: String,
+    pub title: String,
+    pub artist_id: i64,
+    pub artist: String,
+    pub album_id: i64,
+    pub album: String,
+    pub album_order: usize,
+    pub disc_number: Option<usize>,
+    /// Listens that reached the end of the track.
+    pub play_count: usize,
+    pub skip_count: usize,
+    /// When the track was last listened to, in seconds since the epoch.
+    pub last_played: Option<i64>,
+}
+
+/// A listen from the history, with the library track it was of, if any.
+#[derive(Debug, Clone, PartialEq, serde::Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ListenEntry {
+    pub path: String,
+    pub track: Option<LibraryTrack>,
+    pub started_at: i64,
+    pub played_secs: Option<f64>,
+    pub completed: bool,
+    pub skipped: bool,
+}
+
+/// How often a track was listened to in a period.
+#[derive(Debug, Clone, PartialEq, serde::Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct TrackPlays {
+    pub track: LibraryTrack,
+    pub plays: usize,
+    pub played_secs: f64,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, serde::Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ArtistSummary {
+    pub id: i64,
+    pub name: String,
+    pub album_count: usize,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, serde::Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct AlbumSummary {
+    pub id: i64,
+    pub name: String,
+    pub artist_id: i64,
+    pub artist: String,
+    pub cover_path: Option<String>,
+    pub track_count: usize,
+}
+
+#[derive(Debug, Clone)]
+pub enum AlbumFilter {
+    Id(i64),
+    Artist(i64),
+    /// Case insensitive.
+    NameContains(String),
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, serde::Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct PlaylistSummary {
+    pub id: i64,
+    pub name: String,
+    pub track_count: usize,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TrackTag {
+    Artist,
+    Album,
+    Title,
+    Path,
+    /// Any of the above.
+    Any,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TagMatch {
+    Equals,
+    /// Case insensitive.
+    Contains,
+    StartsWith,
+}
+
+/// Keeps the tracks whose `tag` matches `value`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TrackFilter {
+    pub tag: TrackTag,
+    pub matching: TagMatch,
+    pub value: String,
+}
+
 impl Into<AudioFile> for &Track {
     fn into(self) -> AudioFile {
-        AudioFile::new(self.path.clone())
+        AudioFile::new(self.path.clone()).with_replay_gain(self.replay_gain)
     }
 }
@@ -1,6 +1,18 @@
-use rusqlite::Connection;
+use std::collections::HashMap;
+
+use rusqlite::types::Value;
+use rusqlite::{params, Connection, OptionalExtension, Row, Transaction};
 use tokio::sync::Mutex as TokioMutex;
 
+use crate::audio::{AudioFile, ReplayGain};
+
+use crate::error::CommandError;
+
+use super::{
+    Album, AlbumFilter, AlbumSummary, Artist, ArtistSummary, LibraryTrack, ListenEntry,
+    PlaylistSummary, Scrobble, TagMatch, Track, TrackFilter, TrackPlays, TrackSelection, TrackTag,
+};
+
 pub(super) struct LibraryRepository {
     connection: TokioMutex<Connection>,
 }
@@ -10,4 +22,711 @@ impl LibraryRepository {
         let connection = TokioMutex::new(connection);
         Self { connection }
     }
+
+    /// Inserts the scanned artists, albums and tracks, updating tracks already known by path.
+    pub(super) async fn save_scan(
+        &self,
+        scanned: &HashMap<Artist, HashMap<Album, Vec<Track>>>,
+    ) -> anyhow::Result<()> {
+        let mut connection = self.connection.lock().await;
+        let transaction = connection.transaction()?;
+        for (artist, albums) in scanned {
+            let artist_id = get_or_insert_artist(&transaction, artist)?;
+            for (album, tracks) in albums {
+                let album_id = get_or_insert_album(&transaction, artist_id, album)?;
+                for track in tracks {
+                    upsert_track(&transaction, album_id, track)?;
+                }
+            }
+        }
+        transaction.commit()?;
+        Ok(())
+    }
+
+    /// Builds the playable file for `path`, with the album and gain information the library
+    /// has about it.
+    pub(super) async fn get_audio_file(&self, path: &str) -> anyhow::Result<AudioFile> {
+        let connection = self.connection.lock().await;
+        let audio_file = connection
+            .query_row(
+                &format!("SELECT {AUDIO_FILE_COLUMNS} {AUDIO_FILE_JOIN} WHERE t.path = ?1"),
+                params![path],
+                read_audio_file,
+            )
+            .optional()?;
+        Ok(audio_file.unwrap_or_else(|| AudioFile::new(path.to_string())))
+    }
+
+    pub(super) async fn get_audio_files(
+        &self,
+        selection: &TrackSelection,
+    ) -> anyhow::Result<Vec<AudioFile>> {
+        let connection = self.connection.lock().await;
+        let select = format!("SELECT {AUDIO_FILE_COLUMNS} {AUDIO_FILE_JOIN}");
+        select_tracks(&connection, &select, selection, read_audio_file)
+    }
+
+    /// Like `get_audio_files`, with the tags of each track instead.
+    pub(super) async fn get_tracks(
+        &self,
+        selection: &TrackSelection,
+    ) -> anyhow::Result<Vec<LibraryTrack>> {
+        let connection = self.connection.lock().await;
+        let select = format!("SELECT {LIBRARY_TRACK_COLUMNS} {LIBRARY_TRACK_JOIN}");
+        select_tracks(&connection, &select, selection, read_library_track)
+    }
+
+    /// Artists whose name contains `name`, or all of them, by name.
+    pub(super) async fn get_artists(
+        &self,
+        name: Option<&str>,
+    ) -> anyhow::Result<Vec<ArtistSummary>> {
+        let connection = self.connection.lock().await;
+        let mut statement = connection.prepare(
+            "SELECT ar.id, ar.name, COUNT(a.id) FROM Artists ar
+             LEFT JOIN Albums a ON a.artist_id = ar.id
+             WHERE ?1 IS NULL OR ar.name LIKE ?1 ESCAPE '\\'
+             GROUP BY ar.id
+             ORDER BY ar.name",
+        )?;
+        let artists = statement
+            .query_map(params![name.map(like_pattern)], |row| {
+                Ok(ArtistSummary {
+                    id: row.get(0)?,
+                    name: row.get(1)?,
+                    album_count: row.get(2)?,
+                })
+            })?
+            .collect::<Result<Vec<ArtistSummary>, _>>()?;
+        Ok(artists)
+    }
+
+    pub(super) async fn get_albums(
+        &self,
+        filter: &AlbumFilter,
+    ) -> anyhow::Result<Vec<AlbumSummary>> {
+        let connection = self.connection.lock().await;
+        let (condition, value) = match filter {
+            AlbumFilter::Id(id) => ("a.id = ?1", Value::Integer(*id)),
+            AlbumFilter::Artist(id) => ("a.artist_id = ?1", Value::Integer(*id)),
+            AlbumFilter::NameContains(name) => (
+                "a.name LIKE ?1 ESCAPE '\\'",
+                Value::Text(like_pattern(name)),
+            ),
+        };
+        let mut statement = connection.prepare(&format!(
+            "SELECT a.id, a.name, ar.id, ar.name, a.cover_path, COUNT(t.id) FROM Albums a
+             JOIN Artists ar ON ar.id = a.artist_id
+             LEFT JOIN Tracks t ON t.album_id = a.id
+             WHERE {condition}
+             GROUP BY a.id
+             ORDER BY a.name, a.id"
+        ))?;
+        let albums = statement
+            .query_map(params![value], |row| {
+                Ok(AlbumSummary {
+                    id: row.get(0)?,
+                    name: row.get(1)?,
+                    artist_id: row.get(2)?,
+                    artist: row.get(3)?,
+                    cover_path: row.get(4)?,
+                    track_count: row.get(5)?,
+                })
+            })?
+            .collect::<Result<Vec<AlbumSummary>, _>>()?;
+        Ok(albums)
+    }
+
+    pub(super) async fn get_playlists(&self) -> anyhow::Result<Vec<PlaylistSummary>> {
+        let connection = self.connection.lock().await;
+        let mut statement = connection.prepare(
+            "SELECT p.id, p.name, COUNT(pt.track_id) FROM Playlists p
+             LEFT JOIN PlaylistTracks pt ON pt.playlist_id = p.id
+             GROUP BY p.id
+             ORDER BY p.name, p.id",
+        )?;
+        let playlists = statement
+            .query_map([], |row| {
+                Ok(PlaylistSummary {
+                    id: row.get(0)?,
+                    name: row.get(1)?,
+                    track_count: row.get(2)?,
+                })
+            })?
+            .collect::<Result<Vec<PlaylistSummary>, _>>()?;
+        Ok(playlists)
+    }
+
+    pub(super) async fn find_tracks(
+        &self,
+        filters: &[TrackFilter],
+    ) -> anyhow::Result<Vec<LibraryTrack>> {
+        let connection = self.connection.lock().await;
+        let (filter, values) = filter_clause(filters);
+        let mut statement = connection.prepare(&format!(
+            "SELECT {LIBRARY_TRACK_COLUMNS} {LIBRARY_TRACK_JOIN} {filter} ORDER BY t.path"
+        ))?;
+        let tracks = statement
+            .query_map(rusqlite::params_from_iter(values), read_library_track)?
+            .collect::<Result<Vec<LibraryTrack>, _>>()?;
+        Ok(tracks)
+    }
+
+    pub(super) async fn get_recently_played(
+        &self,
+        limit: usize,
+    ) -> anyhow::Result<Vec<ListenEntry>> {
+        let connection = self.connection.lock().await;
+        // Listens of tracks no longer in the library keep their path.
+        let mut statement = connection.prepare(&format!(
+            "SELECT {LIBRARY_TRACK_COLUMNS}, l.path, l.started_at, l.played_secs, l.completed,
+                 l.skipped
+             FROM Listens l
+             LEFT JOIN Tracks t ON t.id = l.track_id
+             LEFT JOIN Albums a ON a.id = t.album_id
+             LEFT JOIN Artists ar ON ar.id = a.artist_id
+             ORDER BY l.started_at DESC, l.id DESC
+             LIMIT ?1"
+        ))?;
+        let listens = statement
+            .query_map(params![limit], |row| {
+                let track_id: Option<i64> = row.get(0)?;
+                Ok(ListenEntry {
+                    track: track_id.map(|_| read_library_track(row)).transpose()?,
+                    path: row.get(12)?,
+                    started_at: row.get(13)?,
+                    played_secs: row.get(14)?,
+                    completed: row.get(15)?,
+                    skipped: row.get(16)?,
+                })
+            })?
+            .collect::<Result<Vec<ListenEntry>, _>>()?;
+        Ok(listens)
+    }
+
+    pub(super) async fn get_most_played(
+        &self,
+        since: i64,
+        until: Option<i64>,
+        limit: usize,
+    ) -> anyhow::Result<Vec<TrackPlays>> {
+        let connection = self.connection.lock().await;
+        let mut statement = connection.prepare(&format!(
+            "SELECT {LIBRARY_TRACK_COLUMNS}, COUNT(l.id), COALESCE(SUM(l.played_secs), 0)
+             FROM Listens l
+             JOIN Tracks t ON t.id = l.track_id
+             JOIN Albums a ON a.id = t.album_id
+             JOIN Artists ar ON ar.id = a.artist_id
+             WHERE l.skipped = 0 AND l.started_at >= ?1 AND (?2 IS NULL OR l.started_at < ?2)
+             GROUP BY t.id
+             ORDER BY COUNT(l.id) DESC, MAX(l.started_at) DESC
+             LIMIT ?3"
+        ))?;
+        let tracks = statement
+            .query_map(params![since, until, limit], |row| {
+                Ok(TrackPlays {
+                    track: read_library_track(row)?,
+                    plays: row.get(12)?,
+                    played_secs: row.get(13)?,
+                })
+            })?
+            .collect::<Result<Vec<TrackPlays>, _>>()?;
+        Ok(tracks)
+    }
+
+    pub(super) async fn get_scrobbles(
+        &self,
+        include_exported: bool,
+    ) -> anyhow::Result<Vec<Scrobble>> {
+        let connection = self.connection.lock().await;
+        let mut statement = connection.prepare(
+            "SELECT id, artist, title, album, track_number, duration_secs, listened_at,
+                 exported_at
+             FROM Scrobbles
+             WHERE ?1 OR exported_at IS NULL
+             ORDER BY listened_at, id",
+        )?;
+        let scrobbles = statement
+            .query_map(params![include_exported], |row| {
+                Ok(Scrobble {
+                    id: row.get(0)?,
+                    artist: row.get(1)?,
+                    title: row.get(2)?,
+                    album: row.get(3)?,
+                    track_number: row.get(4)?,
+                    duration_secs: row.get(5)?,
+                    listened_at: row.get(6)?,
+                    exported_at: row.get(7)?,
+                })
+            })?
+            .collect::<Result<Vec<Scrobble>, _>>()?;
+        Ok(scrobbles)
+    }
+
+    pub(super) async fn mark_scrobbles_exported(
+        &self,
+        ids: &[i64],
+        exported_at: i64,
+    ) -> anyhow::Result<()> {
+        let mut connection = self.connection.lock().await;
+        let transaction = connection.transaction()?;
+        for id in ids {
+            transaction.execute(
+                "UPDATE Scrobbles SET exported_at = ?2 WHERE id = ?1",
+                params![id, exported_at],
+            )?;
+        }
+        transaction.commit()?;
+        Ok(())
+    }
+
+    pub(super) async fn list_tag(
+        &self,
+        tag: TrackTag,
+        filters: &[TrackFilter],
+    ) -> anyhow::Result<Vec<String>> {
+        let column = match tag_columns(tag) {
+            [column] => column,
+            _ => {
+                let message = format!("Can't list {tag:?}");
+                return Err(CommandError::InvalidArgument(message).into());
+            }
+        };
+        let connection = self.connection.lock().await;
+        let (filter, values) = filter_clause(filters);
+        let mut statement = connection.prepare(&format!(
+            "SELECT DISTINCT {column} {LIBRARY_TRACK_JOIN} {filter} ORDER BY {column}"
+        ))?;
+        let values = statement
+            .query_map(rusqlite::params_from_iter(values), |row| row.get(0))?
+            .collect::<Result<Vec<String>, _>>()?;
+        Ok(values)
+    }
+}
+
+const LIBRARY_TRACK_COLUMNS: &str = "t.id, t.path, t.name, ar.id, ar.name, a.id, a.name,
+    t.album_order, t.disc_number, t.play_count, t.skip_count, t.last_played";
+
+const LIBRARY_TRACK_JOIN: &str = "FROM Tracks t
+    JOIN Albums a ON a.id = t.album_id
+    JOIN Artists ar ON ar.id = a.artist_id";
+
+fn read_library_track(row: &Row) -> rusqlite::Result<LibraryTrack> {
+    Ok(LibraryTrack {
+        id: row.get(0)?,
+        path: row.get(1)?,
+        title: row.get(2)?,
+        artist_id: row.get(3)?,
+        artist: row.get(4)?,
+        album_id: row.get(5)?,
+        album: row.get(6)?,
+        album_order: row.get(7)?,
+        disc_number: row.get(8)?,
+        play_count: row.get(9)?,
+        skip_count: row.get(10)?,
+        last_played: row.get(11)?,
+    })
+}
+
+fn tag_columns(tag: TrackTag) -> &'static [&'static str] {
+    match tag {
+        TrackTag::Artist => &["ar.name"],
+        TrackTag::Album => &["a.name"],
+        TrackTag::Title => &["t.name"],
+        TrackTag::Path => &["t.path"],
+        TrackTag::Any => &["ar.name", "a.name", "t.name", "t.path"],
+    }
+}
+
+/// Builds a `WHERE` clause requiring every filter, and the values it binds in order.
+fn filter_clause(filters: &[TrackFilter]) -> (String, Vec<String>) {
+    let mut conditions = Vec::new();
+    let mut values = Vec::new();
+    for filter in filters {
+        let index = values.len() + 1;
+        let condition = |column: &&str| match filter.matching {
+            TagMatch::Equals => format!("{column} = ?{index}"),
+            TagMatch::Contains => format!("{column} LIKE ?{index} ESCAPE '\\'"),
+            // Unlike LIKE and GLOB, this needs no escaping and keeps case.
+            TagMatch::StartsWith => format!("substr({column}, 1, length(?{index})) = ?{index}"),
+        };
+        let alternatives: Vec<String> = tag_columns(filter.tag).iter().map(condition).collect();
+        conditions.push(format!("({})", alternatives.join(" OR ")));
+        values.push(match filter.matching {
+            TagMatch::Contains => like_pattern(&filter.value),
+            _ => filter.value.clone(),
+        });
+    }
+    if conditions.is_empty() {
+        return (String::new(), values);
+    }
+    (format!("WHERE {}", conditions.join(" AND ")), values)
+}
+
+/// A `LIKE` pattern, escaped with `\`, matching text containing `value`.
+fn like_pattern(value: &str) -> String {
+    let escaped = value
+        .replace('\\', "\\\\")
+        .replace('%', "\\%")
+        .replace('_', "\\_");
+    format!("%{escaped}%")
+}
+
+const AUDIO_FILE_COLUMNS: &str = "t.id, t.album_id, t.path, t.name, t.album_order, t.disc_number,
+    t.replaygain_track_gain, t.replaygain_track_peak,
+    t.replaygain_album_gain, t.replaygain_album_peak,
+    t.loudness_integrated, t.loudness_true_peak,
+    a.loudness_integrated, a.loudness_true_peak";
+
+const AUDIO_FILE_JOIN: &str = "FROM Tracks t JOIN Albums a ON a.id = t.album_id";
+
+/// Reads a row selected with `AUDIO_FILE_COLUMNS`.
+fn read_audio_file(row: &Row) -> rusqlite::Result<AudioFile> {
+    let tagged_gain = ReplayGain {
+        track_gain: row.get(6)?,
+        track_peak: row.get(7)?,
+        album_gain: row.get(8)?,
+        album_peak: row.get(9)?,
+    };
+    let track = Track::new(
+        row.get(2)?,
+        row.get(3)?,
+        row.get(4)?,
+        row.get(5)?,
+        tagged_gain.or_loudness(row.get(10)?, row.get(11)?, row.get(12)?, row.get(13)?),
+    );
+    let audio_file: AudioFile = (&track).into();
+    Ok(audio_file
+        .with_track_id(row.get(0)?)
+        .with_album_id(row.get(1)?))
+}
+
+/// Runs `select`, which lists columns from tracks `t` and albums `a`, for the tracks of
+/// `selection` in the order they should be played.
+fn select_tracks<T>(
+    connection: &Connection,
+    select: &str,
+    selection: &TrackSelection,
+    read: fn(&Row) -> rusqlite::Result<T>,
+) -> anyhow::Result<Vec<T>> {
+    let (filter, id) = match selection {
+        TrackSelection::Album { id } => (
+            "WHERE t.album_id = ?1
+             ORDER BY COALESCE(t.disc_number, 1), t.album_order, t.name",
+            id,
+        ),
+        TrackSelection::Artist { id } => (
+            "WHERE a.artist_id = ?1
+             ORDER BY a.name, a.id, COALESCE(t.disc_number, 1), t.album_order, t.name",
+            id,
+        ),
+        TrackSelection::Playlist { id } => (
+            "JOIN PlaylistTracks p ON p.track_id = t.id
+             WHERE p.playlist_id = ?1
+             ORDER BY p.position",
+            id,
+        ),
+        TrackSelection::Tracks { ids } => {
+            return select_tracks_by_id(connection, select, ids, read)
+        }
+    };
+    let mut statement = connection.prepare(&format!("{select} {filter}"))?;
+    let tracks = statement
+        .query_map(params![id], read)?
+        .collect::<Result<Vec<T>, _>>()?;
+    Ok(tracks)
+}
+
+/// Looks tracks up one by one to keep the order they were given in, skipping unknown ids.
+fn select_tracks_by_id<T>(
+    connection: &Connection,
+    select: &str,
+    ids: &[i64],
+    read: fn(&Row) -> rusqlite::Result<T>,
+) -> anyhow::Result<Vec<T>> {
+    let mut statement = connection.prepare(&format!("{select} WHERE t.id = ?1"))?;
+    let mut tracks = Vec::new();
+    for id in ids {
+        if let Some(track) = statement.query_row(params![id], read).optional()? {
+            tracks.push(track);
+        }
+    }
+    Ok(tracks)
+}
+
+fn get_or_insert_artist(transaction: &Transaction, artist: &Artist) -> anyhow::Result<i64> {
+    let existing = transaction
+        .query_row(
+            "SELECT id FROM Artists WHERE name = ?1",
+            params![artist.name],
+            |row| row.get(0),
+        )
+        .optional()?;
+    if let Some(id) = existing {
+        return Ok(id);
+    }
+    transaction.execute(
+        "INSERT INTO Artists (name) VALUES (?1)",
+        params![artist.name],
+    )?;
+    Ok(transaction.last_insert_rowid())
+}
+
+fn get_or_insert_album(
+    transaction: &Transaction,
+    artist_id: i64,
+    album: &Album,
+) -> anyhow::Result<i64> {
+    let existing = transaction
+        .query_row(
+            "SELECT id FROM Albums WHERE name = ?1 AND artist_id = ?2",
+            params![album.name, artist_id],
+            |row| row.get(0),
+        )
+        .optional()?;
+    if let Some(id) = existing {
+        return Ok(id);
+    }
+    transaction.execute(
+        "INSERT INTO Albums (name, cover_path, artist_id) VALUES (?1, ?2, ?3)",
+        params![album.name, album.cover_path, artist_id],
+    )?;
+    Ok(transaction.last_insert_rowid())
+}
+
+fn upsert_track(transaction: &Transaction, album_id: i64, track: &Track) -> anyhow::Result<()> {
+    let name = track.name.clone().unwrap_or_else(|| file_stem(&track.path));
+    let album_order = track.album_order.unwrap_or(0) as i64;
+    let disc_number = track.disc_number.map(|disc_number| disc_number as i64);
+    let gain = &track.replay_gain;
+    let updated = transaction.execute(
+        "UPDATE Tracks SET name = ?2, album_order = ?3, album_id = ?4,
+            replaygain_track_gain = ?5, replaygain_track_peak = ?6,
+            replaygain_album_gain = ?7, replaygain_album_peak = ?8, disc_number = ?9
+         WHERE path = ?1",
+        params![
+            track.path,
+            name,
+            album_order,
+            album_id,
+            gain.track_gain,
+            gain.track_peak,
+            gain.album_gain,
+            gain.album_peak,
+            disc_number
+        ],
+    )?;
+    if updated == 0 {
+        transaction.execute(
+            "INSERT INTO Tracks (path, name, album_order, album_id,
+                replaygain_track_gain, replaygain_track_peak,
+                replaygain_album_gain, replaygain_album_peak, disc_number)
+             VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9)",
+            params![
+                track.path,
+                name,
+                album_order,
+                album_id,
+                gain.track_gain,
+                gain.track_peak,
+                gain.album_gain,
+                gain.album_peak,
+                disc_number
+            ],
+        )?;
+    }
+    Ok(())
+}
+
+fn file_stem(path: &str) -> String {
+    std::path::Path::new(path)
+        .file_stem()
+        .map(|stem| stem.to_string_lossy().to_string())
+        .unwrap_or_else(|| path.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::database::init_test_db;
+
+    #[test]
+    fn test_album_plays_in_disc_and_track_order() {
+        let repository = LibraryRepository::new(init_test_db().unwrap());
+        let tracks = [("b", 1, 2), ("c", 2, 1), ("a", 1, 1)]
+            .into_iter()
+            .map(|(path, disc_number, album_order)| {
+                Track::new(
+                    path.to_string(),
+                    None,
+                    Some(album_order),
+                    Some(disc_number),
+                    ReplayGain::default(),
+                )
+            })
+            .collect();
+        let album = Album::new("Album".to_string(), None);
+        let artist = Artist::new("Artist".to_string());
+        let scanned = HashMap::from([(artist, HashMap::from([(album, tracks)]))]);
+
+        let runtime = tokio::runtime::Runtime::new().unwrap();
+        let audio_files = runtime.block_on(async {
+            repository.save_scan(&scanned).await.unwrap();
+            let album_id = repository.get_audio_file("a").await.unwrap().get_album_id();
+            let selection = TrackSelection::Album {
+                id: album_id.unwrap(),
+            };
+            repository.get_audio_files(&selection).await.unwrap()
+        });
+        let paths: Vec<&str> = audio_files.iter().map(AudioFile::get_path).collect();
+        assert_eq!(paths, ["a", "b", "c"]);
+        assert!(audio_files.iter().all(|file| file.get_track_id().is_some()));
+    }
+
+    #[test]
+    fn test_finds_and_lists_tracks_by_tag() {
+        let repository = LibraryRepository::new(init_test_db().unwrap());
+        let track = |path: &str, name: &str| {
+            let name = Some(name.to_string());
+            Track::new(path.to_string(), name, None, None, ReplayGain::default())
+        };
+        let scanned = HashMap::from([
+            (
+                Artist::new("Low".to_string()),
+                HashMap::from([(
+                    Album::new("Things We Lost".to_string(), None),
+                    vec![
+                        track("/low/1.flac", "Sunflower"),
+                        track("/low/2.flac", "50%"),
+                    ],
+                )]),
+            ),
+            (
+                Artist::new("Lowell".to_string()),
+                HashMap::from([(
+                    Album::new("Sunflowers".to_string(), None),
+                    vec![track("/lowell/1.flac", "Intro")],
+                )]),
+            ),
+        ]);
+        let filter = |tag, matching, value: &str| TrackFilter {
+            tag,
+            matching,
+            value: value.to_string(),
+        };
+
+        let runtime = tokio::runtime::Runtime::new().unwrap();
+        runtime.block_on(async {
+            repository.save_scan(&scanned).await.unwrap();
+            let repository = &repository;
+            let paths = |filters: Vec<TrackFilter>| async move {
+                let tracks = repository.find_tracks(&filters).await.unwrap();
+                tracks
+                    .into_iter()
+                    .map(|track| track.path)
+                    .collect::<Vec<_>>()
+            };
+
+            let exact = paths(vec![filter(TrackTag::Artist, TagMatch::Equals, "Low")]).await;
+            assert_eq!(exact, ["/low/1.flac", "/low/2.flac"]);
+            let anywhere =
+                paths(vec![filter(TrackTag::Any, TagMatch::Contains, "SUNFLOWER")]).await;
+            assert_eq!(anywhere, ["/low/1.flac", "/lowell/1.flac"]);
+            let literal = paths(vec![filter(TrackTag::Title, TagMatch::Contains, "0%")]).await;
+            assert_eq!(literal, ["/low/2.flac"]);
+            let folder = paths(vec![filter(TrackTag::Path, TagMatch::StartsWith, "/low/")]).await;
+            assert_eq!(folder, ["/low/1.flac", "/low/2.flac"]);
+
+            let artists = repository.list_tag(TrackTag::Artist, &[]).await.unwrap();
+            assert_eq!(artists, ["Low", "Lowell"]);
+            let albums = repository
+                .list_tag(
+                    TrackTag::Album,
+                    &[filter(TrackTag::Artist, TagMatch::Equals, "Lowell")],
+                )
+                .await
+                .unwrap();
+            assert_eq!(albums, ["Sunflowers"]);
+        });
+    }
+
+    #[test]
+    fn test_recently_and_most_played() {
+        let repository = LibraryRepository::new(init_test_db().unwrap());
+        let runtime = tokio::runtime::Runtime::new().unwrap();
+        runtime.block_on(async {
+            repository
+                .connection
+                .lock()
+                .await
+                .execute_batch(
+                    "INSERT INTO Artists (id, name) VALUES (1, 'Artist');
+                     INSERT INTO Albums (id, name, artist_id) VALUES (1, 'Album', 1);
+                     INSERT INTO Tracks (id, name, path, album_order, album_id)
+                     VALUES (1, 'One', '/one.flac', 1, 1), (2, 'Two', '/two.flac', 2, 1);
+                     INSERT INTO Listens (track_id, path, started_at, played_secs, completed, skipped)
+                     VALUES (1, '/one.flac', 100, 60, 1, 0),
+                            (2, '/two.flac', 200, 30, 1, 0),
+                            (2, '/two.flac', 300, 45, 1, 0),
+                            (1, '/one.flac', 400, 5, 0, 1),
+                            (NULL, '/gone.flac', 500, 20, 1, 0);",
+                )
+                .unwrap();
+
+            let recent = repository.get_recently_played(2).await.unwrap();
+            let paths: Vec<&str> = recent.iter().map(|listen| listen.path.as_str()).collect();
+            assert_eq!(paths, ["/gone.flac", "/one.flac"]);
+            assert_eq!(recent[0].track, None);
+            assert!(recent[1].skipped);
+            assert_eq!(recent[1].track.as_ref().unwrap().title, "One");
+
+            let most = repository.get_most_played(0, None, 10).await.unwrap();
+            let plays: Vec<(&str, usize, f64)> = most
+                .iter()
+                .map(|plays| (plays.track.path.as_str(), plays.plays, plays.played_secs))
+                .collect();
+            assert_eq!(plays, [("/two.flac", 2, 75.0), ("/one.flac", 1, 60.0)]);
+            let early = repository.get_most_played(0, Some(250), 10).await.unwrap();
+            assert_eq!(early.len(), 2);
+            assert_eq!(early[0].plays, 1);
+            let late = repository.get_most_played(250, None, 10).await.unwrap();
+            assert_eq!(late.len(), 1);
+        });
+    }
+
+    #[test]
+    fn test_marks_exported_scrobbles() {
+        let repository = LibraryRepository::new(init_test_db().unwrap());
+        let runtime = tokio::runtime::Runtime::new().unwrap();
+        runtime.block_on(async {
+            repository
+                .connection
+                .lock()
+                .await
+                .execute_batch(
+                    "INSERT INTO Listens (id, path, started_at) VALUES (1, '/a.flac', 20);
+                     INSERT INTO Listens (id, path, started_at) VALUES (2, '/b.flac', 10);
+                     INSERT INTO Scrobbles (listen_id, artist, title, listened_at)
+                     VALUES (1, 'Artist', 'A', 20), (2, 'Artist', 'B', 10);",
+                )
+                .unwrap();
+
+            let pending = repository.get_scrobbles(false).await.unwrap();
+            let titles: Vec<&str> = pending
+                .iter()
+                .map(|scrobble| scrobble.title.as_str())
+                .collect();
+            assert_eq!(titles, ["B", "A"]);
+            repository
+                .mark_scrobbles_exported(&[pending[0].id], 30)
+                .await
+                .unwrap();
+
+            let pending = repository.get_scrobbles(false).await.unwrap();
+            assert_eq!(pending.len(), 1);
+            assert_eq!(pending[0].title, "A");
+            let all = repository.get_scrobbles(true).await.unwrap();
+            assert_eq!(all[0].exported_at, Some(30));
+        });
+    }
 }
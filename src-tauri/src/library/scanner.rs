@@ -6,20 +6,22 @@ use crate::audio::{AudioFile, AudioMetadata, AudioSource};
 
 use super::{Album, Artist, ScanResult, Track};
 
+/// Names given to tracks without an artist or album tag, so they can still be browsed.
+pub(super) const UNKNOWN_ARTIST: &str = "Unknown Artist";
+pub(super) const UNKNOWN_ALBUM: &str = "Unknown Album";
+
 pub async fn scan_directory(base_path: &str) -> ScanResult {
     let mut scan_result: HashMap<Artist, HashMap<Album, Vec<Track>>> = HashMap::new();
     let file_paths = get_file_paths(base_path).await?;
     let found_file_metadata = get_audio_metadata_for_paths(file_paths).await?;
 
     for file_metadata in found_file_metadata {
-        let artist_name = file_metadata
-            .artist
-            .unwrap_or(String::from("Unknown Artist"));
+        let artist_name = file_metadata.artist.unwrap_or(String::from(UNKNOWN_ARTIST));
         let artist = Artist::new(artist_name);
 
         let artist_albums: &mut HashMap<Album, Vec<Track>> = scan_result.entry(artist).or_default();
 
-        let album_name = file_metadata.album.unwrap_or(String::from("Unknown Album"));
+        let album_name = file_metadata.album.unwrap_or(String::from(UNKNOWN_ALBUM));
         let album = Album::new(album_name, None);
 
         let album_tracks: &mut Vec<Track> = artist_albums.entry(album).or_default();
@@ -28,6 +30,8 @@ pub async fn scan_directory(base_path: &str) -> ScanResult {
             file_metadata.file_path.clone(),
             file_metadata.title,
             file_metadata.track_number,
+            file_metadata.disc_number,
+            file_metadata.replay_gain,
         ));
     }
 
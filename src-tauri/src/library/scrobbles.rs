@@ -0,0 +1,248 @@
+use rusqlite::{params, OptionalExtension, Transaction};
+use serde_json::{json, Map, Value};
+
+use super::scanner::{UNKNOWN_ALBUM, UNKNOWN_ARTIST};
+use crate::audio::Listen;
+
+const CLIENT_NAME: &str = "amptree";
+
+/// The most listens ListenBrainz takes in one submission.
+const MAX_LISTENBRAINZ_LISTENS: usize = 1000;
+
+/// A listen waiting to be submitted to a scrobbling service.
+#[derive(Debug, Clone, PartialEq, serde::Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Scrobble {
+    pub id: i64,
+    pub artist: String,
+    pub title: String,
+    pub album: Option<String>,
+    pub track_number: Option<usize>,
+    pub duration_secs: Option<f64>,
+    /// When the track started playing, in seconds since the epoch.
+    pub listened_at: i64,
+    /// `None` until the scrobble is exported.
+    pub exported_at: Option<i64>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, serde::Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub enum ScrobbleFormat {
+    /// The JSON ListenBrainz imports, as JSON lines of `import` submissions small enough to be
+    /// sent one by one.
+    ListenBrainz,
+    /// The `.scrobbler.log` written by Rockbox, which most scrobbling tools upload.
+    ScrobblerLog,
+}
+
+impl ScrobbleFormat {
+    pub fn write(self, scrobbles: &[Scrobble]) -> anyhow::Result<String> {
+        match self {
+            ScrobbleFormat::ListenBrainz => to_listenbrainz(scrobbles),
+            ScrobbleFormat::ScrobblerLog => Ok(to_scrobbler_log(scrobbles)),
+        }
+    }
+}
+
+/// Queues the listen just inserted as `listen_id`, with the tags of its file, completed by
+/// those of its library track. Listens without an artist and a title can't be scrobbled.
+pub(super) fn queue_scrobble(
+    transaction: &Transaction,
+    listen_id: i64,
+    listen: &Listen,
+) -> anyhow::Result<()> {
+    type Tags = (
+        Option<String>,
+        Option<String>,
+        Option<String>,
+        Option<usize>,
+    );
+    // The library names tracks without tags after placeholders, which aren't worth sending.
+    let library_tags: Option<Tags> = match listen.file.get_track_id() {
+        Some(track_id) => transaction
+            .query_row(
+                "SELECT NULLIF(ar.name, ?2), t.name, NULLIF(a.name, ?3), NULLIF(t.album_order, 0)
+                 FROM Tracks t
+                 JOIN Albums a ON a.id = t.album_id
+                 JOIN Artists ar ON ar.id = a.artist_id
+                 WHERE t.id = ?1",
+                params![track_id, UNKNOWN_ARTIST, UNKNOWN_ALBUM],
+                |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?, row.get(3)?)),
+            )
+            .optional()?,
+        None => None,
+    };
+    let (library_artist, library_title, library_album, library_track_number) =
+        library_tags.unwrap_or_default();
+    let metadata = listen.metadata.as_ref();
+    let artist = metadata
+        .and_then(|tags| tags.artist.clone())
+        .or(library_artist);
+    let title = metadata
+        .and_then(|tags| tags.title.clone())
+        .or(library_title);
+    let album = metadata
+        .and_then(|tags| tags.album.clone())
+        .or(library_album);
+    let track_number = metadata
+        .and_then(|tags| tags.track_number)
+        .or(library_track_number);
+    let (Some(artist), Some(title)) = (artist, title) else {
+        return Ok(());
+    };
+    transaction.execute(
+        "INSERT INTO Scrobbles
+             (listen_id, artist, title, album, track_number, duration_secs, listened_at)
+         VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7)",
+        params![
+            listen_id,
+            artist,
+            title,
+            album,
+            track_number,
+            listen.duration_secs,
+            listen.started_at,
+        ],
+    )?;
+    Ok(())
+}
+
+fn to_listenbrainz(scrobbles: &[Scrobble]) -> anyhow::Result<String> {
+    let mut lines = String::new();
+    for chunk in scrobbles.chunks(MAX_LISTENBRAINZ_LISTENS) {
+        let payload: Vec<Value> = chunk.iter().map(listenbrainz_listen).collect();
+        let submission = json!({ "listen_type": "import", "payload": payload });
+        lines.push_str(&serde_json::to_string(&submission)?);
+        lines.push('\n');
+    }
+    Ok(lines)
+}
+
+fn listenbrainz_listen(scrobble: &Scrobble) -> Value {
+    let mut additional_info = Map::new();
+    additional_info.insert("media_player".into(), CLIENT_NAME.into());
+    additional_info.insert("submission_client".into(), CLIENT_NAME.into());
+    let version = env!("CARGO_PKG_VERSION");
+    additional_info.insert("submission_client_version".into(), version.into());
+    if let Some(duration_secs) = scrobble.duration_secs {
+        let duration_ms = (duration_secs * 1000.0).round() as u64;
+        additional_info.insert("duration_ms".into(), duration_ms.into());
+    }
+    if let Some(track_number) = scrobble.track_number {
+        additional_info.insert("tracknumber".into(), track_number.into());
+    }
+    let mut track_metadata = Map::new();
+    track_metadata.insert("artist_name".into(), scrobble.artist.clone().into());
+    track_metadata.insert("track_name".into(), scrobble.title.clone().into());
+    if let Some(album) = &scrobble.album {
+        track_metadata.insert("release_name".into(), album.clone().into());
+    }
+    track_metadata.insert("additional_info".into(), additional_info.into());
+    json!({
+        "listened_at": scrobble.listened_at,
+        "track_metadata": track_metadata,
+    })
+}
+
+fn to_scrobbler_log(scrobbles: &[Scrobble]) -> String {
+    let mut log = format!(
+        "#AUDIOSCROBBLER/1.1\n#TZ/UTC\n#CLIENT/{CLIENT_NAME} {}\n",
+        env!("CARGO_PKG_VERSION")
+    );
+    for scrobble in scrobbles {
+        // Tab separated: artist, album, title, track number, length, rating, timestamp and
+        // MusicBrainz id. Only listened tracks are queued, so the rating is always L.
+        let fields = [
+            field(&scrobble.artist),
+            scrobble.album.as_deref().map(field).unwrap_or_default(),
+            field(&scrobble.title),
+            scrobble
+                .track_number
+                .map(|number| number.to_string())
+                .unwrap_or_default(),
+            scrobble
+                .duration_secs
+                .map_or(0, |duration_secs| duration_secs.round() as u64)
+                .to_string(),
+            "L".to_string(),
+            scrobble.listened_at.to_string(),
+            String::new(),
+        ];
+        log.push_str(&fields.join("\t"));
+        log.push('\n');
+    }
+    log
+}
+
+/// Keeps a tag from breaking the line it's written to.
+fn field(value: &str) -> String {
+    value.replace(['\t', '\n', '\r'], " ")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn scrobble(album: Option<&str>, track_number: Option<usize>) -> Scrobble {
+        Scrobble {
+            id: 1,
+            artist: "Low".to_string(),
+            title: "Sunflower".to_string(),
+            album: album.map(str::to_string),
+            track_number,
+            duration_secs: Some(275.4),
+            listened_at: 1_000_000,
+            exported_at: None,
+        }
+    }
+
+    #[test]
+    fn test_writes_scrobbler_log() {
+        let scrobbles = [
+            scrobble(Some("Things We Lost\tIn the Fire"), Some(2)),
+            scrobble(None, None),
+        ];
+        let log = ScrobbleFormat::ScrobblerLog.write(&scrobbles).unwrap();
+        let lines: Vec<&str> = log.lines().collect();
+        assert_eq!(lines[0], "#AUDIOSCROBBLER/1.1");
+        assert_eq!(lines[1], "#TZ/UTC");
+        assert!(lines[2].starts_with("#CLIENT/amptree "));
+        assert_eq!(
+            lines[3],
+            "Low\tThings We Lost In the Fire\tSunflower\t2\t275\tL\t1000000\t"
+        );
+        assert_eq!(lines[4], "Low\t\tSunflower\t\t275\tL\t1000000\t");
+    }
+
+    #[test]
+    fn test_writes_listenbrainz_import() {
+        let json = ScrobbleFormat::ListenBrainz
+            .write(&[scrobble(Some("Things We Lost"), None)])
+            .unwrap();
+        assert_eq!(json.lines().count(), 1);
+        let submission: Value = serde_json::from_str(&json).unwrap();
+        assert_eq!(submission["listen_type"], "import");
+        let listen = &submission["payload"][0];
+        assert_eq!(listen["listened_at"], 1_000_000);
+        let metadata = &listen["track_metadata"];
+        assert_eq!(metadata["artist_name"], "Low");
+        assert_eq!(metadata["track_name"], "Sunflower");
+        assert_eq!(metadata["release_name"], "Things We Lost");
+        assert_eq!(metadata["additional_info"]["duration_ms"], 275_400);
+        assert!(metadata["additional_info"].get("tracknumber").is_none());
+    }
+
+    #[test]
+    fn test_splits_listenbrainz_submissions() {
+        let scrobbles = vec![scrobble(None, None); 2001];
+        let json = ScrobbleFormat::ListenBrainz.write(&scrobbles).unwrap();
+        let sizes: Vec<usize> = json
+            .lines()
+            .map(|line| {
+                let submission: Value = serde_json::from_str(line).unwrap();
+                submission["payload"].as_array().unwrap().len()
+            })
+            .collect();
+        assert_eq!(sizes, [1000, 1000, 1]);
+    }
+}
@@ -0,0 +1,268 @@
+use std::sync::Mutex;
+
+use rusqlite::{params, Connection, OptionalExtension};
+
+use crate::audio::{
+    DeviceSelection, EqualizerSettings, PlayerSession, ReplayGainConfig, SessionStore,
+};
+use crate::control::{new_pairing_token, MpdConfig, RemoteConfig, SubsonicConfig};
+
+const OUTPUT_HOST: &str = "output.host";
+const OUTPUT_DEVICE: &str = "output.device";
+const REPLAY_GAIN: &str = "player.replay_gain";
+const EQUALIZER: &str = "player.equalizer";
+const PLAYER_SESSION: &str = "player.session";
+const SUBSONIC: &str = "remote.subsonic";
+const REMOTE: &str = "remote.http";
+const MPD: &str = "remote.mpd";
+
+#[derive(Debug, serde::Serialize, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct EqualizerPreset {
+    pub name: String,
+    pub settings: EqualizerSettings,
+}
+
+/// Key/value application settings persisted in the database.
+pub struct Settings {
+    connection: Mutex<Connection>,
+}
+
+impl Settings {
+    pub fn new(connection: Connection) -> Settings {
+        Settings {
+            connection: Mutex::new(connection),
+        }
+    }
+
+    pub fn get(&self, key: &str) -> anyhow::Result<Option<String>> {
+        let Ok(connection) = self.connection.lock() else {
+            anyhow::bail!("Couldn't acquire settings lock")
+        };
+        let value = connection
+            .query_row(
+                "SELECT value FROM Settings WHERE key = ?1",
+                params![key],
+                |row| row.get(0),
+            )
+            .optional()?;
+        Ok(value)
+    }
+
+    pub fn set(&self, key: &str, value: &str) -> anyhow::Result<()> {
+        let Ok(connection) = self.connection.lock() else {
+            anyhow::bail!("Couldn't acquire settings lock")
+        };
+        connection.execute(
+            "INSERT INTO Settings (key, value) VALUES (?1, ?2)
+             ON CONFLICT(key) DO UPDATE SET value = excluded.value",
+            params![key, value],
+        )?;
+        Ok(())
+    }
+
+    pub fn remove(&self, key: &str) -> anyhow::Result<()> {
+        let Ok(connection) = self.connection.lock() else {
+            anyhow::bail!("Couldn't acquire settings lock")
+        };
+        connection.execute("DELETE FROM Settings WHERE key = ?1", params![key])?;
+        Ok(())
+    }
+
+    pub fn get_output_device(&self) -> anyhow::Result<Option<DeviceSelection>> {
+        let Some(name) = self.get(OUTPUT_DEVICE)? else {
+            return Ok(None);
+        };
+        let host = self.get(OUTPUT_HOST)?;
+        Ok(Some(DeviceSelection { host, name }))
+    }
+
+    pub fn set_output_device(&self, selection: &DeviceSelection) -> anyhow::Result<()> {
+        self.set(OUTPUT_DEVICE, &selection.name)?;
+        match &selection.host {
+            Some(host) => self.set(OUTPUT_HOST, host),
+            None => self.remove(OUTPUT_HOST),
+        }
+    }
+
+    pub fn get_replay_gain(&self) -> anyhow::Result<ReplayGainConfig> {
+        match self.get(REPLAY_GAIN)? {
+            Some(value) => Ok(serde_json::from_str(&value)?),
+            None => Ok(ReplayGainConfig::default()),
+        }
+    }
+
+    pub fn set_replay_gain(&self, config: &ReplayGainConfig) -> anyhow::Result<()> {
+        self.set(REPLAY_GAIN, &serde_json::to_string(config)?)
+    }
+
+    pub fn get_equalizer(&self) -> anyhow::Result<EqualizerSettings> {
+        match self.get(EQUALIZER)? {
+            Some(value) => Ok(serde_json::from_str(&value)?),
+            None => Ok(EqualizerSettings::default()),
+        }
+    }
+
+    pub fn set_equalizer(&self, settings: &EqualizerSettings) -> anyhow::Result<()> {
+        self.set(EQUALIZER, &serde_json::to_string(settings)?)
+    }
+
+    pub fn get_subsonic(&self) -> anyhow::Result<SubsonicConfig> {
+        match self.get(SUBSONIC)? {
+            Some(value) => Ok(serde_json::from_str(&value)?),
+            None => Ok(SubsonicConfig::default()),
+        }
+    }
+
+    pub fn set_subsonic(&self, config: &SubsonicConfig) -> anyhow::Result<()> {
+        self.set(SUBSONIC, &serde_json::to_string(config)?)
+    }
+
+    /// The remote control setup. A pairing token is made up and saved the first time.
+    pub fn get_remote(&self) -> anyhow::Result<RemoteConfig> {
+        let mut config = match self.get(REMOTE)? {
+            Some(value) => serde_json::from_str(&value)?,
+            None => RemoteConfig::default(),
+        };
+        if config.token.is_empty() {
+            config.token = new_pairing_token();
+            self.set_remote(&config)?;
+        }
+        Ok(config)
+    }
+
+    pub fn set_remote(&self, config: &RemoteConfig) -> anyhow::Result<()> {
+        self.set(REMOTE, &serde_json::to_string(config)?)
+    }
+
+    pub fn get_mpd(&self) -> anyhow::Result<MpdConfig> {
+        match self.get(MPD)? {
+            Some(value) => Ok(serde_json::from_str(&value)?),
+            None => Ok(MpdConfig::default()),
+        }
+    }
+
+    pub fn set_mpd(&self, config: &MpdConfig) -> anyhow::Result<()> {
+        self.set(MPD, &serde_json::to_string(config)?)
+    }
+
+    /// The session saved by the player, if any. A session that can't be read anymore is
+    /// dropped rather than keeping the player from booting.
+    pub fn get_player_session(&self) -> anyhow::Result<Option<PlayerSession>> {
+        let Some(value) = self.get(PLAYER_SESSION)? else {
+            return Ok(None);
+        };
+        match serde_json::from_str(&value) {
+            Ok(session) => Ok(Some(session)),
+            Err(err) => {
+                eprintln!("Couldn't read the saved player session: {err}");
+                Ok(None)
+            }
+        }
+    }
+
+    pub fn list_equalizer_presets(&self) -> anyhow::Result<Vec<EqualizerPreset>> {
+        let Ok(connection) = self.connection.lock() else {
+            anyhow::bail!("Couldn't acquire settings lock")
+        };
+        let mut statement =
+            connection.prepare("SELECT name, settings FROM EqualizerPresets ORDER BY id")?;
+        let rows = statement
+            .query_map([], |row| Ok((row.get(0)?, row.get(1)?)))?
+            .collect::<Result<Vec<(String, String)>, _>>()?;
+        let mut presets = Vec::new();
+        for (name, settings) in rows {
+            let settings = serde_json::from_str(&settings)?;
+            presets.push(EqualizerPreset { name, settings });
+        }
+        Ok(presets)
+    }
+
+    /// Saves a preset, replacing the settings of an existing one with the same name.
+    pub fn save_equalizer_preset(
+        &self,
+        name: &str,
+        settings: &EqualizerSettings,
+    ) -> anyhow::Result<()> {
+        let Ok(connection) = self.connection.lock() else {
+            anyhow::bail!("Couldn't acquire settings lock")
+        };
+        connection.execute(
+            "INSERT INTO EqualizerPresets (name, settings) VALUES (?1, ?2)
+             ON CONFLICT(name) DO UPDATE SET settings = excluded.settings",
+            params![name, serde_json::to_string(settings)?],
+        )?;
+        Ok(())
+    }
+
+    pub fn delete_equalizer_preset(&self, name: &str) -> anyhow::Result<()> {
+        let Ok(connection) = self.connection.lock() else {
+            anyhow::bail!("Couldn't acquire settings lock")
+        };
+        connection.execute(
+            "DELETE FROM EqualizerPresets WHERE name = ?1",
+            params![name],
+        )?;
+        Ok(())
+    }
+}
+
+impl SessionStore for Settings {
+    fn save_session(&self, session: &PlayerSession) -> anyhow::Result<()> {
+        self.set(PLAYER_SESSION, &serde_json::to_string(session)?)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::database::init_test_db;
+
+    #[test]
+    fn test_output_device_round_trip() {
+        let settings = Settings::new(init_test_db().unwrap());
+        assert!(settings.get_output_device().unwrap().is_none());
+
+        let selection = DeviceSelection {
+            host: Some("ALSA".to_string()),
+            name: "hw:1,0".to_string(),
+        };
+        settings.set_output_device(&selection).unwrap();
+        settings
+            .set_output_device(&DeviceSelection {
+                host: None,
+                name: "default".to_string(),
+            })
+            .unwrap();
+
+        let saved = settings.get_output_device().unwrap().unwrap();
+        assert_eq!(saved.name, "default");
+        assert_eq!(saved.host, None);
+    }
+
+    #[test]
+    fn test_equalizer_presets() {
+        let settings = Settings::new(init_test_db().unwrap());
+        let presets = settings.list_equalizer_presets().unwrap();
+        assert!(presets.iter().any(|preset| preset.name == "Flat"));
+
+        let custom = EqualizerSettings::Graphic {
+            preamp_db: -2.0,
+            gains_db: [1.0; 10],
+        };
+        settings.save_equalizer_preset("Custom", &custom).unwrap();
+        settings.save_equalizer_preset("Custom", &custom).unwrap();
+        let presets = settings.list_equalizer_presets().unwrap();
+        assert_eq!(
+            presets
+                .iter()
+                .filter(|preset| preset.name == "Custom")
+                .count(),
+            1
+        );
+
+        settings.delete_equalizer_preset("Custom").unwrap();
+        let presets = settings.list_equalizer_presets().unwrap();
+        assert!(!presets.iter().any(|preset| preset.name == "Custom"));
+    }
+}
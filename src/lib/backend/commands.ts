@@ -1,5 +1,19 @@
 import { invoke } from '@tauri-apps/api/core';
 
+/** Shape of every error rejected by a command, and of `player:error` events. */
+export interface CommandError {
+	code:
+		| 'fileNotFound'
+		| 'unsupportedFormat'
+		| 'noDevice'
+		| 'decodeFailed'
+		| 'dbError'
+		| 'playerUnavailable'
+		| 'invalidArgument'
+		| 'internal';
+	message: string;
+}
+
 export async function playAudio(path: string): Promise<void> {
 	return invoke('play_audio', { path });
 }
@@ -20,3 +34,249 @@ export async function queue(path: string): Promise<void> {
 export async function skip(): Promise<void> {
 	return invoke('skip');
 }
+export async function listAudioHosts(): Promise<string[]> {
+	return invoke('list_audio_hosts');
+}
+export async function listOutputDevices(host?: string): Promise<unknown[]> {
+	return invoke('list_output_devices', { host });
+}
+export async function selectOutputDevice(name: string, host?: string): Promise<void> {
+	return invoke('select_output_device', { host, name });
+}
+
+export type ReplayGainMode = 'off' | 'track' | 'album' | 'auto';
+export interface ReplayGainConfig {
+	mode: ReplayGainMode;
+	preampDb: number;
+	preventClipping: boolean;
+}
+export async function getReplayGain(): Promise<ReplayGainConfig> {
+	return invoke('get_replay_gain');
+}
+export async function setReplayGain(config: ReplayGainConfig): Promise<void> {
+	return invoke('set_replay_gain', { config });
+}
+export async function analyzeLoudness(): Promise<boolean> {
+	return invoke('analyze_loudness');
+}
+export async function cancelLoudnessAnalysis(): Promise<void> {
+	return invoke('cancel_loudness_analysis');
+}
+
+export type FilterType = 'peaking' | 'lowShelf' | 'highShelf' | 'lowPass' | 'highPass';
+export interface EqualizerBand {
+	filter: FilterType;
+	frequency: number;
+	gainDb: number;
+	q: number;
+}
+export type EqualizerSettings =
+	| { kind: 'off' }
+	| { kind: 'graphic'; preampDb: number; gainsDb: number[] }
+	| { kind: 'parametric'; preampDb: number; bands: EqualizerBand[] };
+export interface EqualizerPreset {
+	name: string;
+	settings: EqualizerSettings;
+}
+export async function getEqualizer(): Promise<EqualizerSettings> {
+	return invoke('get_equalizer');
+}
+export async function setEqualizer(equalizer: EqualizerSettings): Promise<void> {
+	return invoke('set_equalizer', { equalizer });
+}
+export async function listEqualizerPresets(): Promise<EqualizerPreset[]> {
+	return invoke('list_equalizer_presets');
+}
+export async function saveEqualizerPreset(name: string, equalizer: EqualizerSettings): Promise<void> {
+	return invoke('save_equalizer_preset', { name, equalizer });
+}
+export async function deleteEqualizerPreset(name: string): Promise<void> {
+	return invoke('delete_equalizer_preset', { name });
+}
+
+export interface SubsonicConfig {
+	enabled: boolean;
+	address: string;
+	username: string;
+	password: string;
+}
+export async function getSubsonicSettings(): Promise<SubsonicConfig> {
+	return invoke('get_subsonic_settings');
+}
+/** Takes effect the next time the app starts. */
+export async function setSubsonicSettings(config: SubsonicConfig): Promise<void> {
+	return invoke('set_subsonic_settings', { config });
+}
+
+export interface RemoteConfig {
+	enabled: boolean;
+	/** Listen on the LAN instead of only this machine. */
+	lan: boolean;
+	port: number;
+	token: string;
+}
+export async function getRemoteSettings(): Promise<RemoteConfig> {
+	return invoke('get_remote_settings');
+}
+/** Takes effect the next time the app starts. */
+export async function setRemoteSettings(config: RemoteConfig): Promise<void> {
+	return invoke('set_remote_settings', { config });
+}
+/** Unpairs every remote, returning the new token. */
+export async function resetRemoteToken(): Promise<string> {
+	return invoke('reset_remote_token');
+}
+
+export interface MpdConfig {
+	enabled: boolean;
+	/** Where MPD clients connect, only this machine by default. */
+	address: string;
+}
+export async function getMpdSettings(): Promise<MpdConfig> {
+	return invoke('get_mpd_settings');
+}
+/** Takes effect the next time the app starts. */
+export async function setMpdSettings(config: MpdConfig): Promise<void> {
+	return invoke('set_mpd_settings', { config });
+}
+
+export interface QueuedFile {
+	path: string;
+	albumId: number | null;
+}
+export type ShuffleMode = 'off' | 'tracks' | 'albums';
+export type RepeatMode = 'off' | 'one' | 'all';
+export interface QueueSnapshot {
+	current: QueuedFile | null;
+	upcoming: QueuedFile[];
+	shuffle: ShuffleMode;
+	repeat: RepeatMode;
+}
+export type TrackSelection =
+	| { kind: 'album'; id: number }
+	| { kind: 'artist'; id: number }
+	| { kind: 'playlist'; id: number }
+	| { kind: 'tracks'; ids: number[] };
+export async function playSelection(selection: TrackSelection): Promise<void> {
+	return invoke('play_selection', { selection });
+}
+export async function queueSelection(selection: TrackSelection): Promise<void> {
+	return invoke('queue_selection', { selection });
+}
+export async function playNext(path: string): Promise<void> {
+	return invoke('play_next', { path });
+}
+export type PlaybackState = 'playing' | 'paused' | 'stopped';
+export interface AudioMetadata {
+	filePath: string;
+	artist: string | null;
+	title: string | null;
+	album: string | null;
+	year: number | null;
+	genre: string | null;
+	trackNumber: number | null;
+	discNumber: number | null;
+}
+export interface TrackInfo {
+	file: QueuedFile;
+	metadata: AudioMetadata;
+	/** `null` when the length of the track is unknown. */
+	durationSecs: number | null;
+}
+export interface PlaybackStatus {
+	percentage: number | null;
+	totalDurationSecs: number | null;
+	isDurationEstimated: boolean;
+	playedSecs: number;
+	volume: number;
+	underruns: number;
+}
+export interface PlayerState {
+	state: PlaybackState;
+	track: TrackInfo | null;
+	status: PlaybackStatus | null;
+	volume: number;
+	queue: QueueSnapshot;
+}
+export async function getPlayerState(): Promise<PlayerState> {
+	return invoke('get_player_state');
+}
+export async function getQueue(): Promise<QueueSnapshot> {
+	return invoke('get_queue');
+}
+export async function removeFromQueue(index: number): Promise<void> {
+	return invoke('remove_from_queue', { index });
+}
+export async function moveInQueue(from: number, to: number): Promise<void> {
+	return invoke('move_in_queue', { from, to });
+}
+export async function clearQueue(): Promise<void> {
+	return invoke('clear_queue');
+}
+export async function jumpInQueue(index: number): Promise<void> {
+	return invoke('jump_in_queue', { index });
+}
+export async function setShuffle(shuffle: ShuffleMode): Promise<void> {
+	return invoke('set_shuffle', { shuffle });
+}
+export async function setRepeat(repeat: RepeatMode): Promise<void> {
+	return invoke('set_repeat', { repeat });
+}
+export async function previous(): Promise<void> {
+	return invoke('previous');
+}
+export interface LibraryTrack {
+	id: number;
+	path: string;
+	title: string;
+	artistId: number;
+	artist: string;
+	albumId: number;
+	album: string;
+	albumOrder: number;
+	discNumber: number | null;
+	playCount: number;
+	skipCount: number;
+	/** Seconds since the epoch. */
+	lastPlayed: number | null;
+}
+export interface ListenEntry {
+	path: string;
+	/** `null` when the file isn't in the library. */
+	track: LibraryTrack | null;
+	startedAt: number;
+	playedSecs: number | null;
+	completed: boolean;
+	skipped: boolean;
+}
+export interface TrackPlays {
+	track: LibraryTrack;
+	plays: number;
+	playedSecs: number;
+}
+export async function getRecentlyPlayed(limit?: number): Promise<ListenEntry[]> {
+	return invoke('get_recently_played', { limit });
+}
+/** `since` and `until` are in seconds since the epoch. */
+export async function getMostPlayed(since: number, until?: number, limit?: number): Promise<TrackPlays[]> {
+	return invoke('get_most_played', { since, until, limit });
+}
+export interface Scrobble {
+	id: number;
+	artist: string;
+	title: string;
+	album: string | null;
+	trackNumber: number | null;
+	durationSecs: number | null;
+	/** Seconds since the epoch. */
+	listenedAt: number;
+	exportedAt: number | null;
+}
+export type ScrobbleFormat = 'listenBrainz' | 'scrobblerLog';
+export async function getScrobbles(includeExported?: boolean): Promise<Scrobble[]> {
+	return invoke('get_scrobbles', { includeExported });
+}
+/** Writes the scrobbles not exported yet to `path` and marks them, returning how many there were. */
+export async function exportScrobbles(format: ScrobbleFormat, path: string): Promise<number> {
+	return invoke('export_scrobbles', { format, path });
+}